@@ -0,0 +1,333 @@
+//! Generate a corpus of small synthetic TIFF files for regression testing.
+//!
+//!     cargo run --example fixtures -- /tmp/fixtures
+//!
+//! This writes three directories: `valid` contains files which should have a preview extracted,
+//! `expected` contains the JPEG which should come out of each of them, and `invalid` contains files
+//! which should be rejected. The previews aren't decodable images, but they have all of the
+//! markers which rawtojpg looks at, and each one is labelled so it's obvious which was picked:
+//!
+//!     cargo run --example fixtures -- /tmp/fixtures
+//!     cargo run -- /tmp/fixtures/valid /tmp/fixtures/out
+//!     diff -r /tmp/fixtures/expected /tmp/fixtures/out
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+const COMPRESSION_TAG: u16 = 0x103;
+const STRIP_OFFSETS_TAG: u16 = 0x111;
+const STRIP_BYTE_COUNTS_TAG: u16 = 0x117;
+const SUB_IFDS_TAG: u16 = 0x14a;
+const JPEG_OFFSET_TAG: u16 = 0x201;
+const JPEG_LENGTH_TAG: u16 = 0x202;
+
+/// Old-style JPEG compression, which is what most RAWs use for strip previews.
+const COMPRESSION_OJPEG: u32 = 6;
+
+/// Build a JPEG-shaped stream of `width` by `height` with about `size` bytes of image data. The
+/// label goes in a comment, so that it's easy to tell previews apart.
+fn jpeg(label: &str, width: u16, height: u16, size: usize) -> Vec<u8> {
+    let mut out = vec![0xff, 0xd8];
+
+    out.extend([0xff, 0xfe]);
+    out.extend(u16::try_from(label.len() + 2).unwrap().to_be_bytes());
+    out.extend(label.as_bytes());
+
+    // SOF0 with one component.
+    out.extend([0xff, 0xc0, 0, 11, 8]);
+    out.extend(height.to_be_bytes());
+    out.extend(width.to_be_bytes());
+    out.extend([1, 1, 0x11, 0]);
+
+    // SOS, then some entropy-coded data which never contains a marker.
+    out.extend([0xff, 0xda, 0, 8, 1, 1, 0, 0, 0x3f, 0]);
+    out.extend((0..size).map(|i| (i % 0xfe) as u8));
+
+    out.extend([0xff, 0xd9]);
+    out
+}
+
+/// A TIFF file which is built up from the data at the end of the file towards the first IFD, so
+/// that everything an IFD points to already has an offset when it's written.
+struct Tiff {
+    big_endian: bool,
+    buf: Vec<u8>,
+}
+
+impl Tiff {
+    fn new(big_endian: bool) -> Self {
+        let mut tiff = Self {
+            big_endian,
+            buf: Vec::new(),
+        };
+        let magic: &[u8] = if big_endian { b"MM\0*" } else { b"II*\0" };
+        tiff.buf.extend(magic);
+        tiff.buf.extend([0; 4]);
+        tiff
+    }
+
+    fn u16(&self, value: u16) -> [u8; 2] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn u32(&self, value: u32) -> [u8; 4] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn offset(&self) -> u32 {
+        self.buf.len().try_into().unwrap()
+    }
+
+    /// Append some data, returning its offset.
+    fn data(&mut self, data: &[u8]) -> u32 {
+        let offset = self.offset();
+        self.buf.extend(data);
+        offset
+    }
+
+    /// Append an array of LONGs, returning its offset.
+    fn longs(&mut self, values: &[u32]) -> u32 {
+        let data: Vec<_> = values.iter().flat_map(|&value| self.u32(value)).collect();
+        self.data(&data)
+    }
+
+    /// Append an IFD, returning its offset. Each entry is a tag, type, count, and either the
+    /// value itself or the offset of the values, depending on whether they fit in four bytes.
+    fn ifd(&mut self, entries: &[(u16, u16, u32, u32)], next: u32) -> u32 {
+        let offset = self.offset();
+        self.buf.extend(self.u16(entries.len().try_into().unwrap()));
+        for &(tag, kind, count, value) in entries {
+            self.buf.extend(self.u16(tag));
+            self.buf.extend(self.u16(kind));
+            self.buf.extend(self.u32(count));
+            if kind == SHORT && count == 1 {
+                self.buf.extend(self.u16(value.try_into().unwrap()));
+                self.buf.extend([0; 2]);
+            } else {
+                self.buf.extend(self.u32(value));
+            }
+        }
+        self.buf.extend(self.u32(next));
+        offset
+    }
+
+    /// Append an IFD with a JPEGInterchangeFormat preview, returning its offset.
+    fn jpeg_ifd(&mut self, jpeg: &[u8], next: u32) -> u32 {
+        let offset = self.data(jpeg);
+        self.ifd(
+            &[
+                (JPEG_OFFSET_TAG, LONG, 1, offset),
+                (JPEG_LENGTH_TAG, LONG, 1, jpeg.len().try_into().unwrap()),
+            ],
+            next,
+        )
+    }
+
+    /// Point the next IFD field of the IFD at `ifd` somewhere else.
+    fn set_next(&mut self, ifd: u32, next: u32) {
+        let ifd = usize::try_from(ifd).unwrap();
+        let count = if self.big_endian {
+            u16::from_be_bytes([self.buf[ifd], self.buf[ifd + 1]])
+        } else {
+            u16::from_le_bytes([self.buf[ifd], self.buf[ifd + 1]])
+        };
+        let at = ifd + 2 + usize::from(count) * 12;
+        let next = self.u32(next);
+        self.buf[at..at + 4].copy_from_slice(&next);
+    }
+
+    fn finish(mut self, first_ifd: u32) -> Vec<u8> {
+        let first_ifd = self.u32(first_ifd);
+        self.buf[4..8].copy_from_slice(&first_ifd);
+        self.buf
+    }
+}
+
+/// A file with a single preview in IFD0.
+fn single(big_endian: bool) -> (Vec<u8>, Vec<u8>) {
+    let preview = jpeg("ifd0", 160, 120, 1000);
+    let mut tiff = Tiff::new(big_endian);
+    let ifd0 = tiff.jpeg_ifd(&preview, 0);
+    (tiff.finish(ifd0), preview)
+}
+
+/// A chain of three IFDs, where the largest preview is in the middle.
+fn chain() -> (Vec<u8>, Vec<u8>) {
+    let large = jpeg("ifd1", 1600, 1200, 20000);
+    let mut tiff = Tiff::new(false);
+    let ifd2 = tiff.jpeg_ifd(&jpeg("ifd2", 640, 480, 5000), 0);
+    let ifd1 = tiff.jpeg_ifd(&large, ifd2);
+    let ifd0 = tiff.jpeg_ifd(&jpeg("ifd0", 160, 120, 1000), ifd1);
+    (tiff.finish(ifd0), large)
+}
+
+/// Previews in SubIFDs of IFD0, like NEF and DNG.
+fn sub_ifds(big_endian: bool) -> (Vec<u8>, Vec<u8>) {
+    let large = jpeg("subifd1", 1600, 1200, 20000);
+    let mut tiff = Tiff::new(big_endian);
+    let sub0 = tiff.jpeg_ifd(&jpeg("subifd0", 640, 480, 5000), 0);
+    let sub1 = tiff.jpeg_ifd(&large, 0);
+    let sub_ifds = tiff.longs(&[sub0, sub1]);
+    let thumbnail = jpeg("ifd0", 160, 120, 1000);
+    let thumbnail_offset = tiff.data(&thumbnail);
+    let ifd0 = tiff.ifd(
+        &[
+            (SUB_IFDS_TAG, LONG, 2, sub_ifds),
+            (JPEG_OFFSET_TAG, LONG, 1, thumbnail_offset),
+            (
+                JPEG_LENGTH_TAG,
+                LONG,
+                1,
+                thumbnail.len().try_into().unwrap(),
+            ),
+        ],
+        0,
+    );
+    (tiff.finish(ifd0), large)
+}
+
+/// A preview stored as a single JPEG-compressed strip.
+fn strip() -> (Vec<u8>, Vec<u8>) {
+    let preview = jpeg("strip", 640, 480, 5000);
+    let mut tiff = Tiff::new(false);
+    let offset = tiff.data(&preview);
+    let ifd0 = tiff.ifd(
+        &[
+            (COMPRESSION_TAG, SHORT, 1, COMPRESSION_OJPEG),
+            (STRIP_OFFSETS_TAG, LONG, 1, offset),
+            (
+                STRIP_BYTE_COUNTS_TAG,
+                LONG,
+                1,
+                preview.len().try_into().unwrap(),
+            ),
+        ],
+        0,
+    );
+    (tiff.finish(ifd0), preview)
+}
+
+/// A preview split over several strips which aren't next to each other in the file.
+fn multi_strip() -> (Vec<u8>, Vec<u8>) {
+    let preview = jpeg("multi-strip", 640, 480, 5000);
+    let mut tiff = Tiff::new(true);
+    let mut offsets = Vec::new();
+    let mut lengths = Vec::new();
+    for chunk in preview.chunks(2000) {
+        offsets.push(tiff.data(chunk));
+        lengths.push(chunk.len().try_into().unwrap());
+        tiff.data(&[0; 16]);
+    }
+    let count = offsets.len().try_into().unwrap();
+    let offsets = tiff.longs(&offsets);
+    let lengths = tiff.longs(&lengths);
+    let ifd0 = tiff.ifd(
+        &[
+            (COMPRESSION_TAG, SHORT, 1, COMPRESSION_OJPEG),
+            (STRIP_OFFSETS_TAG, LONG, count, offsets),
+            (STRIP_BYTE_COUNTS_TAG, LONG, count, lengths),
+        ],
+        0,
+    );
+    (tiff.finish(ifd0), preview)
+}
+
+/// A good IFD0 which points to a next IFD past the end of the file.
+fn broken_chain() -> (Vec<u8>, Vec<u8>) {
+    let preview = jpeg("ifd0", 640, 480, 5000);
+    let mut tiff = Tiff::new(false);
+    let ifd0 = tiff.jpeg_ifd(&preview, 0x7fff_ffff);
+    (tiff.finish(ifd0), preview)
+}
+
+/// Two IFDs which point to each other.
+fn ifd_loop() -> (Vec<u8>, Vec<u8>) {
+    let large = jpeg("ifd1", 1600, 1200, 20000);
+    let mut tiff = Tiff::new(false);
+    let ifd1 = tiff.jpeg_ifd(&large, 0);
+    let ifd0 = tiff.jpeg_ifd(&jpeg("ifd0", 160, 120, 1000), ifd1);
+    tiff.set_next(ifd1, ifd0);
+    (tiff.finish(ifd0), large)
+}
+
+fn truncated_header() -> Vec<u8> {
+    b"II*\0\x08".to_vec()
+}
+
+/// A well formed file which just doesn't have a preview.
+fn no_preview() -> Vec<u8> {
+    let mut tiff = Tiff::new(false);
+    let ifd0 = tiff.ifd(&[(COMPRESSION_TAG, SHORT, 1, 1)], 0);
+    tiff.finish(ifd0)
+}
+
+/// A preview pointer past the end of the file.
+fn bad_preview_offset() -> Vec<u8> {
+    let mut tiff = Tiff::new(false);
+    let ifd0 = tiff.ifd(
+        &[
+            (JPEG_OFFSET_TAG, LONG, 1, 0x7fff_0000),
+            (JPEG_LENGTH_TAG, LONG, 1, 1000),
+        ],
+        0,
+    );
+    tiff.finish(ifd0)
+}
+
+/// IFD0 itself is past the end of the file.
+fn bad_first_ifd() -> Vec<u8> {
+    Tiff::new(true).finish(0x7fff_ffff)
+}
+
+fn write(dir: &Path, name: &str, data: &[u8]) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(name);
+    fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn main() -> Result<()> {
+    let out_dir = std::env::args_os()
+        .nth(1)
+        .context("Usage: fixtures <output directory>")?;
+    let out_dir = Path::new(&out_dir);
+
+    let valid = [
+        ("le-ifd0", single(false)),
+        ("be-ifd0", single(true)),
+        ("chain", chain()),
+        ("le-sub-ifds", sub_ifds(false)),
+        ("be-sub-ifds", sub_ifds(true)),
+        ("strip", strip()),
+        ("multi-strip", multi_strip()),
+        ("broken-chain", broken_chain()),
+        ("ifd-loop", ifd_loop()),
+    ];
+    for (name, (tiff, preview)) in valid {
+        write(&out_dir.join("valid"), &format!("{name}.tif"), &tiff)?;
+        write(&out_dir.join("expected"), &format!("{name}.jpg"), &preview)?;
+    }
+
+    let invalid = [
+        ("truncated-header", truncated_header()),
+        ("no-preview", no_preview()),
+        ("bad-preview-offset", bad_preview_offset()),
+        ("bad-first-ifd", bad_first_ifd()),
+    ];
+    for (name, tiff) in invalid {
+        write(&out_dir.join("invalid"), &format!("{name}.tif"), &tiff)?;
+    }
+
+    Ok(())
+}
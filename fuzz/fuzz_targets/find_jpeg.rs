@@ -0,0 +1,24 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rawtojpg::Options;
+
+fuzz_target!(|data: &[u8]| {
+    for strict in [false, true] {
+        let options = Options {
+            strict,
+            ..Options::default()
+        };
+        if let Ok(jpeg) = rawtojpg::find_largest_embedded_jpeg(data, &options) {
+            // Anything we pick should also be readable, including JPEGTables and strips.
+            if let Ok(jpeg) = jpeg.data(data) {
+                // Rewriting it losslessly shouldn't panic either, as baseline or progressive.
+                let _ = rawtojpg::lossless::optimize(&jpeg, strict);
+            }
+        }
+    }
+
+    if let Some(jpeg) = rawtojpg::scan_for_largest_jpeg(data) {
+        let _ = jpeg.data(data);
+    }
+});
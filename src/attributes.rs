@@ -0,0 +1,198 @@
+use anyhow::{bail, ensure, Context, Result};
+use std::ffi::{CString, OsString};
+use std::fs::{self, File, FileTimes, Metadata, Permissions};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::ptr;
+use xattr::FileExt;
+
+/// What --preserve can copy from each RAW onto everything written for it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Preserve {
+    /// The permission bits
+    Perms,
+    /// The user and group. Only root can give files away, so this is skipped for anyone else
+    Owner,
+    /// The extended attributes, other than any which need privileges to set
+    Xattr,
+}
+
+/// A user and group to give files to, either of which can be left as it is.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Owner {
+    user: Option<u32>,
+    group: Option<u32>,
+}
+
+/// What to set on each file written for a RAW, once it's been written.
+#[derive(Default)]
+pub struct Attributes {
+    pub times: Option<FileTimes>,
+    mode: Option<u32>,
+    owner: Owner,
+    xattrs: Vec<(OsString, Vec<u8>)>,
+}
+
+impl Attributes {
+    /// Read what `preserve` says to copy from the RAW at `source`, whose metadata is `metadata`.
+    pub fn from_source(source: &Path, metadata: &Metadata, preserve: &[Preserve]) -> Result<Self> {
+        let mut attributes = Self::default();
+        if preserve.contains(&Preserve::Perms) {
+            attributes.mode = Some(metadata.permissions().mode());
+        }
+        // SAFETY: geteuid() can't fail, and doesn't touch memory.
+        if preserve.contains(&Preserve::Owner) && unsafe { libc::geteuid() } == 0 {
+            attributes.owner = Owner {
+                user: Some(metadata.uid()),
+                group: Some(metadata.gid()),
+            };
+        }
+        if preserve.contains(&Preserve::Xattr) {
+            let names = match xattr::list(source) {
+                Err(err) if err.kind() == io::ErrorKind::Unsupported => return Ok(attributes),
+                names => names?,
+            };
+            for name in names {
+                if let Some(value) = xattr::get(source, &name)? {
+                    attributes.xattrs.push((name, value));
+                }
+            }
+        }
+        Ok(attributes)
+    }
+
+    /// Use the --chmod `mode` and the --chown `owner`, if they're given, over anything from the
+    /// RAW.
+    pub fn with_overrides(mut self, mode: Option<u32>, owner: Option<Owner>) -> Self {
+        self.mode = mode.or(self.mode);
+        if let Some(owner) = owner {
+            self.owner.user = owner.user.or(self.owner.user);
+            self.owner.group = owner.group.or(self.owner.group);
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_none()
+            && self.mode.is_none()
+            && self.owner.user.is_none()
+            && self.owner.group.is_none()
+            && self.xattrs.is_empty()
+    }
+
+    /// Set everything on `file`. The times go last, since nothing after them can change them, and
+    /// the permissions go after the owner, since changing that can clear setuid and setgid bits.
+    /// Extended attributes which need privileges we don't have, or which the filesystem doesn't
+    /// support, are skipped.
+    pub fn apply(&self, file: &File) -> Result<()> {
+        for (name, value) in &self.xattrs {
+            match file.set_xattr(name, value) {
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+                    ) => {}
+                result => result?,
+            }
+        }
+        if self.owner.user.is_some() || self.owner.group.is_some() {
+            unix_fs::fchown(file, self.owner.user, self.owner.group)?;
+        }
+        if let Some(mode) = self.mode {
+            file.set_permissions(Permissions::from_mode(mode))?;
+        }
+        if let Some(times) = self.times {
+            file.set_times(times)?;
+        }
+        Ok(())
+    }
+
+    /// Set the owner and permissions on a directory which was created for output. Directories need
+    /// to be searchable to be any use, so they're also made executable by whoever the mode lets
+    /// read them, like 0644 becoming 0755.
+    pub fn apply_to_dir(&self, dir: &Path) -> Result<()> {
+        if self.owner.user.is_some() || self.owner.group.is_some() {
+            unix_fs::chown(dir, self.owner.user, self.owner.group)?;
+        }
+        if let Some(mode) = self.mode {
+            fs::set_permissions(dir, Permissions::from_mode(mode | (mode & 0o444) >> 2))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse an octal mode for --chmod, like 0644 or 644.
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    let parsed = u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777);
+    parsed.with_context(|| format!("Mode must be in octal, like 0644, not {mode}"))
+}
+
+/// Parse an owner for --chown, like user:group, user, or :group, with names or numeric IDs.
+pub fn parse_owner(owner: &str) -> Result<Owner> {
+    let (user, group) = owner.split_once(':').unwrap_or((owner, ""));
+    let owner = Owner {
+        user: Some(user)
+            .filter(|user| !user.is_empty())
+            .map(user_id)
+            .transpose()?,
+        group: Some(group)
+            .filter(|group| !group.is_empty())
+            .map(group_id)
+            .transpose()?,
+    };
+    ensure!(
+        owner.user.is_some() || owner.group.is_some(),
+        "Owner must have a user, a group, or both"
+    );
+    Ok(owner)
+}
+
+fn user_id(name: &str) -> Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = CString::new(name)?;
+    // SAFETY: getpwnam_r() only writes to the passwd and the buffer, which are both big enough
+    // for what it's told, and the result only points into them.
+    let id = lookup(|passwd: *mut libc::passwd, buf, len, result| unsafe {
+        libc::getpwnam_r(c_name.as_ptr(), passwd, buf, len, result)
+    })?
+    .map(|passwd| passwd.pw_uid);
+    id.with_context(|| format!("No such user: {name}"))
+}
+
+fn group_id(name: &str) -> Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = CString::new(name)?;
+    // SAFETY: As for getpwnam_r() above.
+    let id = lookup(|group: *mut libc::group, buf, len, result| unsafe {
+        libc::getgrnam_r(c_name.as_ptr(), group, buf, len, result)
+    })?
+    .map(|group| group.gr_gid);
+    id.with_context(|| format!("No such group: {name}"))
+}
+
+/// Call one of the reentrant passwd or group lookups, growing the buffer until it fits. Only the
+/// fixed size fields of the result can be used, since anything it points to is in the buffer.
+fn lookup<T>(
+    call: impl Fn(*mut T, *mut libc::c_char, usize, *mut *mut T) -> libc::c_int,
+) -> Result<Option<T>> {
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut entry = MaybeUninit::uninit();
+        let mut result = ptr::null_mut();
+        match call(entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result) {
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            // SAFETY: A non-null result means the entry was filled in.
+            0 if !result.is_null() => return Ok(Some(unsafe { entry.assume_init() })),
+            0 => return Ok(None),
+            err => bail!(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
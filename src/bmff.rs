@@ -0,0 +1,401 @@
+use crate::{get_from, EmbeddedJpegInfo, JPEG_SOI};
+use anyhow::{bail, ensure, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+/// The top level uuid box in CR3 files which holds the PRVW preview.
+const CANON_PREVIEW_UUID: &[u8] = &[
+    0xea, 0xf4, 0x2b, 0x5e, 0x1c, 0x98, 0x4b, 0x88, 0xb9, 0xfb, 0xb7, 0xdc, 0x40, 0x6e, 0x4d, 0x16,
+];
+
+/// The uuid box inside moov in CR3 files which holds Canon metadata, including the THMB thumbnail.
+const CANON_METADATA_UUID: &[u8] = &[
+    0x85, 0xc0, 0xb6, 0x87, 0x82, 0x0f, 0x11, 0xe0, 0x81, 0x11, 0xf4, 0xce, 0x46, 0x2b, 0x6a, 0x48,
+];
+
+/// Check whether a buffer looks like an ISO base media file (CR3, HEIF, and friends), which always
+/// start with an ftyp box.
+pub fn is_bmff(buf: &[u8]) -> bool {
+    buf.get(4..8) == Some(b"ftyp")
+}
+
+/// A single box in an ISO BMFF file.
+#[derive(Clone, Copy)]
+struct Mp4Box<'a> {
+    kind: &'a [u8],
+    uuid: Option<&'a [u8]>,
+    /// The absolute offset of the payload in the file.
+    offset: u64,
+    payload: &'a [u8],
+}
+
+impl<'a> Mp4Box<'a> {
+    fn children(&self) -> Boxes<'a> {
+        Boxes::new(self.payload, self.offset)
+    }
+
+    /// Find the first child with the given type.
+    fn child(&self, kind: &[u8]) -> Result<Option<Mp4Box<'a>>> {
+        for child in self.children() {
+            let child = child?;
+            if child.kind == kind {
+                return Ok(Some(child));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find a descendant by walking down through the given types.
+    fn descendant(&self, path: &[&[u8]]) -> Result<Option<Mp4Box<'a>>> {
+        let mut cur = *self;
+        for kind in path {
+            match cur.child(kind)? {
+                Some(child) => cur = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(cur))
+    }
+}
+
+/// An iterator over sibling boxes in a buffer.
+struct Boxes<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    base: u64,
+}
+
+impl<'a> Boxes<'a> {
+    fn new(buf: &'a [u8], base: u64) -> Self {
+        Self { buf, pos: 0, base }
+    }
+
+    fn parse_box(&mut self) -> Result<Mp4Box<'a>> {
+        let rest = &self.buf[self.pos..];
+        let box_offset = self.base + u64::try_from(self.pos)?;
+        ensure!(
+            rest.len() >= 8,
+            "Truncated box header at offset {box_offset}"
+        );
+
+        let kind = &rest[4..8];
+        let mut header_len = 8;
+        let size = match BigEndian::read_u32(&rest[..4]) {
+            0 => rest.len(),
+            1 => {
+                ensure!(
+                    rest.len() >= 16,
+                    "Truncated box header at offset {box_offset}"
+                );
+                header_len = 16;
+                BigEndian::read_u64(&rest[8..16]).try_into()?
+            }
+            size => size.try_into()?,
+        };
+        ensure!(
+            size >= header_len && size <= rest.len(),
+            "Box at offset {box_offset} exceeds its container"
+        );
+
+        let uuid = if kind == b"uuid" {
+            ensure!(
+                size >= header_len + 16,
+                "Truncated uuid box at offset {box_offset}"
+            );
+            header_len += 16;
+            Some(&rest[header_len - 16..header_len])
+        } else {
+            None
+        };
+
+        let parsed = Mp4Box {
+            kind,
+            uuid,
+            offset: box_offset + u64::try_from(header_len)?,
+            payload: &rest[header_len..size],
+        };
+        self.pos += size;
+        Ok(parsed)
+    }
+}
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = Result<Mp4Box<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+        let parsed = self.parse_box();
+        if parsed.is_err() {
+            // We can't know where the next box starts, so stop here.
+            self.pos = self.buf.len();
+        }
+        Some(parsed)
+    }
+}
+
+fn read_u32_at(buf: &[u8], offset: usize) -> Result<u32> {
+    let bytes = buf
+        .get(offset..offset + 4)
+        .context("Truncated box payload")?;
+    Ok(BigEndian::read_u32(bytes))
+}
+
+/// A cursor for reading the fields of a box payload in order.
+struct FieldReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .context("Truncated box payload")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(BigEndian::read_u16(self.bytes(2)?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(BigEndian::read_u32(self.bytes(4)?))
+    }
+
+    /// Read an unsigned integer whose size in bytes is given by the file, as in iloc.
+    fn uint(&mut self, size: u8) -> Result<u64> {
+        match size {
+            0 => Ok(0),
+            4 => Ok(self.u32()?.into()),
+            8 => Ok(BigEndian::read_u64(self.bytes(8)?)),
+            _ => bail!("Invalid field size {size}"),
+        }
+    }
+}
+
+/// Find all embedded JPEGs in an ISO BMFF based file.
+///
+/// CR3 files may contain up to three JPEGs:
+///
+/// - A small THMB thumbnail inside Canon's metadata box in moov.
+/// - A medium PRVW preview inside its own top level uuid box.
+/// - A full size JPEG, stored as the first sample of one of the tracks in mdat.
+///
+/// HEIF files instead describe their images as items in the top level meta box, and we take any
+/// of those which are JPEGs.
+pub fn find_jpegs(raw_buf: &[u8], candidates: &mut Vec<EmbeddedJpegInfo>) -> Result<()> {
+    for top in Boxes::new(raw_buf, 0) {
+        let top = top?;
+        match (top.kind, top.uuid) {
+            (b"moov", _) => find_moov_jpegs(raw_buf, &top, candidates)?,
+            (b"meta", _) => find_meta_jpegs(&top, candidates)?,
+            (b"uuid", Some(CANON_PREVIEW_UUID)) => {
+                // 8 unknown bytes, followed by the PRVW box.
+                let inner = top.payload.get(8..).context("Truncated preview box")?;
+                for child in Boxes::new(inner, top.offset + 8) {
+                    let child = child?;
+                    if child.kind == b"PRVW" {
+                        // Layout: u32 unknown, u16 unknown, u16 width, u16 height, u16 unknown,
+                        // u32 JPEG length, then the JPEG itself.
+                        candidates.push(EmbeddedJpegInfo::new(
+                            child.offset + 16,
+                            read_u32_at(child.payload, 12)?.into(),
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn find_moov_jpegs(
+    raw_buf: &[u8],
+    moov: &Mp4Box,
+    candidates: &mut Vec<EmbeddedJpegInfo>,
+) -> Result<()> {
+    for child in moov.children() {
+        let child = child?;
+        match (child.kind, child.uuid) {
+            (b"uuid", Some(CANON_METADATA_UUID)) => {
+                if let Some(thmb) = child.child(b"THMB")? {
+                    // Layout: u32 version and flags, u16 width, u16 height, u32 JPEG length, u32
+                    // unknown, then the JPEG itself.
+                    candidates.push(EmbeddedJpegInfo::new(
+                        thmb.offset + 16,
+                        read_u32_at(thmb.payload, 8)?.into(),
+                    ));
+                }
+            }
+            (b"trak", _) => {
+                if let Some(jpeg) = find_trak_jpeg(raw_buf, &child)? {
+                    candidates.push(jpeg);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the location of the first sample in a track, and return it if it's a JPEG. In CR3, one of
+/// the tracks holds a full size JPEG, and the others hold CRX encoded raw data.
+fn find_trak_jpeg(raw_buf: &[u8], trak: &Mp4Box) -> Result<Option<EmbeddedJpegInfo>> {
+    let Some(stbl) = trak.descendant(&[b"mdia", b"minf", b"stbl"])? else {
+        return Ok(None);
+    };
+
+    // stsz: u32 version and flags, u32 sample size (0 if they vary), u32 sample count, then a u32
+    // size for each sample if they vary.
+    let Some(stsz) = stbl.child(b"stsz")? else {
+        return Ok(None);
+    };
+    let length = match read_u32_at(stsz.payload, 4)? {
+        0 => read_u32_at(stsz.payload, 12)?,
+        size => size,
+    };
+
+    // co64/stco: u32 version and flags, u32 entry count, then a u64/u32 offset for each chunk.
+    let offset = if let Some(co64) = stbl.child(b"co64")? {
+        let bytes = co64.payload.get(8..16).context("Truncated co64 box")?;
+        BigEndian::read_u64(bytes)
+    } else if let Some(stco) = stbl.child(b"stco")? {
+        read_u32_at(stco.payload, 8)?.into()
+    } else {
+        return Ok(None);
+    };
+
+    if !get_from(raw_buf, offset).is_some_and(|data| data.starts_with(JPEG_SOI)) {
+        return Ok(None);
+    }
+
+    Ok(Some(EmbeddedJpegInfo::new(offset, length.into())))
+}
+
+/// Find the JPEG items in a HEIF meta box.
+///
+/// iinf tells us the type of each item, and iloc tells us where its data is. Most HEIF files only
+/// contain HEVC images, which we can't do anything with, so we say so rather than claiming there
+/// were no images at all.
+fn find_meta_jpegs(meta: &Mp4Box, candidates: &mut Vec<EmbeddedJpegInfo>) -> Result<()> {
+    // meta is a full box, so the children follow the version and flags.
+    let children = meta.payload.get(4..).context("Truncated meta box")?;
+    let mut jpeg_items = Vec::new();
+    let mut found_hevc = false;
+    let mut iloc = None;
+
+    for child in Boxes::new(children, meta.offset + 4) {
+        let child = child?;
+        match child.kind {
+            b"iinf" => {
+                let mut reader = FieldReader::new(child.payload);
+                let version = reader.u8()?;
+                let _flags = reader.bytes(3)?;
+                let _entry_count = if version == 0 {
+                    reader.u16()?.into()
+                } else {
+                    reader.u32()?
+                };
+                let entries = &child.payload[reader.pos..];
+                for infe in Boxes::new(entries, child.offset + u64::try_from(reader.pos)?) {
+                    let infe = infe?;
+                    if infe.kind != b"infe" {
+                        continue;
+                    }
+                    let mut reader = FieldReader::new(infe.payload);
+                    let version = reader.u8()?;
+                    let _flags = reader.bytes(3)?;
+                    if version < 2 {
+                        // Older infe versions have no item type.
+                        continue;
+                    }
+                    let item_id = if version == 2 {
+                        reader.u16()?.into()
+                    } else {
+                        reader.u32()?
+                    };
+                    let _protection_index = reader.u16()?;
+                    match reader.bytes(4)? {
+                        b"jpeg" => jpeg_items.push(item_id),
+                        b"hvc1" => found_hevc = true,
+                        _ => {}
+                    }
+                }
+            }
+            b"iloc" => iloc = Some(child),
+            _ => {}
+        }
+    }
+
+    if jpeg_items.is_empty() {
+        ensure!(
+            !found_hevc,
+            "HEIF file only contains HEVC images, which can't be extracted as JPEG"
+        );
+        return Ok(());
+    }
+
+    let iloc = iloc.context("HEIF file has no iloc box")?;
+    let mut reader = FieldReader::new(iloc.payload);
+    let version = reader.u8()?;
+    let _flags = reader.bytes(3)?;
+    let sizes = reader.u8()?;
+    let (offset_size, length_size) = (sizes >> 4, sizes & 0xf);
+    let sizes = reader.u8()?;
+    let base_offset_size = sizes >> 4;
+    let index_size = if version == 0 { 0 } else { sizes & 0xf };
+    let item_count = if version < 2 {
+        reader.u16()?.into()
+    } else {
+        reader.u32()?
+    };
+
+    for _ in 0..item_count {
+        let item_id = if version < 2 {
+            reader.u16()?.into()
+        } else {
+            reader.u32()?
+        };
+        let construction_method = if version == 0 { 0 } else { reader.u16()? & 0xf };
+        let _data_reference_index = reader.u16()?;
+        let base_offset = reader.uint(base_offset_size)?;
+        let extent_count = reader.u16()?;
+
+        // We can only point at data which lives in one piece in the file itself, rather than in
+        // idat or split across extents, so items with any other number of extents are skipped
+        // without reading them. Their extents can be 0 bytes each, so reading them one by one
+        // could take forever.
+        if extent_count != 1 {
+            let extent_size =
+                usize::from(index_size) + usize::from(offset_size) + usize::from(length_size);
+            reader.bytes(extent_size * usize::from(extent_count))?;
+            continue;
+        }
+        let _extent_index = reader.uint(index_size)?;
+        let offset = reader.uint(offset_size)?;
+        let length = reader.uint(length_size)?;
+
+        if construction_method == 0 && jpeg_items.contains(&item_id) {
+            let offset = base_offset
+                .checked_add(offset)
+                .context("HEIF item offset overflows")?;
+            candidates.push(EmbeddedJpegInfo::new(offset, length));
+        }
+    }
+
+    Ok(())
+}
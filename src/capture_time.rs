@@ -0,0 +1,45 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone, Timelike};
+use rawtojpg::Metadata;
+
+/// When a picture was taken, from its DateTimeOriginal and SubSecTimeOriginal, in the time zone
+/// its OffsetTimeOriginal gives. Most cameras don't record that, so otherwise, or if it doesn't
+/// make sense, the clock is taken to be at `camera`. Their clocks are usually set to wherever they
+/// are, so the local time zone is assumed without that.
+pub fn parse(metadata: &Metadata, camera: Option<FixedOffset>) -> Option<DateTime<FixedOffset>> {
+    let date = metadata.date_time_original.as_deref()?;
+    let mut naive = NaiveDateTime::parse_from_str(date, "%Y:%m:%d %H:%M:%S").ok()?;
+    if let Some(fraction) = metadata
+        .sub_sec_time_original
+        .as_deref()
+        .filter(|fraction| {
+            !fraction.is_empty() && fraction.bytes().all(|byte| byte.is_ascii_digit())
+        })
+    {
+        // This is a decimal fraction of a second, so it has to be padded out to nanoseconds.
+        let nanos = format!("{:0<9.9}", fraction).parse().ok()?;
+        naive = naive.with_nanosecond(nanos)?;
+    }
+    let offset = metadata
+        .offset_time_original
+        .as_deref()
+        .and_then(|offset| offset.parse::<FixedOffset>().ok());
+    match offset.or(camera) {
+        Some(offset) => offset.from_local_datetime(&naive).single(),
+        None => Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|time| time.fixed_offset()),
+    }
+}
+
+/// Parse a time zone offset, like +02:00, -0530, or UTC.
+pub fn parse_offset(offset: &str) -> Result<FixedOffset> {
+    if offset.eq_ignore_ascii_case("utc") || offset == "Z" {
+        return Ok(FixedOffset::east_opt(0).expect("UTC is a valid offset"));
+    }
+    offset
+        .parse()
+        .ok()
+        .with_context(|| format!("Time zone offset must be like +02:00, not {offset}"))
+}
@@ -0,0 +1,77 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset};
+use rusqlite::{params, Connection};
+use std::path::{Path, PathBuf};
+
+/// The previews which have been written, for --catalog, so that they can be looked up by when
+/// they were taken or with what, without reading them all. There's a row for each preview, which
+/// is replaced whenever it's written again. Paths are absolute, and hashes are SHA-256s.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS previews (
+        path TEXT PRIMARY KEY,
+        source TEXT NOT NULL,
+        taken_at TEXT,
+        make TEXT,
+        model TEXT,
+        lens TEXT,
+        width INTEGER,
+        height INTEGER,
+        sha256 TEXT NOT NULL,
+        source_sha256 TEXT NOT NULL,
+        written_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS previews_source ON previews (source);
+    CREATE INDEX IF NOT EXISTS previews_taken_at ON previews (taken_at);
+";
+
+/// What --catalog records about each preview.
+pub struct Entry {
+    /// Where the preview was written.
+    pub path: PathBuf,
+    /// The RAW file it came from.
+    pub source: PathBuf,
+    /// When the picture was taken, which goes in as RFC 3339.
+    pub taken_at: Option<DateTime<FixedOffset>>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    /// The width and height of the preview as it was written, if they could be read from it.
+    pub dimensions: Option<(u32, u32)>,
+    pub sha256: String,
+    pub source_sha256: String,
+}
+
+/// Add `entries` to the catalog at `path`, creating it if it isn't there. They all go in at once,
+/// so that the catalog never has only some of a run in it.
+pub fn write(path: &Path, entries: &[&Entry]) -> Result<()> {
+    let mut connection = Connection::open(path)
+        .with_context(|| format!("Failed to open catalog {}", path.display()))?;
+    connection.execute_batch(SCHEMA)?;
+    let transaction = connection.transaction()?;
+    {
+        let mut insert = transaction.prepare(
+            "INSERT OR REPLACE INTO previews (
+                 path, source, taken_at, make, model, lens, width, height, sha256, source_sha256,
+                 written_at
+             )
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, unixepoch())",
+        )?;
+        for entry in entries {
+            let (width, height) = entry.dimensions.unzip();
+            insert.execute(params![
+                entry.path.to_string_lossy(),
+                entry.source.to_string_lossy(),
+                entry.taken_at.map(|time| time.to_rfc3339()),
+                entry.make,
+                entry.model,
+                entry.lens,
+                width,
+                height,
+                entry.sha256,
+                entry.source_sha256,
+            ])?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
@@ -0,0 +1,17 @@
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// The SHA-256 of `data` in lowercase hex, which is how sha256sum and most other tools show it.
+pub fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// The BLAKE3 of `data` in lowercase hex, which is how b3sum shows it.
+pub fn blake3(data: &[u8]) -> String {
+    blake3::hash(data).to_hex().to_string()
+}
@@ -0,0 +1,125 @@
+use crate::EmbeddedJpegInfo;
+use anyhow::{ensure, Context, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::collections::HashSet;
+
+const CIFF_SIGNATURE: &[u8] = b"HEAPCCDR";
+
+/// How many records to look at in a whole file at most. Real CRWs have a few hundred, so this only
+/// stops files made to take forever, like ones with many records all pointing at the same heaps.
+const MAX_RECORDS: usize = 65536;
+
+/// Check whether a buffer looks like a CIFF file (Canon CRW), which has a byte order mark, a u32
+/// header length, and then the "HEAPCCDR" signature.
+pub fn is_ciff(buf: &[u8]) -> bool {
+    (buf.starts_with(b"II") || buf.starts_with(b"MM")) && buf.get(6..14) == Some(CIFF_SIGNATURE)
+}
+
+struct Heap<'a> {
+    raw_buf: &'a [u8],
+    read_u16: fn(&[u8]) -> u16,
+    read_u32: fn(&[u8]) -> u32,
+    /// The ranges of the heaps walked so far, so that none is walked twice.
+    walked: HashSet<(usize, usize)>,
+    /// How many more records can be looked at, out of MAX_RECORDS.
+    records_left: usize,
+}
+
+impl Heap<'_> {
+    /// Walk the heap at the given absolute range, recursing into any sub-heaps which haven't been
+    /// walked already.
+    ///
+    /// The last u32 in a heap is the offset of its record table, relative to the start of the
+    /// heap. The table is a u16 count followed by 10 byte records: u16 tag, u32 size, and u32
+    /// offset (again relative to the heap).
+    fn walk(
+        &mut self,
+        start: usize,
+        length: usize,
+        depth: usize,
+        candidates: &mut Vec<EmbeddedJpegInfo>,
+    ) -> Result<()> {
+        const MAX_DEPTH: usize = 8;
+        const RECORD_SIZE: usize = 10;
+        const STORAGE_MASK: u16 = 0xc000;
+        const STORAGE_IN_HEAP: u16 = 0x0000;
+        const TYPE_MASK: u16 = 0x3800;
+        const TYPE_SUBHEAP: [u16; 2] = [0x2800, 0x3000];
+        const ID_MASK: u16 = 0x3fff;
+        const JPEG_IDS: [u16; 2] = [0x2007, 0x2008];
+
+        ensure!(depth < MAX_DEPTH, "CIFF heaps are nested too deeply");
+        let heap = start
+            .checked_add(length)
+            .and_then(|end| self.raw_buf.get(start..end))
+            .with_context(|| format!("CIFF heap at offset {start} exceeds file size"))?;
+        ensure!(heap.len() >= 4, "Truncated CIFF heap");
+
+        let table_offset: usize = (self.read_u32)(&heap[heap.len() - 4..]).try_into()?;
+        let table = heap
+            .get(table_offset..)
+            .filter(|table| table.len() >= 2)
+            .with_context(|| format!("CIFF record table in heap at offset {start} exceeds heap"))?;
+        let num_records = (self.read_u16)(&table[..2]).into();
+
+        for record in table[2..].chunks_exact(RECORD_SIZE).take(num_records) {
+            self.records_left = self
+                .records_left
+                .checked_sub(1)
+                .context("Too many CIFF records")?;
+            let tag = (self.read_u16)(&record[..2]);
+            if tag & STORAGE_MASK != STORAGE_IN_HEAP {
+                // The value is stored inline in the record, so it can't be a JPEG or a heap.
+                continue;
+            }
+
+            let size: usize = (self.read_u32)(&record[2..6]).try_into()?;
+            let offset = start
+                .checked_add((self.read_u32)(&record[6..10]).try_into()?)
+                .context("CIFF record offset overflows")?;
+
+            if JPEG_IDS.contains(&(tag & ID_MASK)) {
+                candidates.push(EmbeddedJpegInfo::new(offset.try_into()?, size.try_into()?));
+            } else if TYPE_SUBHEAP.contains(&(tag & TYPE_MASK))
+                && self.walked.insert((offset, size))
+            {
+                self.walk(offset, size, depth + 1, candidates)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Find all embedded JPEGs in a CIFF file, as used by older Canon cameras for CRW.
+///
+/// CIFF is not TIFF based: after the header, the rest of the file is one big heap of records,
+/// some of which are themselves heaps. The JPEGs are stored in JpgFromRaw (0x2007) and
+/// ThumbnailImage (0x2008) records.
+pub fn find_jpegs(raw_buf: &[u8], candidates: &mut Vec<EmbeddedJpegInfo>) -> Result<()> {
+    let is_le = raw_buf.starts_with(b"II");
+
+    let read_u16 = if is_le {
+        LittleEndian::read_u16
+    } else {
+        BigEndian::read_u16
+    };
+
+    let read_u32 = if is_le {
+        LittleEndian::read_u32
+    } else {
+        BigEndian::read_u32
+    };
+
+    let header_len: usize = read_u32(&raw_buf[2..6]).try_into()?;
+    ensure!(header_len <= raw_buf.len(), "CIFF header exceeds file size");
+
+    let mut heap = Heap {
+        raw_buf,
+        read_u16,
+        read_u32,
+        walked: HashSet::new(),
+        records_left: MAX_RECORDS,
+    };
+    heap.walk(header_len, raw_buf.len() - header_len, 0, candidates)
+}
@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The formats contact sheets can be written in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum SheetFormat {
+    /// One JPEG per sheet
+    #[default]
+    Jpg,
+    /// One PDF per directory, with a page per sheet
+    Pdf,
+}
+
+/// How to lay out contact sheets.
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    pub columns: u32,
+    pub rows: u32,
+    /// The width and height of each cell, including the gap around the thumbnail in it.
+    pub cell: u32,
+    pub format: SheetFormat,
+    pub quality: u8,
+}
+
+const BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+/// The gap around each thumbnail, as a fraction of the cell size.
+const GAP: f32 = 0.04;
+/// The resolution PDF pages are sized for, so that sheets print at a sensible size.
+const PDF_DPI: f32 = 150.0;
+
+/// Make a thumbnail of a preview which fits in a cell. It's kept as a JPEG, since there can be
+/// thousands of them in memory before the sheets are put together.
+pub fn thumbnail(image: &DynamicImage, cell: u32, quality: u8) -> Result<Vec<u8>> {
+    let size = inner_size(cell);
+    let thumbnail = image.thumbnail(size, size).into_rgb8();
+    encode(&thumbnail, quality)
+}
+
+fn inner_size(cell: u32) -> u32 {
+    (cell - 2 * (cell as f32 * GAP).round() as u32).max(1)
+}
+
+fn encode(image: &RgbImage, quality: u8) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    image.write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality))?;
+    Ok(out)
+}
+
+/// Tile thumbnails into sheets, in the order given, each centred in its cell.
+fn sheets(thumbnails: &[&[u8]], layout: Layout) -> Result<Vec<RgbImage>> {
+    let per_sheet = (layout.columns * layout.rows) as usize;
+    thumbnails
+        .chunks(per_sheet)
+        .map(|chunk| {
+            let rows = (chunk.len() as u32).div_ceil(layout.columns);
+            let mut sheet =
+                RgbImage::from_pixel(layout.columns * layout.cell, rows * layout.cell, BACKGROUND);
+            for (index, thumbnail) in (0..).zip(chunk.iter()) {
+                let thumbnail =
+                    image::load_from_memory_with_format(thumbnail, ImageFormat::Jpeg)?.into_rgb8();
+                let x = (index % layout.columns) * layout.cell
+                    + (layout.cell - thumbnail.width().min(layout.cell)) / 2;
+                let y = (index / layout.columns) * layout.cell
+                    + (layout.cell - thumbnail.height().min(layout.cell)) / 2;
+                imageops::overlay(&mut sheet, &thumbnail, x.into(), y.into());
+            }
+            Ok(sheet)
+        })
+        .collect()
+}
+
+/// Make contact sheets for each output directory, from thumbnails keyed by the preview they're of,
+/// returning where each should be written and its contents. Sheets are named contact-sheet.jpg,
+/// or contact-sheet-1.jpg and so on if a directory needs more than one, or contact-sheet.pdf.
+pub fn render(
+    thumbnails: &BTreeMap<&Path, &[u8]>,
+    layout: Layout,
+) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut by_dir: BTreeMap<&Path, Vec<&[u8]>> = BTreeMap::new();
+    for (output_file, thumbnail) in thumbnails {
+        let dir = output_file.parent().unwrap_or(Path::new("."));
+        by_dir.entry(dir).or_default().push(*thumbnail);
+    }
+
+    let mut out = Vec::new();
+    for (dir, thumbnails) in by_dir {
+        let sheets = sheets(&thumbnails, layout)
+            .with_context(|| format!("Failed to make contact sheet for {}", dir.display()))?;
+        let pages = sheets
+            .iter()
+            .map(|sheet| {
+                Ok((
+                    sheet.width(),
+                    sheet.height(),
+                    encode(sheet, layout.quality)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        match layout.format {
+            SheetFormat::Pdf => out.push((dir.join("contact-sheet.pdf"), pdf(&pages))),
+            SheetFormat::Jpg => {
+                let numbered = pages.len() > 1;
+                for (number, (_, _, jpeg)) in (1..).zip(pages) {
+                    let name = if numbered {
+                        format!("contact-sheet-{number}.jpg")
+                    } else {
+                        "contact-sheet.jpg".to_string()
+                    };
+                    out.push((dir.join(name), jpeg));
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Make a PDF with a page for each JPEG, which are embedded as they are.
+fn pdf(pages: &[(u32, u32, Vec<u8>)]) -> Vec<u8> {
+    let mut out = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::new();
+    let mut object = |out: &mut Vec<u8>, body: &[u8]| {
+        offsets.push(out.len());
+        out.extend_from_slice(format!("{} 0 obj\n", offsets.len()).as_bytes());
+        out.extend_from_slice(body);
+        out.extend_from_slice(b"\nendobj\n");
+    };
+
+    // The catalog and page tree come first, then a page, its contents, and its image for each
+    // sheet.
+    let kids = (0..pages.len())
+        .map(|page| format!("{} 0 R", 3 + 3 * page))
+        .collect::<Vec<_>>()
+        .join(" ");
+    object(&mut out, b"<< /Type /Catalog /Pages 2 0 R >>");
+    object(
+        &mut out,
+        format!("<< /Type /Pages /Kids [{kids}] /Count {} >>", pages.len()).as_bytes(),
+    );
+    for (page, (width, height, jpeg)) in pages.iter().enumerate() {
+        let id = 3 + 3 * page;
+        let points = |pixels: u32| pixels as f32 * 72.0 / PDF_DPI;
+        let (page_width, page_height) = (points(*width), points(*height));
+        object(
+            &mut out,
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {page_width:.2} {page_height:.2}] \
+                 /Contents {} 0 R /Resources << /XObject << /Im0 {} 0 R >> >> >>",
+                id + 1,
+                id + 2
+            )
+            .as_bytes(),
+        );
+        let contents = format!("q {page_width:.2} 0 0 {page_height:.2} 0 0 cm /Im0 Do Q");
+        object(
+            &mut out,
+            format!(
+                "<< /Length {} >>\nstream\n{contents}\nendstream",
+                contents.len()
+            )
+            .as_bytes(),
+        );
+        let mut image = format!(
+            "<< /Type /XObject /Subtype /Image /Width {width} /Height {height} \
+             /ColorSpace /DeviceRGB /BitsPerComponent 8 /Filter /DCTDecode /Length {} >>\nstream\n",
+            jpeg.len()
+        )
+        .into_bytes();
+        image.extend_from_slice(jpeg);
+        image.extend_from_slice(b"\nendstream");
+        object(&mut out, &image);
+    }
+
+    let xref = out.len();
+    out.extend_from_slice(
+        format!("xref\n0 {}\n0000000000 65535 f \n", offsets.len() + 1).as_bytes(),
+    );
+    for offset in &offsets {
+        out.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+    }
+    out.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            offsets.len() + 1
+        )
+        .as_bytes(),
+    );
+    out
+}
@@ -0,0 +1,23 @@
+use anyhow::{Context, Result};
+use zune_jpeg::zune_core::options::DecoderOptions;
+use zune_jpeg::JpegDecoder;
+
+/// Decode a whole JPEG, throwing away the result, to make sure that it's intact.
+///
+/// Strict mode makes the decoder fail on anything which doesn't conform to the spec, rather than
+/// trying to produce whatever image it can, which is what we want here.
+pub fn verify(data: &[u8]) -> Result<()> {
+    let options = DecoderOptions::default().set_strict_mode(true);
+    let mut decoder = JpegDecoder::new_with_options(data, options);
+    decoder
+        .decode_headers()
+        .context("Failed to decode JPEG headers")?;
+    let size = decoder
+        .output_buffer_size()
+        .context("JPEG is too large to decode")?;
+    let mut pixels = vec![0; size];
+    decoder
+        .decode_into(&mut pixels)
+        .context("Failed to decode JPEG")?;
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use glob::MatchOptions;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// `*` and `?` don't match slashes, like in rsync, but `**` does.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// A pattern for --exclude, following rsync: those without slashes match the name of a file or
+/// directory anywhere, like "*.tmp", and the rest match whole paths under the input directory,
+/// like "**/rejects/**". A trailing slash means only directories match.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    pattern: glob::Pattern,
+    whole_path: bool,
+    dirs_only: bool,
+}
+
+impl Pattern {
+    /// Whether the file or directory at `relative_path` under the input directory is excluded.
+    pub fn matches(&self, relative_path: &Path, is_dir: bool) -> bool {
+        if self.dirs_only && !is_dir {
+            return false;
+        }
+        if self.whole_path {
+            self.pattern.matches_path_with(relative_path, MATCH_OPTIONS)
+        } else {
+            relative_path.file_name().is_some_and(|name| {
+                self.pattern
+                    .matches_path_with(Path::new(name), MATCH_OPTIONS)
+            })
+        }
+    }
+}
+
+/// Parse a pattern for --exclude.
+pub fn parse(pattern: &str) -> Result<Pattern> {
+    let (pattern_str, dirs_only) = match pattern.strip_suffix('/') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    // A leading slash only anchors the pattern to the input directory, which it already is.
+    let whole_path = pattern_str.contains('/');
+    let pattern_str = pattern_str.trim_start_matches('/');
+    Ok(Pattern {
+        pattern: glob::Pattern::new(pattern_str)
+            .with_context(|| format!("Invalid pattern {pattern}"))?,
+        whole_path,
+        dirs_only,
+    })
+}
+
+/// A regex for --match or --no-match. Like --exclude patterns, those with slashes match whole
+/// paths under the input directory, and the rest match file names. They match anywhere unless
+/// they're anchored with ^ and $.
+#[derive(Clone, Debug)]
+pub struct Regex {
+    regex: regex::bytes::Regex,
+    whole_path: bool,
+}
+
+impl Regex {
+    /// Whether the file at `relative_path` under the input directory matches.
+    pub fn matches(&self, relative_path: &Path) -> bool {
+        let haystack = if self.whole_path {
+            relative_path.as_os_str()
+        } else {
+            relative_path.file_name().unwrap_or_default()
+        };
+        self.regex.is_match(haystack.as_bytes())
+    }
+}
+
+/// Parse a regex for --match or --no-match.
+pub fn parse_regex(regex: &str) -> Result<Regex> {
+    Ok(Regex {
+        regex: regex::bytes::Regex::new(regex).with_context(|| format!("Invalid regex {regex}"))?,
+        whole_path: regex.contains('/'),
+    })
+}
@@ -0,0 +1,251 @@
+//! Writing Exif, for copying the main metadata from a RAW into its previews, which often have
+//! little or none of their own.
+
+use crate::tiff::{Tiff, EXIF_IFD_TAG, GPS_IFD_TAG, INTEROP_IFD_TAG, TYPE_LONG};
+
+/// The tags copied from IFD0: who made the picture, with what, and when. Anything describing the
+/// RAW image data itself is left out, since the preview's own markers say that.
+const IFD0_TAGS: &[u16] = &[
+    0x10e,  // ImageDescription
+    0x10f,  // Make
+    0x110,  // Model
+    0x112,  // Orientation
+    0x131,  // Software
+    0x132,  // DateTime
+    0x13b,  // Artist
+    0x8298, // Copyright
+];
+
+/// The tags copied from the Exif IFD: the exposure, the dates, the colour space, and the lens and
+/// body. MakerNotes are left out, since they're big and often have offsets into the RAW.
+const EXIF_TAGS: &[u16] = &[
+    0x829a, // ExposureTime
+    0x829d, // FNumber
+    0x8822, // ExposureProgram
+    0x8827, // ISOSpeedRatings
+    0x8830, // SensitivityType
+    0x9000, // ExifVersion
+    0x9003, // DateTimeOriginal
+    0x9004, // DateTimeDigitized
+    0x9010, // OffsetTime
+    0x9011, // OffsetTimeOriginal
+    0x9012, // OffsetTimeDigitized
+    0x9201, // ShutterSpeedValue
+    0x9202, // ApertureValue
+    0x9203, // BrightnessValue
+    0x9204, // ExposureBiasValue
+    0x9205, // MaxApertureValue
+    0x9207, // MeteringMode
+    0x9208, // LightSource
+    0x9209, // Flash
+    0x920a, // FocalLength
+    0x9290, // SubSecTime
+    0x9291, // SubSecTimeOriginal
+    0x9292, // SubSecTimeDigitized
+    0xa001, // ColorSpace
+    0xa402, // ExposureMode
+    0xa403, // WhiteBalance
+    0xa405, // FocalLengthIn35mmFilm
+    0xa406, // SceneCaptureType
+    0xa430, // CameraOwnerName
+    0xa431, // BodySerialNumber
+    0xa432, // LensSpecification
+    0xa433, // LensMake
+    0xa434, // LensModel
+    0xa435, // LensSerialNumber
+];
+
+/// The tags copied from the interoperability IFD, which some viewers use for the colour space.
+const INTEROP_TAGS: &[u16] = &[
+    0x1, // InteropIndex
+    0x2, // InteropVersion
+];
+
+/// An entry to be written, with its values already in the right byte order.
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    data: Vec<u8>,
+}
+
+/// An IFD to be written, and the IFDs it points to, each with the tag which points to it.
+#[derive(Default)]
+struct Ifd {
+    entries: Vec<Entry>,
+    children: Vec<(u16, Ifd)>,
+}
+
+impl Ifd {
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.children.is_empty()
+    }
+
+    fn add_child(&mut self, tag: u16, child: Ifd) {
+        if !child.is_empty() {
+            self.children.push((tag, child));
+        }
+    }
+
+    /// The size of just this IFD, with the values which don't fit in its entries.
+    fn own_size(&self) -> usize {
+        let entries = self.entries.len() + self.children.len();
+        let data: usize = self
+            .entries
+            .iter()
+            .filter(|entry| entry.data.len() > 4)
+            .map(|entry| entry.data.len().next_multiple_of(2))
+            .sum();
+        2 + 12 * entries + 4 + data
+    }
+
+    /// The size of this IFD and all of the IFDs under it.
+    fn size(&self) -> usize {
+        self.own_size()
+            + self
+                .children
+                .iter()
+                .map(|(_, child)| child.size())
+                .sum::<usize>()
+    }
+
+    /// Write this IFD at the end of `out`, followed by its values and then its children. Offsets
+    /// are from the start of `out`, which is the start of the TIFF structure.
+    fn write(&self, out: &mut Vec<u8>, order: ByteOrder) {
+        let start = out.len();
+        let mut child_offset = start + self.own_size();
+        let mut pointers = Vec::with_capacity(self.children.len());
+        for (tag, child) in &self.children {
+            pointers.push(Entry {
+                tag: *tag,
+                kind: TYPE_LONG,
+                count: 1,
+                data: order.u32(child_offset as u32).to_vec(),
+            });
+            child_offset += child.size();
+        }
+        let mut entries: Vec<&Entry> = self.entries.iter().chain(&pointers).collect();
+        entries.sort_by_key(|entry| entry.tag);
+
+        out.extend_from_slice(&order.u16(entries.len() as u16));
+        let mut data_offset = start + 2 + 12 * entries.len() + 4;
+        for entry in &entries {
+            out.extend_from_slice(&order.u16(entry.tag));
+            out.extend_from_slice(&order.u16(entry.kind));
+            out.extend_from_slice(&order.u32(entry.count));
+            if entry.data.len() > 4 {
+                out.extend_from_slice(&order.u32(data_offset as u32));
+                data_offset += entry.data.len().next_multiple_of(2);
+            } else {
+                let mut value = [0; 4];
+                value[..entry.data.len()].copy_from_slice(&entry.data);
+                out.extend_from_slice(&value);
+            }
+        }
+        // There's never a next IFD, since the thumbnail isn't copied.
+        out.extend_from_slice(&[0; 4]);
+        for entry in entries.iter().filter(|entry| entry.data.len() > 4) {
+            out.extend_from_slice(&entry.data);
+            if entry.data.len() % 2 == 1 {
+                out.push(0);
+            }
+        }
+
+        for (_, child) in &self.children {
+            child.write(out, order);
+        }
+    }
+}
+
+/// The byte order to write in, which is the same as the RAW's, so that values can be copied as
+/// they are without knowing what's in them.
+#[derive(Clone, Copy)]
+struct ByteOrder {
+    little_endian: bool,
+}
+
+impl ByteOrder {
+    fn u16(self, value: u16) -> [u8; 2] {
+        if self.little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        }
+    }
+
+    fn u32(self, value: u32) -> [u8; 4] {
+        if self.little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        }
+    }
+}
+
+/// Copy the entries of the IFD at `offset` whose tags `keep` says to. Entries of types we don't
+/// know the size of, or whose values aren't within the file, are skipped.
+fn copy_ifd(tiff: &Tiff, offset: u64, keep: impl Fn(u16) -> bool) -> Ifd {
+    let Ok((entries, _)) = tiff.read_ifd(offset) else {
+        return Ifd::default();
+    };
+    let entries = entries
+        .filter(|entry| keep(entry.tag))
+        .filter_map(|entry| {
+            Some(Entry {
+                tag: entry.tag,
+                kind: entry.kind,
+                count: entry.count.try_into().ok()?,
+                data: tiff.entry_bytes(&entry)?.to_vec(),
+            })
+        })
+        .collect();
+    Ifd {
+        entries,
+        children: Vec::new(),
+    }
+}
+
+/// Find where the IFD pointed to by `tag` in the IFD at `offset` is.
+fn find_pointer(tiff: &Tiff, offset: u64, tag: u16) -> Option<u64> {
+    let (mut entries, _) = tiff.read_ifd(offset).ok()?;
+    entries
+        .find(|entry| entry.tag == tag)
+        .and_then(|entry| tiff.entry_uint(&entry))
+        .filter(|&offset| offset != 0)
+}
+
+/// Build a TIFF structure for an Exif segment from the main metadata of a TIFF based RAW, with
+/// IFD0, the Exif IFD, its interoperability IFD, and all of the GPS IFD if `gps` is set. Returns
+/// `None` for other formats, BigTIFFs, which can't go in Exif, and files with nothing to copy.
+pub(crate) fn from_raw(raw_buf: &[u8], gps: bool) -> Option<Vec<u8>> {
+    let tiff = Tiff::new(raw_buf).ok().filter(|tiff| !tiff.is_big())?;
+    let order = ByteOrder {
+        little_endian: tiff.is_little_endian(),
+    };
+    let ifd0_offset = tiff.first_ifd_offset();
+
+    let mut ifd0 = copy_ifd(&tiff, ifd0_offset, |tag| IFD0_TAGS.contains(&tag));
+    if let Some(exif_offset) = find_pointer(&tiff, ifd0_offset, EXIF_IFD_TAG) {
+        let mut exif = copy_ifd(&tiff, exif_offset, |tag| EXIF_TAGS.contains(&tag));
+        if let Some(interop_offset) = find_pointer(&tiff, exif_offset, INTEROP_IFD_TAG) {
+            let interop = copy_ifd(&tiff, interop_offset, |tag| INTEROP_TAGS.contains(&tag));
+            exif.add_child(INTEROP_IFD_TAG, interop);
+        }
+        ifd0.add_child(EXIF_IFD_TAG, exif);
+    }
+    if let Some(gps_offset) = find_pointer(&tiff, ifd0_offset, GPS_IFD_TAG).filter(|_| gps) {
+        ifd0.add_child(GPS_IFD_TAG, copy_ifd(&tiff, gps_offset, |_| true));
+    }
+    if ifd0.is_empty() {
+        return None;
+    }
+
+    let mut out = if order.little_endian {
+        b"II*\0".to_vec()
+    } else {
+        b"MM\0*".to_vec()
+    };
+    out.extend_from_slice(&order.u32(8));
+    ifd0.write(&mut out, order);
+    Some(out)
+}
@@ -0,0 +1,143 @@
+use anyhow::Result;
+use rawtojpg::Metadata;
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+
+/// What --json records about each RAW file, with the names and formatting `exiftool -json` uses
+/// for the same tags, so that scripts written for it can read this instead.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Entry {
+    source_file: String,
+    file_name: String,
+    directory: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    orientation: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exposure_time: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    f_number: Option<f64>,
+    #[serde(rename = "ISO", skip_serializing_if = "Option::is_none")]
+    iso: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_time_original: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset_time_original: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub_sec_time_original: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    focal_length: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lens_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview_image_start: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview_image_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview_image: Option<String>,
+}
+
+impl Entry {
+    /// Make the entry for the RAW at `source`, whose preview was read from `ranges`. Where the
+    /// preview is is only given if it was in one piece, since exiftool has no way to say otherwise.
+    pub fn new(
+        source: &Path,
+        metadata: Metadata,
+        orientation: Option<u16>,
+        ranges: &[(u64, u64)],
+    ) -> Self {
+        let (start, length) = match ranges {
+            &[(start, length)] => (Some(start), Some(length)),
+            _ => (None, None),
+        };
+        Self {
+            source_file: source.to_string_lossy().into_owned(),
+            file_name: source
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            directory: source
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .to_string_lossy()
+                .into_owned(),
+            make: metadata.make,
+            model: metadata.model,
+            orientation: orientation.and_then(orientation_name),
+            exposure_time: metadata
+                .exposure_time
+                .map(|seconds| number_or_string(exposure_time(seconds))),
+            f_number: metadata
+                .f_number
+                .map(|f_number| (f_number * 10.0).round() / 10.0),
+            iso: metadata.iso,
+            date_time_original: metadata.date_time_original,
+            offset_time_original: metadata.offset_time_original,
+            sub_sec_time_original: metadata.sub_sec_time_original.map(number_or_string),
+            focal_length: metadata
+                .focal_length
+                .map(|focal_length| format!("{focal_length:.1} mm")),
+            lens_model: metadata.lens,
+            preview_image_start: start,
+            preview_image_length: length,
+            preview_image: length
+                .map(|length| format!("(Binary data {length} bytes, use -b option to extract)")),
+        }
+    }
+}
+
+/// How exiftool shows an Orientation.
+fn orientation_name(orientation: u16) -> Option<&'static str> {
+    Some(match orientation {
+        1 => "Horizontal (normal)",
+        2 => "Mirror horizontal",
+        3 => "Rotate 180",
+        4 => "Mirror vertical",
+        5 => "Mirror horizontal and rotate 270 CW",
+        6 => "Rotate 90 CW",
+        7 => "Mirror horizontal and rotate 90 CW",
+        8 => "Rotate 270 CW",
+        _ => return None,
+    })
+}
+
+/// How exiftool shows an ExposureTime: as a fraction of a second for short exposures, like 1/250,
+/// and in seconds to one decimal place otherwise, like 0.5 or 2.
+pub fn exposure_time(seconds: f64) -> String {
+    if seconds > 0.0 && seconds < 0.25001 {
+        return format!("1/{}", (1.0 / seconds).round());
+    }
+    let text = format!("{seconds:.1}");
+    text.strip_suffix(".0").map(str::to_string).unwrap_or(text)
+}
+
+/// exiftool writes anything which looks like a number as one, except where that would lose a
+/// leading zero.
+fn number_or_string(text: String) -> Value {
+    let (integer, fraction) = text.split_once('.').unwrap_or((&text, "0"));
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+    let looks_numeric =
+        digits(integer) && digits(fraction) && (integer.len() == 1 || !integer.starts_with('0'));
+    if !looks_numeric {
+        Value::from(text)
+    } else if let Ok(number) = text.parse::<u64>() {
+        Value::from(number)
+    } else {
+        text.parse::<f64>().map_or(Value::from(text), Value::from)
+    }
+}
+
+/// Render the entries as a JSON array, sorted by source file like exiftool does for a directory.
+pub fn render(mut entries: Vec<Entry>) -> Result<Vec<u8>> {
+    entries.sort_by(|a, b| a.source_file.cmp(&b.source_file));
+    let mut json = serde_json::to_vec_pretty(&entries)?;
+    json.push(b'\n');
+    Ok(json)
+}
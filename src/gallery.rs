@@ -0,0 +1,138 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// A preview to show in a gallery.
+pub struct Picture<'a> {
+    pub output_file: &'a Path,
+    /// A smaller copy to show on the index page, if there is one.
+    pub small_file: Option<&'a Path>,
+}
+
+const STYLE: &str = "\
+body { margin: 0; padding: 1em; background: #202020; color: #ddd; font-family: sans-serif; }
+h1 { font-size: 1.2em; font-weight: normal; }
+a { color: #9cf; }
+nav a { margin-right: 1em; }
+main { display: grid; grid-template-columns: repeat(auto-fill, minmax(220px, 1fr)); gap: 1em; }
+figure { margin: 0; }
+img { display: block; width: 100%; aspect-ratio: 3 / 2; object-fit: contain; background: #181818; }
+figcaption { margin-top: 0.3em; font-size: 0.8em; overflow-wrap: anywhere; }
+";
+
+/// Make an index.html for each directory under `root` with pictures in it, and each directory
+/// above those, so that they can all be reached from the top. Returns where each should be written
+/// and its contents.
+pub fn render(root: &Path, pictures: &[Picture]) -> Vec<(PathBuf, String)> {
+    let mut by_dir: BTreeMap<&Path, Vec<&Picture>> = BTreeMap::new();
+    for picture in pictures {
+        let mut dir = picture.output_file.parent().unwrap_or(root);
+        by_dir.entry(dir).or_default().push(picture);
+        while dir != root {
+            match dir.parent() {
+                Some(parent) if parent.starts_with(root) => dir = parent,
+                _ => break,
+            }
+            by_dir.entry(dir).or_default();
+        }
+    }
+    let dirs: BTreeSet<&Path> = by_dir.keys().copied().collect();
+
+    by_dir
+        .iter()
+        .map(|(&dir, pictures)| {
+            let subdirs = dirs
+                .iter()
+                .filter(|subdir| subdir.parent() == Some(dir))
+                .filter_map(|subdir| subdir.file_name());
+            // The top level is named after the output directory itself.
+            let relative = dir.strip_prefix(root).unwrap_or(dir);
+            let title = if relative.as_os_str().is_empty() {
+                root.canonicalize()
+                    .ok()
+                    .and_then(|root| root.file_name().map(file_name_string))
+                    .unwrap_or_else(|| ".".to_string())
+            } else {
+                relative.display().to_string()
+            };
+
+            let mut html = String::new();
+            let _ = write!(
+                html,
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                 <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+                 <title>{}</title>\n<style>\n{STYLE}</style>\n</head>\n<body>\n<h1>{}</h1>\n<nav>\n",
+                escape(&title),
+                escape(&title)
+            );
+            if dir != root {
+                html.push_str("<a href=\"../index.html\">..</a>\n");
+            }
+            for subdir in subdirs {
+                let name = subdir.to_string_lossy();
+                let _ = writeln!(
+                    html,
+                    "<a href=\"{}/index.html\">{}/</a>",
+                    escape(&url_encode(&name)),
+                    escape(&name)
+                );
+            }
+            html.push_str("</nav>\n<main>\n");
+
+            let mut pictures = pictures.clone();
+            pictures.sort_by_key(|picture| picture.output_file);
+            for picture in pictures {
+                let link = file_name(picture.output_file);
+                let image = picture.small_file.map_or_else(|| link.clone(), file_name);
+                let _ = writeln!(
+                    html,
+                    "<figure><a href=\"{}\"><img src=\"{}\" loading=\"lazy\" alt=\"\"></a>\
+                     <figcaption>{}</figcaption></figure>",
+                    escape(&url_encode(&link)),
+                    escape(&url_encode(&image)),
+                    escape(&link)
+                );
+            }
+            html.push_str("</main>\n</body>\n</html>\n");
+            (dir.join("index.html"), html)
+        })
+        .collect()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map(file_name_string).unwrap_or_default()
+}
+
+fn file_name_string(name: &OsStr) -> String {
+    name.to_string_lossy().into_owned()
+}
+
+/// Escape text for HTML or XML, both in elements and quoted attributes.
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent-encode a file name for use as a relative URL.
+fn url_encode(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            out.push(char::from(byte));
+        } else {
+            let _ = write!(out, "%{byte:02X}");
+        }
+    }
+    out
+}
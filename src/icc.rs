@@ -0,0 +1,115 @@
+//! Minimal ICC profiles for the colour spaces cameras use, for when the RAW says which one its
+//! previews are in but doesn't include a profile for it.
+
+/// The D50 white point which ICC profiles are always relative to.
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+/// The white point of Adobe RGB itself, which is D65.
+const D65: [f64; 3] = [0.9505, 1.0, 1.0891];
+
+/// The Adobe RGB primaries, adapted to D50, as given in Adobe's own profile.
+const ADOBE_RGB_PRIMARIES: [[f64; 3]; 3] = [
+    [0.60974, 0.31111, 0.01947],
+    [0.20528, 0.62567, 0.06087],
+    [0.14919, 0.06322, 0.74457],
+];
+
+/// Adobe RGB's transfer function, a gamma of 563/256, as a u8Fixed8Number.
+const ADOBE_RGB_GAMMA: u16 = 563;
+
+const HEADER_SIZE: usize = 128;
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz(values: [f64; 3]) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    for value in values {
+        tag.extend_from_slice(&s15_fixed16(value));
+    }
+    tag
+}
+
+fn text(value: &str) -> Vec<u8> {
+    let mut tag = b"text\0\0\0\0".to_vec();
+    tag.extend_from_slice(value.as_bytes());
+    tag.push(0);
+    tag
+}
+
+/// A version 2 textDescriptionType, which has ASCII, Unicode, and ScriptCode versions of the
+/// description, although only the ASCII one has to be filled in.
+fn description(value: &str) -> Vec<u8> {
+    let mut tag = b"desc\0\0\0\0".to_vec();
+    tag.extend_from_slice(&(value.len() as u32 + 1).to_be_bytes());
+    tag.extend_from_slice(value.as_bytes());
+    tag.push(0);
+    // The Unicode language and length, then the ScriptCode code, length, and its fixed 67 bytes.
+    tag.extend_from_slice(&[0; 8]);
+    tag.extend_from_slice(&[0; 3 + 67]);
+    tag
+}
+
+/// Build a profile from `tags`, each of which is a signature and its data. Tags with the same data
+/// share it, which is how the three tone curves are normally stored.
+fn profile(tags: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let table_size = 4 + 12 * tags.len();
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data: Vec<u8> = Vec::new();
+    let mut written: Vec<(&[u8], usize)> = Vec::new();
+
+    for &(signature, tag) in tags {
+        let offset = match written.iter().find(|(existing, _)| *existing == tag) {
+            Some(&(_, offset)) => offset,
+            None => {
+                let offset = HEADER_SIZE + table_size + data.len();
+                data.extend_from_slice(tag);
+                // Every tag has to start on a 4 byte boundary.
+                data.resize(data.len().next_multiple_of(4), 0);
+                written.push((tag, offset));
+                offset
+            }
+        };
+        table.extend_from_slice(signature);
+        table.extend_from_slice(&(offset as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+    }
+
+    let size = HEADER_SIZE + table_size + data.len();
+    let mut header = Vec::with_capacity(size);
+    header.extend_from_slice(&(size as u32).to_be_bytes());
+    // No preferred CMM, version 2.1, a display profile of RGB data with XYZ as the connection
+    // space, and a creation date of 1 January 2000.
+    header.extend_from_slice(b"\0\0\0\0\x02\x10\0\0mntrRGB XYZ ");
+    header.extend_from_slice(&[0x07, 0xd0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+    header.extend_from_slice(b"acsp");
+    // The platform, flags, manufacturer, model, attributes, and perceptual rendering intent.
+    header.extend_from_slice(&[0; 28]);
+    for value in D50 {
+        header.extend_from_slice(&s15_fixed16(value));
+    }
+    header.resize(HEADER_SIZE, 0);
+
+    header.extend_from_slice(&table);
+    header.extend_from_slice(&data);
+    header
+}
+
+/// An ICC profile describing Adobe RGB (1998).
+pub fn adobe_rgb() -> Vec<u8> {
+    let mut curve = b"curv\0\0\0\0\0\0\0\x01".to_vec();
+    curve.extend_from_slice(&ADOBE_RGB_GAMMA.to_be_bytes());
+    let [red, green, blue] = ADOBE_RGB_PRIMARIES.map(xyz);
+
+    profile(&[
+        (b"desc", &description("Adobe RGB (1998) compatible")),
+        (b"cprt", &text("No copyright, use freely")),
+        (b"wtpt", &xyz(D65)),
+        (b"rXYZ", &red),
+        (b"gXYZ", &green),
+        (b"bXYZ", &blue),
+        (b"rTRC", &curve),
+        (b"gTRC", &curve),
+        (b"bTRC", &curve),
+    ])
+}
@@ -0,0 +1,494 @@
+use crate::{tiff, JPEG_SOI};
+use anyhow::{bail, ensure, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::borrow::Cow;
+use std::ops::Range;
+
+const MARKER_TEM: u8 = 0x01;
+const MARKER_APP0: u8 = 0xe0;
+pub(crate) const MARKER_APP1: u8 = 0xe1;
+const MARKER_APP2: u8 = 0xe2;
+const MARKER_APP14: u8 = 0xee;
+const MARKER_APP15: u8 = 0xef;
+const MARKER_COM: u8 = 0xfe;
+pub(crate) const MARKER_RST0: u8 = 0xd0;
+pub(crate) const MARKER_RST7: u8 = 0xd7;
+pub(crate) const MARKER_EOI: u8 = 0xd9;
+pub(crate) const MARKER_SOS: u8 = 0xda;
+/// The start of frame markers for DCT based JPEGs. The others are lossless, and used for RAW data
+/// rather than previews, or aren't markers which start a frame at all.
+pub(crate) const MARKERS_SOF_DCT: &[u8] = &[0xc0, 0xc1, 0xc2, 0xc5, 0xc6, 0xc9, 0xca, 0xcd, 0xce];
+pub(crate) const MARKERS_SOF_LOSSLESS: &[u8] = &[0xc3, 0xc7, 0xcb, 0xcf];
+
+/// Read the marker at `pos`, returning it and the position after it. Markers can be preceded by any
+/// number of fill bytes.
+pub(crate) fn read_marker(data: &[u8], mut pos: usize) -> Option<(u8, usize)> {
+    if *data.get(pos)? != 0xff {
+        return None;
+    }
+    while *data.get(pos)? == 0xff {
+        pos += 1;
+    }
+    Some((data[pos], pos + 1))
+}
+
+/// Work out the length of the JPEG stream at the start of `data`, by walking its markers until
+/// EOI.
+///
+/// Returns `None` if it isn't a well formed JPEG, that is, if the markers don't make sense, it
+/// ends before EOI, or it never has any image data at all.
+pub fn stream_length(data: &[u8]) -> Option<usize> {
+    if !data.starts_with(JPEG_SOI) {
+        return None;
+    }
+
+    let mut pos = JPEG_SOI.len();
+    let mut seen_sos = false;
+
+    loop {
+        let marker;
+        (marker, pos) = read_marker(data, pos)?;
+
+        match marker {
+            MARKER_EOI if seen_sos => return Some(pos),
+            MARKER_EOI | 0x00 => return None,
+            MARKER_TEM | MARKER_RST0..=MARKER_RST7 => {}
+            _ => {
+                let length: usize = BigEndian::read_u16(data.get(pos..pos + 2)?).into();
+                if length < 2 {
+                    return None;
+                }
+                pos += length;
+
+                if marker == MARKER_SOS {
+                    seen_sos = true;
+                    pos = entropy_coded_end(data, pos)?;
+                }
+            }
+        }
+    }
+}
+
+/// Read the width and height from the frame header of the JPEG at the start of `data`.
+///
+/// Returns `None` for lossless JPEGs, which are how some formats (like CR2) store their RAW data,
+/// since they aren't something which can be viewed.
+pub fn dimensions(data: &[u8]) -> Option<(u16, u16)> {
+    if !data.starts_with(JPEG_SOI) {
+        return None;
+    }
+
+    let mut pos = JPEG_SOI.len();
+
+    loop {
+        let marker;
+        (marker, pos) = read_marker(data, pos)?;
+
+        match marker {
+            // The frame header always comes before the image data.
+            MARKER_SOS | MARKER_EOI | 0x00 => return None,
+            MARKER_TEM | MARKER_RST0..=MARKER_RST7 => {}
+            _ if MARKERS_SOF_LOSSLESS.contains(&marker) => return None,
+            _ => {
+                let length: usize = BigEndian::read_u16(data.get(pos..pos + 2)?).into();
+                if MARKERS_SOF_DCT.contains(&marker) {
+                    // The length, then the sample precision, the height, and the width.
+                    let header = data.get(pos + 3..pos + 7)?;
+                    let height = BigEndian::read_u16(&header[..2]);
+                    let width = BigEndian::read_u16(&header[2..]);
+                    // A height of 0 means it's defined later, by a DNL marker after the first scan.
+                    return Some((width, height)).filter(|_| width > 0 && height > 0);
+                }
+                if length < 2 {
+                    return None;
+                }
+                pos += length;
+            }
+        }
+    }
+}
+
+/// Exif is stored in an APP1 segment starting with this, followed by a TIFF structure.
+pub(crate) const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Find the Exif in the JPEG at the start of `data`, returning the TIFF structure in it.
+pub fn exif(data: &[u8]) -> Option<&[u8]> {
+    find_exif(data).map(|range| &data[range])
+}
+
+/// Find where the TIFF structure in the Exif of a JPEG is.
+fn find_exif(data: &[u8]) -> Option<Range<usize>> {
+    find_app_segment(data, MARKER_APP1, EXIF_HEADER)
+}
+
+/// Find the first `app_marker` segment in the JPEG at the start of `data` which starts with `header`,
+/// returning where what comes after the header is.
+fn find_app_segment(data: &[u8], app_marker: u8, header: &[u8]) -> Option<Range<usize>> {
+    if !data.starts_with(JPEG_SOI) {
+        return None;
+    }
+
+    let mut pos = JPEG_SOI.len();
+
+    loop {
+        let marker;
+        (marker, pos) = read_marker(data, pos)?;
+
+        match marker {
+            // Application segments have to come before the image data.
+            MARKER_SOS | MARKER_EOI | 0x00 => return None,
+            MARKER_TEM | MARKER_RST0..=MARKER_RST7 => {}
+            _ => {
+                let length: usize = BigEndian::read_u16(data.get(pos..pos + 2)?).into();
+                if length < 2 {
+                    return None;
+                }
+                let segment = data.get(pos + 2..pos + length)?;
+                if marker == app_marker && segment.starts_with(header) {
+                    return Some(pos + 2 + header.len()..pos + length);
+                }
+                pos += length;
+            }
+        }
+    }
+}
+
+/// Set the Orientation in the Exif of a JPEG. If its Exif has no Orientation, one is added to IFD0,
+/// and if it has no Exif at all, a minimal one with just the Orientation is added, unless it's
+/// upright anyway.
+pub fn set_orientation(data: &mut Cow<'_, [u8]>, orientation: u16) {
+    if let Some(range) = find_exif(data) {
+        match tiff::orientation(&data[range.clone()]) {
+            Some(old) if old != orientation => {
+                tiff::set_orientation(&mut data.to_mut()[range], orientation);
+            }
+            Some(_) => {}
+            None if orientation == 1 => {}
+            None => add_orientation(data, range, orientation),
+        }
+        return;
+    }
+    if orientation == 1 || !data.starts_with(JPEG_SOI) {
+        return;
+    }
+
+    // A big endian TIFF header, then IFD0 with just the Orientation, and no next IFD.
+    let mut exif = EXIF_HEADER.to_vec();
+    exif.extend_from_slice(b"MM\0*\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01");
+    exif.extend_from_slice(&orientation.to_be_bytes());
+    exif.extend_from_slice(&[0; 6]);
+    let mut segment = vec![0xff, MARKER_APP1];
+    segment.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(&exif);
+
+    // JFIF requires its APP0 to come straight after SOI, so Exif has to go after it.
+    let pos = after_segments(data, &[MARKER_APP0]);
+    data.to_mut().splice(pos..pos, segment);
+}
+
+/// Add an Orientation to the Exif at `range` of a JPEG, which doesn't have one. If the Exif can't
+/// be parsed, or there's no room for another entry, the JPEG is left as it is.
+fn add_orientation(data: &mut Cow<'_, [u8]>, range: Range<usize>, orientation: u16) {
+    let exif = &data[range];
+    let Ok(little_endian) = tiff::Tiff::new(exif).map(|tiff| tiff.is_little_endian()) else {
+        return;
+    };
+    let value = if little_endian {
+        orientation.to_le_bytes()
+    } else {
+        orientation.to_be_bytes()
+    };
+    if let Some(tiff) =
+        tiff::with_ifd0_entry(exif, tiff::ORIENTATION_TAG, tiff::TYPE_SHORT, 1, &value)
+    {
+        // This only fails if the Exif would be too big for a JPEG segment.
+        let _ = set_exif(data, &tiff);
+    }
+}
+
+/// Replace the Exif of a JPEG with the TIFF structure `tiff`, or add it after any JFIF segment if
+/// it has none.
+pub fn set_exif(data: &mut Cow<'_, [u8]>, tiff: &[u8]) -> Result<()> {
+    ensure!(data.starts_with(JPEG_SOI), "Not a JPEG");
+    let length = u16::try_from(2 + EXIF_HEADER.len() + tiff.len())
+        .ok()
+        .filter(|&length| length < u16::MAX)
+        .context("Exif is too big for a JPEG")?;
+    let mut segment = vec![0xff, MARKER_APP1];
+    segment.extend_from_slice(&length.to_be_bytes());
+    segment.extend_from_slice(EXIF_HEADER);
+    segment.extend_from_slice(tiff);
+
+    // The range found is just the TIFF structure, after the marker, the length, and the header.
+    let range = match find_exif(data) {
+        Some(range) => range.start - EXIF_HEADER.len() - 4..range.end,
+        None => {
+            let pos = after_segments(data, &[MARKER_APP0]);
+            pos..pos
+        }
+    };
+    data.to_mut().splice(range, segment);
+    Ok(())
+}
+
+/// Set the UserComment in the Exif of a JPEG, adding Exif if it has none. Anything which isn't
+/// ASCII is written as UTF-16, which is what Exif calls Unicode.
+pub fn set_user_comment(data: &mut Cow<'_, [u8]>, comment: &str) -> Result<()> {
+    const USER_COMMENT_TAG: u16 = 0x9286;
+    /// A big endian TIFF header, then an empty IFD0.
+    const EMPTY_TIFF: &[u8] = b"MM\0*\0\0\0\x08\0\0\0\0\0\0";
+
+    let tiff = exif(data).unwrap_or(EMPTY_TIFF);
+    let little_endian = tiff::Tiff::new(tiff)
+        .context("Failed to parse Exif")?
+        .is_little_endian();
+    // UserComment starts with a character code saying how the rest is encoded.
+    let value = if comment.is_ascii() {
+        [b"ASCII\0\0\0", comment.as_bytes()].concat()
+    } else {
+        let mut value = b"UNICODE\0".to_vec();
+        for unit in comment.encode_utf16() {
+            let bytes = if little_endian {
+                unit.to_le_bytes()
+            } else {
+                unit.to_be_bytes()
+            };
+            value.extend_from_slice(&bytes);
+        }
+        value
+    };
+    let tiff = tiff::with_exif_entry(
+        tiff,
+        USER_COMMENT_TAG,
+        tiff::TYPE_UNDEFINED,
+        u32::try_from(value.len())?,
+        &value,
+    )
+    .context("Failed to parse Exif")?;
+    set_exif(data, &tiff)
+}
+
+/// XMP is stored in an APP1 segment starting with this, and whatever doesn't fit in one segment
+/// goes in more segments starting with the extension header.
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_EXTENSION_HEADER: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+
+/// Remove the GPS position from the Exif of a JPEG, if it has one, and from its XMP.
+///
+/// Editing XML without parsing it isn't something which can be done safely, so if any of the XMP
+/// has GPS properties, like exif:GPSLatitude, all of it is removed instead.
+pub fn strip_gps(data: &mut Cow<'_, [u8]>) {
+    if let Some(range) = find_exif(data) {
+        if tiff::has_gps(&data[range.clone()]) {
+            tiff::strip_gps(&mut data.to_mut()[range]);
+        }
+    }
+
+    const GPS_PROPERTY: &[u8] = b":GPS";
+    let xmp = find_xmp_segments(data);
+    let has_gps = xmp.iter().any(|range| {
+        data[range.clone()]
+            .windows(GPS_PROPERTY.len())
+            .any(|window| window == GPS_PROPERTY)
+    });
+    if has_gps {
+        let mut pos = 0;
+        let mut stripped = Vec::with_capacity(data.len());
+        for range in xmp {
+            stripped.extend_from_slice(&data[pos..range.start]);
+            pos = range.end;
+        }
+        stripped.extend_from_slice(&data[pos..]);
+        *data = Cow::Owned(stripped);
+    }
+}
+
+/// Find the XMP segments in the JPEG at the start of `data`, returning where each whole segment
+/// is, including its marker.
+fn find_xmp_segments(data: &[u8]) -> Vec<Range<usize>> {
+    let mut segments = Vec::new();
+    if !data.starts_with(JPEG_SOI) {
+        return segments;
+    }
+
+    let mut pos = JPEG_SOI.len();
+    loop {
+        let start = pos;
+        let Some((marker, after_marker)) = read_marker(data, pos) else {
+            return segments;
+        };
+        pos = after_marker;
+
+        match marker {
+            // Application segments have to come before the image data.
+            MARKER_SOS | MARKER_EOI | 0x00 => return segments,
+            MARKER_TEM | MARKER_RST0..=MARKER_RST7 => {}
+            _ => {
+                let Some(length) = data.get(pos..pos + 2).map(BigEndian::read_u16) else {
+                    return segments;
+                };
+                let length = usize::from(length);
+                let Some(segment) = data.get(pos + 2..pos + length).filter(|_| length >= 2) else {
+                    return segments;
+                };
+                let is_xmp =
+                    segment.starts_with(XMP_HEADER) || segment.starts_with(XMP_EXTENSION_HEADER);
+                pos += length;
+                if marker == MARKER_APP1 && is_xmp {
+                    segments.push(start..pos);
+                }
+            }
+        }
+    }
+}
+
+/// ICC profiles are stored in APP2 segments starting with this, then which chunk of the profile
+/// this is and how many there are, both counting from 1.
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+
+/// Whether a JPEG already has an ICC profile.
+pub fn has_icc_profile(data: &[u8]) -> bool {
+    find_app_segment(data, MARKER_APP2, ICC_HEADER).is_some()
+}
+
+/// Add an ICC profile to a JPEG, split over as many APP2 segments as it takes. They go after any
+/// JFIF and Exif segments, since some readers expect those first.
+pub fn set_icc_profile(data: &mut Cow<'_, [u8]>, profile: &[u8]) -> Result<()> {
+    const MAX_CHUNK: usize = u16::MAX as usize - 2 - ICC_HEADER.len() - 2;
+
+    ensure!(data.starts_with(JPEG_SOI), "Not a JPEG");
+    let chunks = profile.chunks(MAX_CHUNK);
+    let count = u8::try_from(chunks.len()).context("ICC profile is too big for a JPEG")?;
+    let mut segments = Vec::new();
+    for (index, chunk) in (1..=count).zip(chunks) {
+        segments.extend_from_slice(&[0xff, MARKER_APP2]);
+        segments
+            .extend_from_slice(&((2 + ICC_HEADER.len() + 2 + chunk.len()) as u16).to_be_bytes());
+        segments.extend_from_slice(ICC_HEADER);
+        segments.extend_from_slice(&[index, count]);
+        segments.extend_from_slice(chunk);
+    }
+
+    let pos = after_segments(data, &[MARKER_APP0, MARKER_APP1]);
+    data.to_mut().splice(pos..pos, segments);
+    Ok(())
+}
+
+/// Remove all metadata from a JPEG: Exif, XMP, IPTC, comments, and any other application
+/// segments, wherever they are, as well as anything after EOI. JFIF, ICC profiles, and Adobe's
+/// segment are kept, since they say how to decode and show the image rather than anything about
+/// where it came from.
+pub fn strip_metadata(data: &mut Cow<'_, [u8]>) -> Result<()> {
+    ensure!(data.starts_with(JPEG_SOI), "Not a JPEG");
+    const MALFORMED: &str = "Malformed JPEG, so its metadata can't be stripped";
+
+    let mut keep = Vec::new();
+    keep.push(0..JPEG_SOI.len());
+    let mut pos = JPEG_SOI.len();
+    loop {
+        let start = pos;
+        let marker;
+        (marker, pos) = read_marker(data, pos).context(MALFORMED)?;
+
+        match marker {
+            MARKER_EOI => {
+                keep.push(start..pos);
+                break;
+            }
+            0x00 => bail!(MALFORMED),
+            MARKER_TEM | MARKER_RST0..=MARKER_RST7 => keep.push(start..pos),
+            _ => {
+                let length: usize = data
+                    .get(pos..pos + 2)
+                    .map(BigEndian::read_u16)
+                    .context(MALFORMED)?
+                    .into();
+                let segment = data
+                    .get(pos + 2..pos + length)
+                    .filter(|_| length >= 2)
+                    .context(MALFORMED)?;
+                let is_metadata = match marker {
+                    MARKER_APP0 => !segment.starts_with(b"JFIF\0"),
+                    MARKER_APP2 => !segment.starts_with(ICC_HEADER),
+                    MARKER_APP14 => !segment.starts_with(b"Adobe"),
+                    MARKER_APP0..=MARKER_APP15 | MARKER_COM => true,
+                    _ => false,
+                };
+                pos += length;
+                if marker == MARKER_SOS {
+                    pos = entropy_coded_end(data, pos).context(MALFORMED)?;
+                }
+                if !is_metadata {
+                    keep.push(start..pos);
+                }
+            }
+        }
+    }
+
+    if keep.iter().map(ExactSizeIterator::len).sum::<usize>() < data.len() {
+        *data = Cow::Owned(
+            keep.into_iter()
+                .flat_map(|range| &data[range])
+                .copied()
+                .collect(),
+        );
+    }
+    Ok(())
+}
+
+/// Find the end of any of the `markers` segments which come straight after SOI.
+fn after_segments(data: &[u8], markers: &[u8]) -> usize {
+    let mut pos = JPEG_SOI.len();
+    while let Some(&[0xff, marker, ..]) = data.get(pos..) {
+        let Some(length) = data.get(pos + 2..pos + 4).map(BigEndian::read_u16) else {
+            break;
+        };
+        if !markers.contains(&marker) {
+            break;
+        }
+        pos = (pos + 2 + usize::from(length)).min(data.len());
+    }
+    pos
+}
+
+/// Get `data` without any padding after the end of the JPEG. Some cameras give a length which
+/// includes a few zero or fill bytes after EOI. If what's left doesn't end with EOI, the padding
+/// could be part of the image, so nothing is removed.
+pub fn without_padding(data: &[u8]) -> &[u8] {
+    let end = data
+        .iter()
+        .rposition(|&byte| byte != 0x00 && byte != 0xff)
+        .map_or(0, |last| last + 1);
+    if data[..end].ends_with(&[0xff, MARKER_EOI]) {
+        &data[..end]
+    } else {
+        data
+    }
+}
+
+/// Make sure that `data` starts with SOI and ends with EOI, which catches most JPEGs whose
+/// offset or length is wrong, without having to walk the whole thing.
+pub fn check_markers(data: &[u8]) -> Result<()> {
+    ensure!(
+        data.starts_with(JPEG_SOI),
+        "JPEG doesn't start with an SOI marker"
+    );
+    ensure!(
+        without_padding(data).ends_with(&[0xff, MARKER_EOI]),
+        "JPEG doesn't end with an EOI marker, so it's probably truncated"
+    );
+    Ok(())
+}
+
+/// Find the end of the entropy coded data starting at `pos`, which is the first marker that isn't
+/// a stuffed zero byte or a restart marker.
+pub(crate) fn entropy_coded_end(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let ff = pos + data.get(pos..)?.iter().position(|&byte| byte == 0xff)?;
+        match *data.get(ff + 1)? {
+            0x00 | MARKER_RST0..=MARKER_RST7 => pos = ff + 2,
+            0xff => pos = ff + 1,
+            _ => return Some(ff),
+        }
+    }
+}
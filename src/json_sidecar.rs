@@ -0,0 +1,97 @@
+use anyhow::Result;
+use rawtojpg::{ImageFormat, Metadata};
+use serde::Serialize;
+
+/// What --json-sidecar records about each preview.
+#[derive(Serialize)]
+pub struct Sidecar<'a> {
+    /// The RAW file the preview came from.
+    pub source: String,
+    /// Where the preview was written.
+    pub output: String,
+    pub preview: Preview,
+    pub exif: Exif<'a>,
+}
+
+/// Where the preview is in the RAW, and its size, as it was before anything was done to it.
+#[derive(Serialize)]
+pub struct Preview {
+    format: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+    /// The offsets and lengths of the rest of it, for previews split over several strips.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    strips: Vec<(u64, u64)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+}
+
+impl Preview {
+    /// Describe a preview from the `ranges` it was read from, which are empty if it wasn't read
+    /// from the RAW directly.
+    pub fn new(format: ImageFormat, ranges: &[(u64, u64)], dimensions: Option<(u32, u32)>) -> Self {
+        let first = ranges.first();
+        Self {
+            format: format.extension(),
+            offset: first.map(|&(offset, _)| offset),
+            length: first.map(|&(_, length)| length),
+            strips: ranges.get(1..).unwrap_or_default().to_vec(),
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+        }
+    }
+}
+
+/// The Exif fields from `Metadata`, leaving out any which the RAW doesn't have.
+#[derive(Serialize)]
+pub struct Exif<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    make: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lens: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_time_original: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub_sec_time_original: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset_time_original: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exposure_time: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    f_number: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iso: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    focal_length: Option<f64>,
+}
+
+impl<'a> From<&'a Metadata> for Exif<'a> {
+    fn from(metadata: &'a Metadata) -> Self {
+        Self {
+            make: metadata.make.as_deref(),
+            model: metadata.model.as_deref(),
+            lens: metadata.lens.as_deref(),
+            date_time_original: metadata.date_time_original.as_deref(),
+            sub_sec_time_original: metadata.sub_sec_time_original.as_deref(),
+            offset_time_original: metadata.offset_time_original.as_deref(),
+            exposure_time: metadata.exposure_time,
+            f_number: metadata.f_number,
+            iso: metadata.iso,
+            focal_length: metadata.focal_length,
+        }
+    }
+}
+
+impl Sidecar<'_> {
+    pub fn render(&self) -> Result<Vec<u8>> {
+        let mut json = serde_json::to_vec_pretty(self)?;
+        json.push(b'\n');
+        Ok(json)
+    }
+}
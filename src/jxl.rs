@@ -0,0 +1,126 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::borrow::Cow;
+
+const CODESTREAM_SIGNATURE: &[u8] = &[0xff, 0x0a];
+
+/// Read the width and height of the JPEG XL image at the start of `data`, which can either be a
+/// bare codestream, or a codestream inside the ISOBMFF based container.
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let codestream = if data.starts_with(CODESTREAM_SIGNATURE) {
+        data
+    } else {
+        find_codestream(data)?
+    };
+    read_size_header(codestream.strip_prefix(CODESTREAM_SIGNATURE)?)
+}
+
+/// Get the bare codestream of a JPEG XL image, which can't have any metadata in it, unlike the
+/// container. Returns `None` if it's a container with no codestream, or a box doesn't fit.
+pub fn codestream(data: &[u8]) -> Option<Cow<'_, [u8]>> {
+    if data.starts_with(CODESTREAM_SIGNATURE) {
+        return Some(Cow::Borrowed(data));
+    }
+    let mut codestream = Vec::new();
+    for (kind, payload) in boxes(data)? {
+        match kind {
+            b"jxlc" => return Some(Cow::Borrowed(payload)),
+            b"jxlp" => codestream.extend_from_slice(payload.get(4..)?),
+            _ => {}
+        }
+    }
+    codestream
+        .starts_with(CODESTREAM_SIGNATURE)
+        .then_some(Cow::Owned(codestream))
+}
+
+/// Split a container into its boxes, returning the type and payload of each.
+fn boxes(mut data: &[u8]) -> Option<Vec<(&[u8; 4], &[u8])>> {
+    let mut boxes = Vec::new();
+    while data.len() >= 8 {
+        let (size, header_size) = box_size(data)?;
+        let payload = data.get(header_size..size)?;
+        boxes.push((data[4..8].try_into().ok()?, payload));
+        data = &data[size..];
+    }
+    Some(boxes)
+}
+
+/// The size of the box at the start of `data`, including its header, and the size of the header.
+fn box_size(data: &[u8]) -> Option<(usize, usize)> {
+    Some(match BigEndian::read_u32(&data[..4]) {
+        0 => (data.len(), 8),
+        1 => (
+            usize::try_from(BigEndian::read_u64(data.get(8..16)?)).ok()?,
+            16,
+        ),
+        size => (usize::try_from(size).ok()?, 8),
+    })
+}
+
+/// Find the start of the codestream in a container, which is either the whole of a jxlc box, or
+/// split over jxlp boxes, each of which starts with a 4 byte index. The size is always in the first
+/// part.
+fn find_codestream(mut data: &[u8]) -> Option<&[u8]> {
+    while data.len() >= 8 {
+        let (size, header_size) = box_size(data)?;
+        let payload = data.get(header_size..size)?;
+        match &data[4..8] {
+            b"jxlc" => return Some(payload),
+            b"jxlp" => return payload.get(4..),
+            _ => data = &data[size..],
+        }
+    }
+    None
+}
+
+/// Read the SizeHeader at the start of a codestream, after the signature.
+fn read_size_header(data: &[u8]) -> Option<(u32, u32)> {
+    /// The aspect ratios which can be used instead of storing the width, as numerator and
+    /// denominator. Ratio 0 means the width is stored.
+    const RATIOS: [(u64, u64); 7] = [(1, 1), (12, 10), (4, 3), (3, 2), (16, 9), (5, 4), (2, 1)];
+
+    let mut bits = Bits { data, pos: 0 };
+    let small = bits.read(1)? == 1;
+    let height = bits.read_dimension(small)?;
+    let width = match bits.read(3)? {
+        0 => bits.read_dimension(small)?,
+        ratio => {
+            let (numerator, denominator) = RATIOS[usize::try_from(ratio).ok()? - 1];
+            u32::try_from(u64::from(height) * numerator / denominator).ok()?
+        }
+    };
+    Some((width, height))
+}
+
+/// A reader for JPEG XL's bit packing, which fills each byte from the least significant bit.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bits<'_> {
+    fn read(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self.data.get(self.pos / 8)?;
+            value |= u32::from(byte >> (self.pos % 8) & 1) << i;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    /// Read a width or height. Small images store it as a multiple of 8, and everything else uses
+    /// one of four sizes of field, chosen by a 2 bit selector.
+    fn read_dimension(&mut self, small: bool) -> Option<u32> {
+        if small {
+            return Some((self.read(5)? + 1) * 8);
+        }
+        let size = match self.read(2)? {
+            0 => self.read(9)?,
+            1 => self.read(13)?,
+            2 => self.read(18)?,
+            _ => self.read(30)?,
+        };
+        Some(size + 1)
+    }
+}
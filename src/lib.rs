@@ -0,0 +1,596 @@
+//! Find and extract the embedded JPEG previews in RAW files.
+//!
+//! Everything here works on a plain byte slice, so it doesn't care whether the file was read,
+//! memory-mapped, or made up by a fuzzer. Finding and extracting previews also works on any other
+//! `Source`, like a `ReaderSource` for files which can't be memory-mapped, in which case only the
+//! parts of the file which are needed get read.
+
+use anyhow::{anyhow, ensure, Context, Result};
+use std::borrow::Cow;
+use std::io::{Read, Seek};
+
+mod bmff;
+mod ciff;
+mod exif;
+mod icc;
+mod jpeg;
+mod jxl;
+pub mod lossless;
+mod makernote;
+mod mrw;
+mod quirks;
+mod raf;
+mod scan;
+mod source;
+pub mod tiff;
+mod x3f;
+
+pub use scan::find_largest_jpeg as scan_for_largest_jpeg;
+pub use source::{ReaderSource, Source};
+
+/// The start of image marker that every JPEG begins with.
+const JPEG_SOI: &[u8] = &[0xff, 0xd8];
+
+/// The format of an embedded image. Almost everything embeds JPEGs, but DNG 1.7 (for example,
+/// Apple ProRAW) allows JPEG XL previews.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ImageFormat {
+    Jpeg,
+    Jxl,
+}
+
+impl ImageFormat {
+    /// The most bytes `sniff` needs to see to recognise a signature.
+    const SIGNATURE_LENGTH: u64 = 12;
+
+    /// Work out the format of an image from its signature, if it's one we know.
+    fn sniff(data: &[u8]) -> Option<Self> {
+        const JXL_CODESTREAM: &[u8] = &[0xff, 0x0a];
+        const JXL_CONTAINER: &[u8] = b"\0\0\0\x0cJXL \r\n\x87\n";
+
+        if data.starts_with(JPEG_SOI) {
+            Some(Self::Jpeg)
+        } else if data.starts_with(JXL_CODESTREAM) || data.starts_with(JXL_CONTAINER) {
+            Some(Self::Jxl)
+        } else {
+            None
+        }
+    }
+
+    /// Read the width and height from the header of an image of this format.
+    pub fn dimensions(self, data: &[u8]) -> Option<(u32, u32)> {
+        match self {
+            Self::Jpeg => {
+                jpeg::dimensions(data).map(|(width, height)| (width.into(), height.into()))
+            }
+            Self::Jxl => jxl::dimensions(data),
+        }
+    }
+
+    /// Check that `data` looks like a whole image of this format, as far as can be told without
+    /// decoding it.
+    pub fn check_complete(self, data: &[u8]) -> Result<()> {
+        match self {
+            Self::Jpeg => jpeg::check_markers(data),
+            // JPEG XL has no end marker, and we've already checked the signature.
+            Self::Jxl => Ok(()),
+        }
+    }
+
+    /// Add an ICC profile to an image of this format, unless it already has one. Only JPEGs are
+    /// supported, since JPEG XL stores its colour space in the codestream itself.
+    pub fn set_icc_profile(self, data: &mut Cow<'_, [u8]>, profile: &[u8]) -> Result<()> {
+        if self == Self::Jpeg && !jpeg::has_icc_profile(data) {
+            jpeg::set_icc_profile(data, profile)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the Exif of an image of this format with the TIFF structure `exif`, like one from
+    /// `raw_exif`. JPEG XL keeps Exif in a container box rather than the codestream, so nothing is
+    /// done to those.
+    pub fn set_exif(self, data: &mut Cow<'_, [u8]>, exif: &[u8]) -> Result<()> {
+        if self == Self::Jpeg {
+            jpeg::set_exif(data, exif)?;
+        }
+        Ok(())
+    }
+
+    /// Set the Exif UserComment of an image of this format. JPEG XL has no Exif in the codestream,
+    /// so nothing is done to those.
+    pub fn set_user_comment(self, data: &mut Cow<'_, [u8]>, comment: &str) -> Result<()> {
+        if self == Self::Jpeg {
+            jpeg::set_user_comment(data, comment)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the GPS position from the Exif of an image of this format. Only JPEGs have Exif in the
+    /// image itself, so nothing is done to anything else.
+    pub fn strip_gps(self, data: &mut Cow<'_, [u8]>) {
+        if self == Self::Jpeg {
+            jpeg::strip_gps(data);
+        }
+    }
+
+    /// Remove all metadata from an image of this format, like Exif, XMP, and comments, keeping
+    /// only what's needed to show it properly. For JPEG XL, that's just the bare codestream.
+    pub fn strip_metadata(self, data: &mut Cow<'_, [u8]>) -> Result<()> {
+        match self {
+            Self::Jpeg => jpeg::strip_metadata(data),
+            Self::Jxl => {
+                let codestream = jxl::codestream(data)
+                    .context("Malformed JPEG XL, so its metadata can't be stripped")?;
+                if codestream.len() < data.len() {
+                    *data = Cow::Owned(codestream.into_owned());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Get the TIFF structure from the Exif of an image of this format, if it has any.
+    pub fn exif(self, data: &[u8]) -> Option<&[u8]> {
+        match self {
+            Self::Jpeg => jpeg::exif(data),
+            Self::Jxl => None,
+        }
+    }
+
+    /// Remove any padding after the end of an image of this format.
+    pub fn trim_padding(self, data: &mut Cow<'_, [u8]>) {
+        if self != Self::Jpeg {
+            return;
+        }
+        let length = jpeg::without_padding(data).len();
+        match data {
+            Cow::Borrowed(data) => *data = &data[..length],
+            Cow::Owned(data) => data.truncate(length),
+        }
+    }
+
+    /// Set the Exif Orientation of an image of this format, to be copied from the RAW. JPEG XL
+    /// has its own orientation in the codestream, so this only changes JPEGs.
+    pub fn set_orientation(self, data: &mut Cow<'_, [u8]>, orientation: u16) {
+        if self == Self::Jpeg {
+            jpeg::set_orientation(data, orientation);
+        }
+    }
+
+    /// The file extension to write images of this format with.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Jxl => "jxl",
+        }
+    }
+}
+
+/// Get the `length` bytes at `offset` in `buf`, or `None` if they aren't all inside it.
+///
+/// Offsets and lengths read from files are kept as u64 until they're used, so that files over 4
+/// GiB work, and so that nothing can overflow or be silently truncated where usize is 32 bits.
+fn get_range(buf: &[u8], offset: u64, length: u64) -> Option<&[u8]> {
+    let start = usize::try_from(offset).ok()?;
+    let end = start.checked_add(usize::try_from(length).ok()?)?;
+    buf.get(start..end)
+}
+
+/// The colour space of an embedded image, going by its Exif or the RAW's.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ColorSpace {
+    Srgb,
+    AdobeRgb,
+}
+
+/// How a picture was taken, going by the Exif of the RAW or its preview.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    /// When the picture was taken, as the Exif has it, like "2024:05:06 07:08:09". This is local
+    /// time, with the offset from UTC in `offset_time_original` if the camera knew it.
+    pub date_time_original: Option<String>,
+    /// The digits of the fraction of a second to add to `date_time_original`.
+    pub sub_sec_time_original: Option<String>,
+    /// The offset from UTC of `date_time_original`, like "+01:00".
+    pub offset_time_original: Option<String>,
+    /// In seconds.
+    pub exposure_time: Option<f64>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u64>,
+    /// In millimetres.
+    pub focal_length: Option<f64>,
+}
+
+impl Metadata {
+    /// Fill in anything this doesn't have from `other`.
+    fn or(self, other: Self) -> Self {
+        Self {
+            make: self.make.or(other.make),
+            model: self.model.or(other.model),
+            lens: self.lens.or(other.lens),
+            date_time_original: self.date_time_original.or(other.date_time_original),
+            sub_sec_time_original: self.sub_sec_time_original.or(other.sub_sec_time_original),
+            offset_time_original: self.offset_time_original.or(other.offset_time_original),
+            exposure_time: self.exposure_time.or(other.exposure_time),
+            f_number: self.f_number.or(other.f_number),
+            iso: self.iso.or(other.iso),
+            focal_length: self.focal_length.or(other.focal_length),
+        }
+    }
+}
+
+/// Get everything from `offset` onwards in `buf`, or `None` if it's past the end.
+fn get_from(buf: &[u8], offset: u64) -> Option<&[u8]> {
+    buf.get(usize::try_from(offset).ok()?..)
+}
+
+/// How to decide which embedded image is the largest.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum LargestBy {
+    /// The most bytes. This is slightly faster, since it doesn't need to read any image headers
+    #[default]
+    Bytes,
+    /// The most pixels, and then the most bytes, which picks the same size of preview whatever
+    /// the camera's quality settings
+    Pixels,
+}
+
+/// How to parse a file, and which of its embedded images to pick.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// Ignore embedded images which aren't JPEGs.
+    pub jpeg_only: bool,
+    /// Fail on any problem with the file's structure, instead of using whatever can still be
+    /// found.
+    pub strict: bool,
+    /// Pick the image at this position in the list from `find_embedded_jpegs`, instead of the
+    /// largest.
+    pub preview_index: Option<usize>,
+    /// Pick the Exif thumbnail, or if there isn't one, the smallest image, instead of the largest.
+    pub thumbnail: bool,
+    /// How to compare images when looking for the largest or smallest.
+    pub largest_by: LargestBy,
+}
+
+/// An embedded JPEG in a RAW file.
+#[derive(Debug)]
+pub struct EmbeddedJpegInfo {
+    offset: u64,
+    length: u64,
+    format: ImageFormat,
+    /// The offset and length of a separate JPEGTables stream, for TIFF strips which contain
+    /// abbreviated JPEGs without their own quantisation and Huffman tables.
+    jpeg_tables: Option<(u64, u64)>,
+    /// The offsets and lengths of the rest of the JPEG, for JPEGs which are split over several
+    /// non-contiguous TIFF strips. `offset` and `length` are the first strip.
+    strips: Vec<(u64, u64)>,
+    /// Whether the file says this is the image to show, like a full size DNG preview. This wins
+    /// over any other image, however large.
+    preferred: bool,
+    /// Whether this is the Exif thumbnail in IFD1.
+    thumbnail: bool,
+}
+
+impl EmbeddedJpegInfo {
+    fn new(offset: u64, length: u64) -> Self {
+        Self {
+            offset,
+            length,
+            format: ImageFormat::Jpeg,
+            jpeg_tables: None,
+            strips: Vec::new(),
+            preferred: false,
+            thumbnail: false,
+        }
+    }
+
+    /// Move all of the offsets along by `base`, for parsers which only see part of the file. Fails,
+    /// leaving the offsets as they were, if any of them would overflow.
+    fn shift(&mut self, base: u64) -> Result<()> {
+        let add = |offset: u64| {
+            offset
+                .checked_add(base)
+                .ok_or_else(|| anyhow!("Embedded image offset overflows"))
+        };
+        let offset = add(self.offset)?;
+        let jpeg_tables = self
+            .jpeg_tables
+            .map(|(offset, length)| add(offset).map(|offset| (offset, length)))
+            .transpose()?;
+        let strips = self
+            .strips
+            .iter()
+            .map(|&(offset, length)| add(offset).map(|offset| (offset, length)))
+            .collect::<Result<_>>()?;
+        self.offset = offset;
+        self.jpeg_tables = jpeg_tables;
+        self.strips = strips;
+        Ok(())
+    }
+
+    /// The format of the embedded image.
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// The offsets and lengths of the image data, in the order it must be joined.
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        std::iter::once((self.offset, self.length)).chain(self.strips.iter().copied())
+    }
+
+    /// The number of pixels in the image, if we can tell.
+    fn pixels<S: Source + ?Sized>(&self, source: &S) -> Option<u64> {
+        let data = source.read_at(self.offset, self.length).ok()?;
+        self.format
+            .dimensions(&data)
+            .map(|(width, height)| u64::from(width) * u64::from(height))
+    }
+
+    /// The length of the whole JPEG, including any other strips.
+    fn total_length(&self) -> u64 {
+        self.strips
+            .iter()
+            .map(|&(_, length)| length)
+            .fold(self.length, u64::saturating_add)
+    }
+
+    /// Get the image out of the source it was found in. For buffers, this only copies if the image
+    /// has to be put back together from strips or separate tables.
+    pub fn data<'a, S: Source + ?Sized>(&self, source: &'a S) -> Result<Cow<'a, [u8]>> {
+        let mut data = source.read_at(self.offset, self.length)?;
+
+        if !self.strips.is_empty() {
+            let mut joined = Vec::with_capacity(self.total_length().try_into()?);
+            joined.extend_from_slice(&data);
+            for &(offset, length) in &self.strips {
+                joined.extend_from_slice(&source.read_at(offset, length)?);
+            }
+            data = Cow::Owned(joined);
+        }
+
+        let Some((tables_offset, tables_length)) = self.jpeg_tables else {
+            return Ok(data);
+        };
+
+        // JPEGTables is a complete JPEG stream with no image, that is, SOI, the tables, then EOI.
+        // We splice the tables in right after the SOI of the image data.
+        let tables = source
+            .read_at(tables_offset, tables_length)
+            .ok()
+            .filter(|tables| tables.len() >= 4 && data.len() >= 2)
+            .context("Invalid JPEGTables")?;
+        Ok(Cow::Owned(
+            [&tables[..tables.len() - 2], &data[2..]].concat(),
+        ))
+    }
+}
+
+/// Find all of the embedded JPEGs in a RAW buffer, in the order they were found in the file's
+/// structure.
+///
+/// The container format is detected from the magic at the start of the file, and then all of the
+/// embedded JPEGs found by the relevant parser are considered. Unless `jpeg_only` is set, other
+/// embedded image formats are considered too. Candidates which don't start with the signature of
+/// their format are skipped.
+///
+/// If the parser fails part way through, we still use what it found before then, unless `strict`
+/// is set.
+///
+/// Only TIFF based files are read a piece at a time. The other containers are read whole first,
+/// which costs nothing for buffers, but means reading all of anything else.
+pub fn find_embedded_jpegs<S: Source + ?Sized>(
+    source: &S,
+    options: &Options,
+) -> Result<Vec<EmbeddedJpegInfo>> {
+    /// Enough of the start of the file to tell which container it is.
+    const MAGIC_LENGTH: u64 = 16;
+
+    let mut candidates = Vec::new();
+    let magic = source::read_up_to(source, 0, MAGIC_LENGTH).unwrap_or_default();
+    let is_container = [
+        bmff::is_bmff,
+        raf::is_raf,
+        x3f::is_x3f,
+        ciff::is_ciff,
+        mrw::is_mrw,
+    ]
+    .iter()
+    .any(|is_container| is_container(&magic));
+
+    let result = if is_container {
+        source
+            .read_at(0, source.len())
+            .and_then(|raw_buf| find_container_jpegs(&raw_buf, options, &mut candidates))
+    } else {
+        tiff::find_jpegs(source, options.strict, &mut candidates)
+    };
+    if let Err(err) = result {
+        if options.strict || candidates.is_empty() {
+            return Err(err);
+        }
+    }
+
+    Ok(candidates
+        .into_iter()
+        .filter(|jpeg| jpeg.length > 0)
+        // Parsers can be given bad pointers, so make sure the data really is what we expect.
+        .filter(|jpeg| {
+            source::read_up_to(source, jpeg.offset, ImageFormat::SIGNATURE_LENGTH)
+                .and_then(|signature| ImageFormat::sniff(&signature))
+                == Some(jpeg.format)
+        })
+        .filter(|jpeg| !options.jpeg_only || jpeg.format == ImageFormat::Jpeg)
+        .collect())
+}
+
+/// Find the embedded JPEGs in a file which isn't TIFF based.
+fn find_container_jpegs(
+    raw_buf: &[u8],
+    options: &Options,
+    candidates: &mut Vec<EmbeddedJpegInfo>,
+) -> Result<()> {
+    if bmff::is_bmff(raw_buf) {
+        bmff::find_jpegs(raw_buf, candidates)
+    } else if raf::is_raf(raw_buf) {
+        raf::find_jpegs(raw_buf, candidates)
+    } else if x3f::is_x3f(raw_buf) {
+        x3f::find_jpegs(raw_buf, candidates)
+    } else if ciff::is_ciff(raw_buf) {
+        ciff::find_jpegs(raw_buf, candidates)
+    } else {
+        mrw::find_jpegs(raw_buf, options.strict, candidates)
+    }
+}
+
+/// Find the largest embedded JPEG in a RAW buffer, or the one at `preview_index` or the thumbnail if
+/// they're asked for.
+///
+/// By default the image with the most bytes wins. With `LargestBy::Pixels`, the one with the most
+/// pixels does instead, since some cameras embed a high quality medium sized preview which is
+/// bigger than the full size one, and the length only decides between images of the same size, or
+/// whose size we can't read.
+///
+/// If there's no Exif thumbnail, the smallest image is used as the thumbnail instead. Images whose
+/// size we can't read are never picked for that, since they could be anything.
+pub fn find_largest_embedded_jpeg<S: Source + ?Sized>(
+    source: &S,
+    options: &Options,
+) -> Result<EmbeddedJpegInfo> {
+    let mut candidates = find_embedded_jpegs(source, options)?;
+    ensure!(!candidates.is_empty(), "No JPEG data found");
+    let pixels = |jpeg: &EmbeddedJpegInfo| match options.largest_by {
+        LargestBy::Bytes => Some(0),
+        LargestBy::Pixels => jpeg.pixels(source),
+    };
+
+    let jpeg = match options.preview_index {
+        Some(index) => {
+            ensure!(
+                index < candidates.len(),
+                "Preview index {index} is out of range, only {} previews found",
+                candidates.len()
+            );
+            candidates.swap_remove(index)
+        }
+        None if options.thumbnail => candidates
+            .into_iter()
+            .min_by_key(|jpeg| {
+                let pixels = pixels(jpeg).unwrap_or(u64::MAX);
+                (!jpeg.thumbnail, pixels, jpeg.total_length())
+            })
+            .context("No JPEG data found")?,
+        None => candidates
+            .into_iter()
+            .max_by_key(|jpeg| {
+                let pixels = pixels(jpeg).unwrap_or(0);
+                (jpeg.preferred, pixels, jpeg.total_length())
+            })
+            .context("No JPEG data found")?,
+    };
+    ensure!(
+        jpeg.ranges().all(|(offset, length)| offset
+            .checked_add(length)
+            .is_some_and(|end| end <= source.len())),
+        "JPEG data at offset {} exceeds file size",
+        jpeg.offset
+    );
+
+    Ok(jpeg)
+}
+
+/// Find the width and height of the RAW image itself, for TIFF based formats.
+pub fn raw_dimensions(raw_buf: &[u8]) -> Option<(u64, u64)> {
+    tiff::largest_image_dimensions(raw_buf)
+}
+
+/// Work out how to crop a preview of `width` by `height` to the DefaultCrop of a DNG's main image,
+/// which is what the camera and most editors show. Returns `None` if there's nothing to crop, or
+/// if the preview's aspect ratio doesn't match the area the crop is of, since then it's probably
+/// already cropped.
+pub fn default_crop(raw_buf: &[u8], width: u32, height: u32) -> Option<lossless::Crop> {
+    let crop = tiff::default_crop(raw_buf)?;
+    let (area_width, area_height) = crop.area;
+    let aspect = f64::from(width) / f64::from(height);
+    if (aspect - area_width / area_height).abs() > 0.01 * aspect {
+        return None;
+    }
+
+    let scale = f64::from(width) / area_width;
+    let scaled = |value: f64| (value * scale).round() as u32;
+    let left = scaled(crop.origin.0).min(width);
+    let top = scaled(crop.origin.1).min(height);
+    let crop = lossless::Crop {
+        left,
+        top,
+        width: scaled(crop.size.0).min(width - left),
+        height: scaled(crop.size.1).min(height - top),
+    };
+    let whole = lossless::Crop {
+        left: 0,
+        top: 0,
+        width,
+        height,
+    };
+    Some(crop).filter(|&crop| crop.width > 0 && crop.height > 0 && crop != whole)
+}
+
+/// Find the colour space of `preview`, from its own Exif, or otherwise from the RAW's for TIFF
+/// based formats. Returns `None` if neither says.
+pub fn color_space(raw_buf: &[u8], preview: &[u8]) -> Option<ColorSpace> {
+    jpeg::exif(preview)
+        .and_then(tiff::color_space)
+        .or_else(|| tiff::color_space(raw_buf))
+}
+
+/// Find how a picture was taken, from the RAW's own Exif for TIFF based formats, and otherwise from
+/// the Exif in the `preview`, which is all that most other formats have.
+pub fn metadata(raw_buf: &[u8], preview: &[u8]) -> Metadata {
+    let raw = tiff::metadata(raw_buf).unwrap_or_default();
+    let preview = jpeg::exif(preview)
+        .and_then(tiff::metadata)
+        .unwrap_or_default();
+    raw.or(preview)
+}
+
+/// Find the ICC profile to embed in `preview`. This is the RAW's own profile for TIFF based formats
+/// which have one, or otherwise a standard one if the colour space is known to be Adobe RGB. sRGB
+/// is what viewers assume anyway, so there's no need for a profile for that.
+pub fn icc_profile<'a>(raw_buf: &'a [u8], preview: &[u8]) -> Option<Cow<'a, [u8]>> {
+    if let Some(profile) = tiff::icc_profile(raw_buf) {
+        return Some(profile);
+    }
+    (color_space(raw_buf, preview)? == ColorSpace::AdobeRgb).then(|| Cow::Owned(icc::adobe_rgb()))
+}
+
+/// Build Exif from the metadata of a TIFF based RAW, for previews which have little or none of
+/// their own: the camera, lens, exposure, dates, and copyright from IFD0 and the Exif IFD, as well
+/// as the GPS IFD if `gps` is set. Returns the TIFF structure to go in the Exif segment, or `None`
+/// for other formats.
+pub fn raw_exif(raw_buf: &[u8], gps: bool) -> Option<Vec<u8>> {
+    exif::from_raw(raw_buf, gps)
+}
+
+/// Find which way up the camera was held, from the Orientation in IFD0 for TIFF based formats, or
+/// otherwise from the Exif in the `preview` itself. This is the Exif Orientation value, where 1 is
+/// upright.
+pub fn orientation(raw_buf: &[u8], preview: &[u8]) -> Option<u16> {
+    tiff::orientation(raw_buf).or_else(|| jpeg::exif(preview).and_then(tiff::orientation))
+}
+
+/// Extract the largest embedded JPEG from a RAW file which is read from `reader`, like a file
+/// which can't be memory-mapped, or an uncompressed entry in an archive.
+///
+/// For TIFF based files, only the IFDs and the preview itself are read, rather than the whole
+/// file, unless `LargestBy::Pixels` is used, in which case each candidate is read to find its
+/// size. Other containers are read whole, see `find_embedded_jpegs`.
+pub fn extract_from_reader<R: Read + Seek>(
+    reader: R,
+    options: &Options,
+) -> Result<(ImageFormat, Vec<u8>)> {
+    let source = ReaderSource::new(reader)?;
+    let jpeg = find_largest_embedded_jpeg(&source, options)?;
+    Ok((jpeg.format(), jpeg.data(&source)?.into_owned()))
+}
@@ -0,0 +1,59 @@
+use anyhow::{bail, ensure, Result};
+use libraw_sys as sys;
+use std::ffi::CStr;
+use std::os::raw::c_int;
+use std::ptr;
+use std::slice;
+
+/// A libraw handle, which is closed when dropped.
+struct Handle(*mut sys::libraw_data_t);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        // SAFETY: The handle came from libraw_init, and is never used again after this.
+        unsafe { sys::libraw_close(self.0) }
+    }
+}
+
+fn check(ret: c_int) -> Result<()> {
+    if ret == sys::LibRaw_errors_LIBRAW_SUCCESS {
+        return Ok(());
+    }
+    // SAFETY: libraw_strerror always returns a static, NUL terminated string.
+    let msg = unsafe { CStr::from_ptr(sys::libraw_strerror(ret)) };
+    bail!("libraw: {}", msg.to_string_lossy())
+}
+
+/// Extract the embedded JPEG from a RAW buffer using libraw.
+///
+/// This is only used as a fallback when our own parsers can't find anything. libraw supports many
+/// more formats, but it's also much slower, since it parses far more of the file than we need.
+pub fn extract_thumbnail(raw_buf: &[u8]) -> Result<Vec<u8>> {
+    // SAFETY: libraw only reads from the buffer, which outlives the handle. The processed image
+    // is only accessed before it's freed, and data_size bytes are allocated after its header.
+    unsafe {
+        let handle = Handle(sys::libraw_init(0));
+        ensure!(!handle.0.is_null(), "Failed to initialise libraw");
+
+        check(sys::libraw_open_buffer(
+            handle.0,
+            raw_buf.as_ptr().cast(),
+            raw_buf.len(),
+        ))?;
+        check(sys::libraw_unpack_thumb(handle.0))?;
+
+        let mut err = 0;
+        let image = sys::libraw_dcraw_make_mem_thumb(handle.0, &mut err);
+        check(err)?;
+        ensure!(!image.is_null(), "libraw returned no thumbnail");
+
+        let thumbnail = if (*image).type_ == sys::LibRaw_image_formats_LIBRAW_IMAGE_JPEG {
+            let data = ptr::addr_of!((*image).data).cast::<u8>();
+            Ok(slice::from_raw_parts(data, (*image).data_size.try_into()?).to_vec())
+        } else {
+            Err(anyhow::anyhow!("libraw thumbnail is not a JPEG"))
+        };
+        sys::libraw_dcraw_clear_mem(image);
+        thumbnail
+    }
+}
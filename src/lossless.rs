@@ -0,0 +1,1160 @@
+//! Lossless rewriting of JPEGs, in the same way as jpegtran. The entropy coded data is decoded as
+//! far as the quantised DCT coefficients, which are then encoded again, so none of the pixels
+//! change.
+//!
+//! Only sequential Huffman coded JPEGs are supported, since that's what cameras embed.
+
+use crate::jpeg::{self, EXIF_HEADER, MARKER_APP1, MARKER_EOI, MARKER_RST0, MARKER_SOS};
+use crate::{tiff, JPEG_SOI};
+use anyhow::{bail, ensure, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::borrow::Cow;
+
+const MARKER_DHT: u8 = 0xc4;
+const MARKER_SOF0: u8 = 0xc0;
+const MARKER_SOF1: u8 = 0xc1;
+const MARKER_SOF2: u8 = 0xc2;
+const MARKER_DQT: u8 = 0xdb;
+const MARKER_DNL: u8 = 0xdc;
+const MARKER_DRI: u8 = 0xdd;
+const MARKER_APP0: u8 = 0xe0;
+const MARKER_APP15: u8 = 0xef;
+const MARKER_COM: u8 = 0xfe;
+
+const DC: usize = 0;
+const AC: usize = 1;
+
+/// The most blocks a single MCU can have in an interleaved scan.
+const MAX_MCU_BLOCKS: usize = 10;
+
+/// An 8x8 block of quantised coefficients, in zigzag order.
+type Block = [i16; 64];
+
+/// The position in an 8x8 block, reading along the rows, of each coefficient in zigzag order.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+const UNZIGZAG: [usize; 64] = {
+    let mut unzigzag = [0; 64];
+    let mut i = 0;
+    while i < 64 {
+        unzigzag[ZIGZAG[i]] = i;
+        i += 1;
+    }
+    unzigzag
+};
+
+/// A lossless transformation, for each of the Exif Orientation values other than upright.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transform {
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270,
+}
+
+impl Transform {
+    /// Get the transformation which makes an image with this Orientation upright, or `None` if it
+    /// already is.
+    pub fn from_orientation(orientation: u16) -> Option<Self> {
+        match orientation {
+            2 => Some(Self::FlipHorizontal),
+            3 => Some(Self::Rotate180),
+            4 => Some(Self::FlipVertical),
+            5 => Some(Self::Transpose),
+            6 => Some(Self::Rotate90),
+            7 => Some(Self::Transverse),
+            8 => Some(Self::Rotate270),
+            _ => None,
+        }
+    }
+
+    /// Break the transformation down into transposes and flips, in the order they're done.
+    fn steps(self) -> &'static [Self] {
+        match self {
+            Self::Rotate180 => &[Self::FlipHorizontal, Self::FlipVertical],
+            Self::Rotate90 => &[Self::Transpose, Self::FlipHorizontal],
+            Self::Transverse => &[Self::Transpose, Self::FlipHorizontal, Self::FlipVertical],
+            Self::Rotate270 => &[Self::Transpose, Self::FlipVertical],
+            Self::FlipHorizontal => &[Self::FlipHorizontal],
+            Self::FlipVertical => &[Self::FlipVertical],
+            Self::Transpose => &[Self::Transpose],
+        }
+    }
+}
+
+#[derive(Clone)]
+struct QuantTable {
+    precision: u8,
+    values: [u16; 64],
+}
+
+#[derive(Clone, Default)]
+struct HuffmanTable {
+    counts: [u8; 16],
+    values: Vec<u8>,
+}
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    quant_table: u8,
+    dc_table: Option<u8>,
+    ac_table: Option<u8>,
+    /// The size of the block grid, including any partial MCUs at the right and bottom edges.
+    blocks_wide: usize,
+    blocks_high: usize,
+    blocks: Vec<Block>,
+}
+
+/// A decoded JPEG, with everything needed to encode it again.
+struct Frame<'a> {
+    marker: u8,
+    precision: u8,
+    width: usize,
+    height: usize,
+    components: Vec<Component>,
+    quant_tables: [Option<QuantTable>; 4],
+    /// APPn and COM segments, which are written back out as they are.
+    metadata: Vec<(u8, Cow<'a, [u8]>)>,
+}
+
+/// A scan to encode. Scans for progressive JPEGs use spectral selection, but not successive
+/// approximation.
+struct Scan {
+    components: Vec<usize>,
+    start: usize,
+    end: usize,
+}
+
+/// A rectangle to crop a JPEG to, in pixels.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Crop {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What to do to a JPEG when rewriting it. Whatever else is done, it always gets Huffman tables
+/// optimised for its contents.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rewrite {
+    /// Crop to this rectangle first. Only whole MCUs can be dropped, so like `jpegtran -crop`, the
+    /// left and top are rounded down to the start of the MCU they're in.
+    pub crop: Option<Crop>,
+    /// Then rotate or flip it. Like `jpegtran -trim`, any partial MCUs which would end up on the
+    /// top or left edge are dropped. If the JPEG has an Orientation in its Exif, it's set to
+    /// upright.
+    pub transform: Option<Transform>,
+    pub progressive: bool,
+}
+
+/// Rewrite a JPEG without decoding it to pixels, so none of the pixels change other than by being
+/// moved or cropped. Like jpegtran, restart markers aren't kept.
+pub fn rewrite(data: &[u8], rewrite: &Rewrite) -> Result<Vec<u8>> {
+    let mut frame = Frame::parse(data)?;
+    if let Some(crop) = rewrite.crop {
+        frame.crop(crop)?;
+    }
+    if let Some(transform) = rewrite.transform {
+        for step in transform.steps() {
+            match step {
+                Transform::Transpose => frame.transpose(),
+                Transform::FlipHorizontal => frame.flip(Axis::Horizontal)?,
+                _ => frame.flip(Axis::Vertical)?,
+            }
+        }
+        frame.reset_orientation();
+    }
+    frame.encode(rewrite.progressive)
+}
+
+/// Rewrite a JPEG with Huffman tables optimised for its contents, and optionally make it
+/// progressive.
+pub fn optimize(data: &[u8], progressive: bool) -> Result<Vec<u8>> {
+    rewrite(
+        data,
+        &Rewrite {
+            progressive,
+            ..Rewrite::default()
+        },
+    )
+}
+
+/// Rotate or flip a JPEG, and optimise its Huffman tables.
+pub fn transform(data: &[u8], transform: Transform, progressive: bool) -> Result<Vec<u8>> {
+    rewrite(
+        data,
+        &Rewrite {
+            transform: Some(transform),
+            progressive,
+            ..Rewrite::default()
+        },
+    )
+}
+
+#[derive(Clone, Copy)]
+enum Axis {
+    Horizontal,
+    Vertical,
+}
+
+fn segment_length(data: &[u8], pos: usize) -> Result<usize> {
+    let length: usize =
+        BigEndian::read_u16(data.get(pos..pos + 2).context("Truncated JPEG")?).into();
+    ensure!(length >= 2, "Invalid JPEG segment length");
+    ensure!(pos + length <= data.len(), "Truncated JPEG");
+    Ok(length)
+}
+
+fn parse_dqt(mut segment: &[u8], tables: &mut [Option<QuantTable>; 4]) -> Result<()> {
+    while let [info, rest @ ..] = segment {
+        let precision = info >> 4;
+        let size = if precision == 0 { 64 } else { 128 };
+        ensure!(precision <= 1 && rest.len() >= size, "Invalid DQT segment");
+        let mut values = [0; 64];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = if precision == 0 {
+                rest[i].into()
+            } else {
+                BigEndian::read_u16(&rest[i * 2..])
+            };
+        }
+        *tables
+            .get_mut(usize::from(info & 0xf))
+            .context("Invalid DQT table")? = Some(QuantTable { precision, values });
+        segment = &rest[size..];
+    }
+    Ok(())
+}
+
+fn parse_dht(mut segment: &[u8], tables: &mut [[Option<HuffmanTable>; 4]; 2]) -> Result<()> {
+    while let [info, rest @ ..] = segment {
+        let (class, id) = (usize::from(info >> 4), usize::from(info & 0xf));
+        ensure!(
+            class <= AC && id < 4 && rest.len() >= 16,
+            "Invalid DHT segment"
+        );
+        let mut counts = [0; 16];
+        counts.copy_from_slice(&rest[..16]);
+        let total = counts
+            .iter()
+            .map(|&count| usize::from(count))
+            .sum::<usize>();
+        let values = rest.get(16..16 + total).context("Invalid DHT segment")?;
+        tables[class][id] = Some(HuffmanTable {
+            counts,
+            values: values.to_vec(),
+        });
+        segment = &rest[16 + total..];
+    }
+    Ok(())
+}
+
+struct HuffmanDecoder<'a> {
+    max_code: [i32; 17],
+    offset: [i32; 17],
+    values: &'a [u8],
+}
+
+impl<'a> HuffmanDecoder<'a> {
+    fn new(table: &'a HuffmanTable) -> Self {
+        let mut max_code = [-1; 17];
+        let mut offset = [0; 17];
+        let (mut code, mut index) = (0, 0);
+        for length in 1..=16 {
+            let count = i32::from(table.counts[length - 1]);
+            offset[length] = index - code;
+            if count > 0 {
+                max_code[length] = code + count - 1;
+            }
+            code = (code + count) << 1;
+            index += count;
+        }
+        Self {
+            max_code,
+            offset,
+            values: &table.values,
+        }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8> {
+        let mut code = 0;
+        for length in 1..=16 {
+            code = code << 1 | reader.bit()?;
+            if code <= self.max_code[length] {
+                let index = usize::try_from(code + self.offset[length])?;
+                return self
+                    .values
+                    .get(index)
+                    .copied()
+                    .context("Invalid Huffman code");
+            }
+        }
+        bail!("Invalid Huffman code")
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    byte: u8,
+    left: u32,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Result<i32> {
+        if self.left == 0 {
+            let byte = *self.data.get(self.pos).context("Truncated JPEG data")?;
+            if byte == 0xff {
+                ensure!(
+                    self.data.get(self.pos + 1) == Some(&0),
+                    "Unexpected marker in JPEG data"
+                );
+                self.pos += 1;
+            }
+            self.pos += 1;
+            self.byte = byte;
+            self.left = 8;
+        }
+        self.left -= 1;
+        Ok(i32::from(self.byte >> self.left & 1))
+    }
+
+    /// Read a coefficient of `size` bits, where values with the top bit clear are negative.
+    fn value(&mut self, size: u8) -> Result<i32> {
+        ensure!(size <= 16, "Invalid JPEG coefficient size");
+        let mut value = 0;
+        for _ in 0..size {
+            value = value << 1 | self.bit()?;
+        }
+        if size > 0 && value < 1 << (size - 1) {
+            value -= (1 << size) - 1;
+        }
+        Ok(value)
+    }
+
+    fn restart(&mut self, index: u8) -> Result<()> {
+        self.left = 0;
+        ensure!(
+            self.data.get(self.pos..self.pos + 2) == Some(&[0xff, MARKER_RST0 + index]),
+            "Missing restart marker in JPEG data"
+        );
+        self.pos += 2;
+        Ok(())
+    }
+}
+
+fn coefficient(value: i32) -> Result<i16> {
+    i16::try_from(value).context("JPEG coefficient out of range")
+}
+
+/// Get the size in bits of a coefficient, and the bits to write for it.
+fn magnitude(value: i32) -> (u8, u32) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = if value < 0 { value - 1 } else { value };
+    (size as u8, bits as u32 & ((1 << size) - 1))
+}
+
+impl<'a> Frame<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        ensure!(
+            data.starts_with(JPEG_SOI),
+            "JPEG doesn't start with an SOI marker"
+        );
+
+        let mut frame: Option<Self> = None;
+        let mut metadata = Vec::new();
+        let mut quant_tables = [None, None, None, None];
+        let mut huffman_tables = Default::default();
+        let mut restart_interval = 0;
+        let mut scans = 0;
+        let mut pos = JPEG_SOI.len();
+
+        loop {
+            let marker;
+            (marker, pos) = jpeg::read_marker(data, pos).context("Invalid JPEG marker")?;
+            if marker == MARKER_EOI {
+                break;
+            }
+            let length = segment_length(data, pos)?;
+            let segment = &data[pos + 2..pos + length];
+
+            match marker {
+                MARKER_APP0..=MARKER_APP15 | MARKER_COM => {
+                    metadata.push((marker, Cow::Borrowed(segment)));
+                }
+                MARKER_DQT => parse_dqt(segment, &mut quant_tables)?,
+                MARKER_DHT => parse_dht(segment, &mut huffman_tables)?,
+                MARKER_DRI => {
+                    ensure!(segment.len() == 2, "Invalid DRI segment");
+                    restart_interval = BigEndian::read_u16(segment);
+                }
+                MARKER_SOF0 | MARKER_SOF1 => {
+                    ensure!(frame.is_none(), "JPEG has more than one frame header");
+                    frame = Some(Self::new(marker, segment, data.len())?);
+                }
+                MARKER_SOS => {
+                    let frame = frame.as_mut().context("JPEG scan comes before its frame")?;
+                    let end = frame.decode_scan(
+                        segment,
+                        &huffman_tables,
+                        restart_interval,
+                        data,
+                        pos + length,
+                    )?;
+                    scans += 1;
+                    pos = end;
+                    continue;
+                }
+                MARKER_DNL => bail!("JPEGs with a DNL marker aren't supported"),
+                _ if jpeg::MARKERS_SOF_DCT.contains(&marker)
+                    || jpeg::MARKERS_SOF_LOSSLESS.contains(&marker) =>
+                {
+                    bail!("Only sequential Huffman coded JPEGs can be rewritten losslessly")
+                }
+                _ => {}
+            }
+            pos += length;
+        }
+
+        let mut frame = frame.context("JPEG has no frame header")?;
+        ensure!(scans > 0, "JPEG has no image data");
+        for component in &frame.components {
+            ensure!(
+                component.dc_table.is_some() && component.ac_table.is_some(),
+                "JPEG has no scan for component {}",
+                component.id
+            );
+            ensure!(
+                quant_tables[usize::from(component.quant_table)].is_some(),
+                "JPEG is missing quantisation table {}",
+                component.quant_table
+            );
+        }
+        frame.quant_tables = quant_tables;
+        frame.metadata = metadata;
+        Ok(frame)
+    }
+
+    fn new(marker: u8, segment: &[u8], data_length: usize) -> Result<Self> {
+        ensure!(segment.len() >= 6, "Invalid frame header");
+        let precision = segment[0];
+        let height = BigEndian::read_u16(&segment[1..]).into();
+        let width = BigEndian::read_u16(&segment[3..]).into();
+        let count = usize::from(segment[5]);
+        ensure!(precision == 8 || precision == 12, "Invalid JPEG precision");
+        ensure!(width > 0 && height > 0, "Invalid JPEG dimensions");
+        ensure!((1..=4).contains(&count), "Invalid JPEG component count");
+        let specs = segment
+            .get(6..6 + count * 3)
+            .context("Invalid frame header")?;
+
+        let mut components = Vec::new();
+        for spec in specs.chunks_exact(3) {
+            let (h, v) = (usize::from(spec[1] >> 4), usize::from(spec[1] & 0xf));
+            ensure!(
+                (1..=4).contains(&h) && (1..=4).contains(&v) && spec[2] < 4,
+                "Invalid JPEG component"
+            );
+            components.push(Component {
+                id: spec[0],
+                h,
+                v,
+                quant_table: spec[2],
+                dc_table: None,
+                ac_table: None,
+                blocks_wide: 0,
+                blocks_high: 0,
+                blocks: Vec::new(),
+            });
+        }
+        // Sampling factors mean nothing with only one component, and are always treated as 1.
+        if let [component] = components.as_mut_slice() {
+            (component.h, component.v) = (1, 1);
+        }
+
+        let mut frame = Self {
+            marker,
+            precision,
+            width,
+            height,
+            components,
+            quant_tables: [None, None, None, None],
+            metadata: Vec::new(),
+        };
+        frame.allocate_blocks(data_length)?;
+        Ok(frame)
+    }
+
+    /// Size the block grid of every component to cover whole MCUs. `data_length` is used to reject
+    /// dimensions which couldn't possibly have been coded in that much data, rather than trying
+    /// to allocate for them.
+    fn allocate_blocks(&mut self, data_length: usize) -> Result<()> {
+        let (mcus_wide, mcus_high) = self.mcus();
+        let total = self
+            .components
+            .iter()
+            .map(|component| mcus_wide * component.h * mcus_high * component.v)
+            .sum::<usize>();
+        ensure!(
+            total <= data_length.saturating_mul(8),
+            "JPEG is too short for its dimensions"
+        );
+        for component in &mut self.components {
+            component.blocks_wide = mcus_wide * component.h;
+            component.blocks_high = mcus_high * component.v;
+            component.blocks = vec![[0; 64]; component.blocks_wide * component.blocks_high];
+        }
+        Ok(())
+    }
+
+    fn max_sampling(&self) -> (usize, usize) {
+        let h = self.components.iter().map(|c| c.h).max().unwrap_or(1);
+        let v = self.components.iter().map(|c| c.v).max().unwrap_or(1);
+        (h, v)
+    }
+
+    fn mcus(&self) -> (usize, usize) {
+        let (h, v) = self.max_sampling();
+        (self.width.div_ceil(8 * h), self.height.div_ceil(8 * v))
+    }
+
+    /// Get the blocks a scan of `components` codes, in the order it codes them, and how many blocks
+    /// make up each MCU. Scans of one component only cover the blocks which are in the image,
+    /// rather than whole MCUs.
+    fn scan_order(&self, components: &[usize]) -> (Vec<(usize, usize)>, usize) {
+        let mut order = Vec::new();
+        if let &[index] = components {
+            let component = &self.components[index];
+            let (h, v) = self.max_sampling();
+            let wide = (self.width * component.h).div_ceil(h).div_ceil(8);
+            let high = (self.height * component.v).div_ceil(v).div_ceil(8);
+            for y in 0..high {
+                order.extend((0..wide).map(|x| (index, y * component.blocks_wide + x)));
+            }
+            return (order, 1);
+        }
+
+        let (mcus_wide, mcus_high) = self.mcus();
+        for mcu_y in 0..mcus_high {
+            for mcu_x in 0..mcus_wide {
+                for &index in components {
+                    let component = &self.components[index];
+                    for y in 0..component.v {
+                        let row = (mcu_y * component.v + y) * component.blocks_wide;
+                        order.extend(
+                            (0..component.h).map(|x| (index, row + mcu_x * component.h + x)),
+                        );
+                    }
+                }
+            }
+        }
+        let mcu_blocks = components
+            .iter()
+            .map(|&index| self.components[index].h * self.components[index].v)
+            .sum();
+        (order, mcu_blocks)
+    }
+
+    /// Decode a sequential scan into the coefficients of its components, returning where the
+    /// entropy coded data ends.
+    fn decode_scan(
+        &mut self,
+        header: &[u8],
+        tables: &[[Option<HuffmanTable>; 4]; 2],
+        restart_interval: u16,
+        data: &[u8],
+        pos: usize,
+    ) -> Result<usize> {
+        let count = usize::from(*header.first().context("Invalid scan header")?);
+        ensure!(
+            header.len() == 4 + count * 2 && (1..=4).contains(&count),
+            "Invalid scan header"
+        );
+        ensure!(
+            header[1 + count * 2..] == [0, 63, 0],
+            "Only sequential JPEGs can be rewritten losslessly"
+        );
+
+        let mut components = Vec::new();
+        for spec in header[1..1 + count * 2].chunks_exact(2) {
+            let index = self
+                .components
+                .iter()
+                .position(|component| component.id == spec[0])
+                .context("Scan refers to a component which isn't in the frame")?;
+            let component = &mut self.components[index];
+            component.dc_table = Some(spec[1] >> 4);
+            component.ac_table = Some(spec[1] & 0xf);
+            components.push(index);
+        }
+
+        let decoder = |class: usize, id: Option<u8>| {
+            tables[class]
+                .get(usize::from(id.unwrap_or(u8::MAX)))
+                .and_then(Option::as_ref)
+                .map(HuffmanDecoder::new)
+                .context("Scan uses an undefined Huffman table")
+        };
+        let mut decoders: Vec<_> = self.components.iter().map(|_| None).collect();
+        for &index in &components {
+            let component = &self.components[index];
+            decoders[index] = Some((
+                decoder(DC, component.dc_table)?,
+                decoder(AC, component.ac_table)?,
+            ));
+        }
+
+        let (order, mcu_blocks) = self.scan_order(&components);
+        let interval = usize::from(restart_interval) * mcu_blocks;
+        let mut reader = BitReader {
+            data,
+            pos,
+            byte: 0,
+            left: 0,
+        };
+        let mut predictions = vec![0; self.components.len()];
+        let mut restarts = 0;
+
+        for (n, &(index, block)) in order.iter().enumerate() {
+            if interval > 0 && n > 0 && n % interval == 0 {
+                reader.restart(restarts)?;
+                restarts = (restarts + 1) % 8;
+                predictions.fill(0);
+            }
+            let (dc, ac) = decoders[index]
+                .as_ref()
+                .context("Scan has no Huffman tables")?;
+            let block = &mut self.components[index].blocks[block];
+
+            let size = dc.decode(&mut reader)?;
+            predictions[index] += reader.value(size)?;
+            block[0] = coefficient(predictions[index])?;
+
+            let mut k = 1;
+            while k < 64 {
+                let symbol = ac.decode(&mut reader)?;
+                let (run, size) = (usize::from(symbol >> 4), symbol & 0xf);
+                if size == 0 {
+                    if run != 15 {
+                        break;
+                    }
+                    k += 16;
+                    continue;
+                }
+                k += run;
+                ensure!(k < 64, "Too many coefficients in JPEG block");
+                block[k] = coefficient(reader.value(size)?)?;
+                k += 1;
+            }
+        }
+
+        jpeg::entropy_coded_end(data, reader.pos).context("Truncated JPEG data")
+    }
+
+    fn crop(&mut self, crop: Crop) -> Result<()> {
+        let [left, top, width, height] =
+            [crop.left, crop.top, crop.width, crop.height].map(|value| value as usize);
+        ensure!(
+            width > 0
+                && height > 0
+                && left.saturating_add(width) <= self.width
+                && top.saturating_add(height) <= self.height,
+            "Crop is outside of the JPEG"
+        );
+
+        let (h, v) = self.max_sampling();
+        let (mcu_left, mcu_top) = (left / (8 * h), top / (8 * v));
+        self.width = left + width - mcu_left * 8 * h;
+        self.height = top + height - mcu_top * 8 * v;
+        let (mcus_wide, mcus_high) = self.mcus();
+
+        for component in &mut self.components {
+            let (wide, high) = (mcus_wide * component.h, mcus_high * component.v);
+            let (x_start, y_start) = (mcu_left * component.h, mcu_top * component.v);
+            let mut blocks = Vec::with_capacity(wide * high);
+            for y in y_start..y_start + high {
+                let row = y * component.blocks_wide;
+                blocks.extend_from_slice(&component.blocks[row + x_start..row + x_start + wide]);
+            }
+            component.blocks = blocks;
+            (component.blocks_wide, component.blocks_high) = (wide, high);
+        }
+        Ok(())
+    }
+
+    fn transpose(&mut self) {
+        (self.width, self.height) = (self.height, self.width);
+        for table in self.quant_tables.iter_mut().flatten() {
+            table.values = transposed(&table.values);
+        }
+        for component in &mut self.components {
+            let mut blocks = Vec::with_capacity(component.blocks.len());
+            for x in 0..component.blocks_wide {
+                for y in 0..component.blocks_high {
+                    blocks.push(transposed(&component.blocks[y * component.blocks_wide + x]));
+                }
+            }
+            component.blocks = blocks;
+            (component.h, component.v) = (component.v, component.h);
+            (component.blocks_wide, component.blocks_high) =
+                (component.blocks_high, component.blocks_wide);
+        }
+    }
+
+    /// Mirror the image along `axis`, first trimming it to a whole number of MCUs in that
+    /// direction, since partial MCUs on the far edge can't be moved to the near one.
+    fn flip(&mut self, axis: Axis) -> Result<()> {
+        let (h, v) = self.max_sampling();
+        let (size, mcu_size) = match axis {
+            Axis::Horizontal => (&mut self.width, 8 * h),
+            Axis::Vertical => (&mut self.height, 8 * v),
+        };
+        let mcus = *size / mcu_size;
+        ensure!(mcus > 0, "JPEG is too small to flip losslessly");
+        *size = mcus * mcu_size;
+
+        for component in &mut self.components {
+            let (wide, high) = match axis {
+                Axis::Horizontal => (mcus * component.h, component.blocks_high),
+                Axis::Vertical => (component.blocks_wide, mcus * component.v),
+            };
+            let mut blocks = Vec::with_capacity(wide * high);
+            for y in 0..high {
+                for x in 0..wide {
+                    let (x, y) = match axis {
+                        Axis::Horizontal => (wide - 1 - x, y),
+                        Axis::Vertical => (x, high - 1 - y),
+                    };
+                    blocks.push(flipped(
+                        &component.blocks[y * component.blocks_wide + x],
+                        axis,
+                    ));
+                }
+            }
+            component.blocks = blocks;
+            (component.blocks_wide, component.blocks_high) = (wide, high);
+        }
+        Ok(())
+    }
+
+    /// Set any Orientation in the Exif to upright, once the image itself has been made upright.
+    fn reset_orientation(&mut self) {
+        for (marker, segment) in &mut self.metadata {
+            let Some(exif) = segment.strip_prefix(EXIF_HEADER) else {
+                continue;
+            };
+            if *marker == MARKER_APP1 && tiff::orientation(exif).is_some_and(|o| o != 1) {
+                tiff::set_orientation(&mut segment.to_mut()[EXIF_HEADER.len()..], 1);
+            }
+        }
+    }
+
+    /// The scans to write. Progressive JPEGs get their DC coefficients first, then the low
+    /// frequencies of the luma, then everything else.
+    fn scans(&self, progressive: bool) -> Vec<Scan> {
+        let all: Vec<_> = (0..self.components.len()).collect();
+        let mcu_blocks = self.components.iter().map(|c| c.h * c.v).sum::<usize>();
+        let groups = if all.len() == 1 || mcu_blocks <= MAX_MCU_BLOCKS {
+            vec![all.clone()]
+        } else {
+            all.iter().map(|&index| vec![index]).collect()
+        };
+        let scan = |components: Vec<usize>, start, end| Scan {
+            components,
+            start,
+            end,
+        };
+
+        if !progressive {
+            return groups.into_iter().map(|group| scan(group, 0, 63)).collect();
+        }
+        let mut scans: Vec<_> = groups.into_iter().map(|group| scan(group, 0, 0)).collect();
+        scans.push(scan(vec![0], 1, 5));
+        scans.extend((1..all.len()).map(|index| scan(vec![index], 1, 63)));
+        scans.push(scan(vec![0], 6, 63));
+        scans
+    }
+
+    fn encode_scan(&self, scan: &Scan, progressive: bool, sink: &mut impl Sink) {
+        let (order, _) = self.scan_order(&scan.components);
+        let mut predictions = vec![0; self.components.len()];
+        // Progressive scans only ever have one component when they have AC coefficients, so
+        // there's only one table to code the run of empty blocks with.
+        let eob_table = self.components[scan.components[0]].ac_table.unwrap_or(0);
+        let mut eob_run = progressive.then_some(0);
+
+        for &(index, block) in &order {
+            let component = &self.components[index];
+            let block = &component.blocks[block];
+
+            if scan.start == 0 {
+                let value = i32::from(block[0]);
+                let (size, bits) = magnitude(value - predictions[index]);
+                predictions[index] = value;
+                sink.symbol(DC, component.dc_table.unwrap_or(0), size);
+                sink.bits(bits, size);
+            }
+            if scan.end > 0 {
+                let table = component.ac_table.unwrap_or(0);
+                encode_ac(
+                    sink,
+                    table,
+                    &block[scan.start.max(1)..=scan.end],
+                    &mut eob_run,
+                );
+            }
+        }
+        if let Some(run) = eob_run.as_mut() {
+            flush_eob_run(sink, eob_table, run);
+        }
+    }
+
+    fn encode(&self, progressive: bool) -> Result<Vec<u8>> {
+        let mut out = JPEG_SOI.to_vec();
+        for (marker, segment) in &self.metadata {
+            write_segment(&mut out, *marker, segment)?;
+        }
+
+        let mut dqt = Vec::new();
+        for (id, table) in self.quant_tables.iter().enumerate() {
+            let Some(table) = table else { continue };
+            dqt.push(table.precision << 4 | id as u8);
+            for &value in &table.values {
+                if table.precision == 0 {
+                    dqt.push(value as u8);
+                } else {
+                    dqt.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+        }
+        write_segment(&mut out, MARKER_DQT, &dqt)?;
+
+        let mut sof = vec![self.precision];
+        for dimension in [self.height, self.width] {
+            sof.extend_from_slice(&u16::try_from(dimension)?.to_be_bytes());
+        }
+        sof.push(self.components.len() as u8);
+        for component in &self.components {
+            sof.extend_from_slice(&[
+                component.id,
+                (component.h << 4 | component.v) as u8,
+                component.quant_table,
+            ]);
+        }
+        let marker = if progressive {
+            MARKER_SOF2
+        } else {
+            self.marker
+        };
+        write_segment(&mut out, marker, &sof)?;
+
+        for scan in self.scans(progressive) {
+            let mut counter = Counter::default();
+            self.encode_scan(&scan, progressive, &mut counter);
+
+            let mut writer = BitWriter::default();
+            let mut dht = Vec::new();
+            for (class, tables) in counter.counts.iter().enumerate() {
+                for (id, counts) in tables.iter().enumerate() {
+                    if counts.iter().all(|&count| count == 0) {
+                        continue;
+                    }
+                    let table = optimal_table(counts);
+                    dht.push((class << 4 | id) as u8);
+                    dht.extend_from_slice(&table.counts);
+                    dht.extend_from_slice(&table.values);
+                    writer.codes[class][id] = codes(&table);
+                }
+            }
+            write_segment(&mut out, MARKER_DHT, &dht)?;
+
+            let mut sos = vec![scan.components.len() as u8];
+            for &index in &scan.components {
+                let component = &self.components[index];
+                let dc = if scan.start == 0 {
+                    component.dc_table.unwrap_or(0)
+                } else {
+                    0
+                };
+                let ac = if scan.end > 0 {
+                    component.ac_table.unwrap_or(0)
+                } else {
+                    0
+                };
+                sos.extend_from_slice(&[component.id, dc << 4 | ac]);
+            }
+            sos.extend_from_slice(&[scan.start as u8, scan.end as u8, 0]);
+            write_segment(&mut out, MARKER_SOS, &sos)?;
+
+            self.encode_scan(&scan, progressive, &mut writer);
+            writer.flush();
+            out.append(&mut writer.out);
+        }
+
+        out.extend_from_slice(&[0xff, MARKER_EOI]);
+        Ok(out)
+    }
+}
+
+/// Transpose a block of coefficients or quantisation table, which are both in zigzag order.
+fn transposed<T: Copy + Default>(values: &[T; 64]) -> [T; 64] {
+    let mut out = [T::default(); 64];
+    for (i, &value) in values.iter().enumerate() {
+        let (row, column) = (ZIGZAG[i] / 8, ZIGZAG[i] % 8);
+        out[UNZIGZAG[column * 8 + row]] = value;
+    }
+    out
+}
+
+/// Mirror a block along `axis`, which just means negating the odd frequencies in that direction.
+fn flipped(block: &Block, axis: Axis) -> Block {
+    let mut out = *block;
+    for (i, value) in out.iter_mut().enumerate() {
+        let (row, column) = (ZIGZAG[i] / 8, ZIGZAG[i] % 8);
+        let frequency = match axis {
+            Axis::Horizontal => column,
+            Axis::Vertical => row,
+        };
+        if frequency % 2 == 1 {
+            *value = value.saturating_neg();
+        }
+    }
+    out
+}
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, segment: &[u8]) -> Result<()> {
+    let length = u16::try_from(segment.len() + 2).context("JPEG segment is too long")?;
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&length.to_be_bytes());
+    out.extend_from_slice(segment);
+    Ok(())
+}
+
+/// Encode the AC coefficients of a block. Progressive scans pass `eob_run`, so that runs of
+/// blocks with nothing left in them can be coded together.
+fn encode_ac(sink: &mut impl Sink, table: u8, coefficients: &[i16], eob_run: &mut Option<u32>) {
+    let mut zeros = 0;
+    for &value in coefficients {
+        if value == 0 {
+            zeros += 1;
+            continue;
+        }
+        if let Some(run) = eob_run.as_mut() {
+            flush_eob_run(sink, table, run);
+        }
+        while zeros > 15 {
+            sink.symbol(AC, table, 0xf0);
+            zeros -= 16;
+        }
+        let (size, bits) = magnitude(value.into());
+        sink.symbol(AC, table, zeros << 4 | size);
+        sink.bits(bits, size);
+        zeros = 0;
+    }
+
+    if zeros > 0 {
+        match eob_run {
+            Some(run) => {
+                *run += 1;
+                if *run == 0x7fff {
+                    flush_eob_run(sink, table, run);
+                }
+            }
+            None => sink.symbol(AC, table, 0),
+        }
+    }
+}
+
+fn flush_eob_run(sink: &mut impl Sink, table: u8, run: &mut u32) {
+    if *run > 0 {
+        let size = (31 - run.leading_zeros()) as u8;
+        sink.symbol(AC, table, size << 4);
+        sink.bits(*run, size);
+        *run = 0;
+    }
+}
+
+/// Where encoded symbols go. Each scan is encoded twice, first to count the symbols so that the
+/// Huffman tables can be built, and then to write it.
+trait Sink {
+    fn symbol(&mut self, class: usize, table: u8, symbol: u8);
+    fn bits(&mut self, bits: u32, size: u8);
+}
+
+struct Counter {
+    counts: [[[u32; 256]; 4]; 2],
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self {
+            counts: [[[0; 256]; 4]; 2],
+        }
+    }
+}
+
+impl Sink for Counter {
+    fn symbol(&mut self, class: usize, table: u8, symbol: u8) {
+        self.counts[class][usize::from(table)][usize::from(symbol)] += 1;
+    }
+
+    fn bits(&mut self, _bits: u32, _size: u8) {}
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    len: u32,
+    /// The code and its length for every symbol in every table.
+    codes: [[[(u16, u8); 256]; 4]; 2],
+}
+
+impl Default for BitWriter {
+    fn default() -> Self {
+        Self {
+            out: Vec::new(),
+            acc: 0,
+            len: 0,
+            codes: [[[(0, 0); 256]; 4]; 2],
+        }
+    }
+}
+
+impl BitWriter {
+    /// Pad the last byte with ones, as the spec requires.
+    fn flush(&mut self) {
+        if self.len > 0 {
+            let size = 8 - self.len as u8;
+            self.bits(u32::MAX, size);
+        }
+    }
+}
+
+impl Sink for BitWriter {
+    fn symbol(&mut self, class: usize, table: u8, symbol: u8) {
+        let (code, size) = self.codes[class][usize::from(table)][usize::from(symbol)];
+        self.bits(code.into(), size);
+    }
+
+    fn bits(&mut self, bits: u32, size: u8) {
+        let size = u32::from(size);
+        if size == 0 {
+            return;
+        }
+        self.acc = (self.acc << size) | (bits & ((1 << size) - 1));
+        self.len += size;
+        while self.len >= 8 {
+            self.len -= 8;
+            let byte = (self.acc >> self.len) as u8;
+            self.out.push(byte);
+            if byte == 0xff {
+                self.out.push(0);
+            }
+        }
+        self.acc &= (1 << self.len) - 1;
+    }
+}
+
+/// Build the optimal Huffman table for these symbol counts, with no code longer than 16 bits and
+/// no code of all ones. This is the procedure from Annex K.2 of the spec, as used by libjpeg.
+fn optimal_table(counts: &[u32; 256]) -> HuffmanTable {
+    // One extra symbol with the smallest count reserves the all ones code, so nothing gets it.
+    let mut freq = [0; 257];
+    freq[..256].copy_from_slice(counts);
+    freq[256] = 1;
+    let mut code_size = [0usize; 257];
+    let mut others = [None::<usize>; 257];
+
+    // Repeatedly merge the two least frequent trees. Ties go to the highest symbol, which
+    // keeps the reserved symbol at the bottom of the tree.
+    let smallest = |freq: &[u32; 257], exclude: Option<usize>| {
+        let mut found: Option<usize> = None;
+        for symbol in 0..257 {
+            if freq[symbol] > 0
+                && Some(symbol) != exclude
+                && found.is_none_or(|found| freq[symbol] <= freq[found])
+            {
+                found = Some(symbol);
+            }
+        }
+        found
+    };
+    while let Some(first) = smallest(&freq, None) {
+        let Some(second) = smallest(&freq, Some(first)) else {
+            break;
+        };
+        freq[first] += freq[second];
+        freq[second] = 0;
+
+        let mut symbol = first;
+        code_size[symbol] += 1;
+        while let Some(next) = others[symbol] {
+            symbol = next;
+            code_size[symbol] += 1;
+        }
+        others[symbol] = Some(second);
+        let mut symbol = second;
+        code_size[symbol] += 1;
+        while let Some(next) = others[symbol] {
+            symbol = next;
+            code_size[symbol] += 1;
+        }
+    }
+
+    let mut bits = [0u32; 33];
+    for &size in code_size.iter().filter(|&&size| size > 0) {
+        bits[size.min(32)] += 1;
+    }
+    // Shorten any codes which are too long, by moving pairs of them up the tree.
+    for size in (17..=32).rev() {
+        while bits[size] > 0 {
+            let mut shorter = size - 2;
+            while bits[shorter] == 0 {
+                shorter -= 1;
+            }
+            bits[size] -= 2;
+            bits[size - 1] += 1;
+            bits[shorter + 1] += 2;
+            bits[shorter] -= 1;
+        }
+    }
+    // Then remove the reserved code, which is always one of the longest.
+    if let Some(size) = (1..=16).rev().find(|&size| bits[size] > 0) {
+        bits[size] -= 1;
+    }
+
+    let mut table = HuffmanTable::default();
+    for (count, &bits) in table.counts.iter_mut().zip(&bits[1..=16]) {
+        *count = bits as u8;
+    }
+    for size in 1..=32 {
+        table
+            .values
+            .extend((0..=255u8).filter(|&symbol| code_size[usize::from(symbol)] == size));
+    }
+    table
+}
+
+/// Assign the canonical codes for a table to its symbols.
+fn codes(table: &HuffmanTable) -> [(u16, u8); 256] {
+    let mut codes = [(0, 0); 256];
+    let mut values = table.values.iter();
+    let mut code = 0u32;
+    for (size, &count) in (1..=16).zip(&table.counts) {
+        for &symbol in values.by_ref().take(count.into()) {
+            codes[usize::from(symbol)] = (code as u16, size);
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
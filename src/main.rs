@@ -1,37 +1,958 @@
-use anyhow::{ensure, Result};
-use byteorder::{BigEndian, ByteOrder, LittleEndian};
-use clap::Parser;
+use anyhow::{bail, ensure, Context, Result};
+use attributes::{Attributes, Owner, Preserve};
+use chrono::{DateTime, FixedOffset, TimeDelta};
+use clap::{Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressStyle};
 use memmap2::{Advice, Mmap};
-use std::collections::HashSet;
-use std::ffi::OsString;
-use std::os::unix::io::AsRawFd;
+use rawtojpg::lossless::{self, Rewrite, Transform};
+use rawtojpg::{ImageFormat, LargestBy, Metadata, Options};
+use std::borrow::Cow;
+#[cfg(feature = "contact-sheet")]
+use std::collections::BTreeMap;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::FileTimes;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs::{self, File};
 use tokio::io::AsyncWriteExt;
 use tokio::sync::Semaphore;
 
+mod attributes;
+mod capture_time;
+#[cfg(feature = "catalog")]
+mod catalog;
+mod checksum;
+#[cfg(feature = "contact-sheet")]
+mod contact_sheet;
+#[cfg(feature = "verify-decode")]
+mod decode;
+mod exclude;
+mod exiftool;
+mod gallery;
+mod json_sidecar;
+#[cfg(feature = "libraw-fallback")]
+mod libraw;
+mod manifest;
+mod naming;
+mod orphans;
+#[cfg(feature = "perceptual-hash")]
+mod perceptual_hash;
+#[cfg(feature = "placeholders")]
+mod placeholder;
+mod report;
+#[cfg(feature = "resize")]
+mod resize;
+#[cfg(feature = "state-db")]
+mod state;
+#[cfg(feature = "resize")]
+use resize::OutputFormat;
+mod summary;
+mod verify;
+#[cfg(feature = "watermark")]
+mod watermark;
+mod xmp;
+
+/// The extended attributes --source-xattrs sets on each preview.
+const SOURCE_XATTR: &str = "user.rawtojpg.source";
+const SOURCE_SHA256_XATTR: &str = "user.rawtojpg.source_sha256";
+
+/// What's given instead of a path to read from stdin, or write to stdout.
+const STDIO: &str = "-";
+
+/// The extended attribute --stamp sets on each RAW once it's been done.
+const STAMP_XATTR: &str = "user.rawtojpg.stamp";
+
 #[derive(Parser)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Option<Args>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check that the previews in the output directory are still what the RAWs give
+    ///
+    /// Give it the same options the previews were written with. Nothing is written, and previews
+    /// which differ or are missing are listed, failing if there are any. With --manifest, they're
+    /// checked against the hashes in it instead of being read
+    Verify(Args),
+}
+
+#[derive(clap::Args)]
 struct Args {
-    /// Input directory containing RAW files
-    input_dir: PathBuf,
+    /// Input directories containing RAW files, or RAW files themselves, which can be globs like
+    /// "misc/IMG_*.ARW". Unless there's --output, the last of two or more is the output directory
+    /// to store extracted JPEGs in, which is otherwise the current one. It can't be an existing
+    /// file
+    #[arg(required_unless_present = "files_from", value_name = "INPUT")]
+    paths: Vec<PathBuf>,
 
-    /// Output directory to store extracted JPEGs
-    #[arg(default_value = ".")]
-    output_dir: PathBuf,
+    /// Read more inputs from this file, one per line, or from stdin if it's -, like those found by
+    /// find. They're taken as they are, without expanding globs. The last of the paths given is
+    /// then always the output directory, unless there's --output
+    #[arg(long, value_name = "FILE")]
+    files_from: Option<PathBuf>,
+
+    /// Separate the inputs in --files-from with NULs rather than newlines, like find -print0 does,
+    /// so that they can have newlines in them
+    #[arg(short = '0', long, requires = "files_from")]
+    null: bool,
+
+    /// Where to write: the output directory, if it is one or ends with a slash, so that all of the
+    /// paths are inputs, or for a single RAW file, exactly where to write its preview. Anything
+    /// written alongside that is named after it, like out.jpg.xmp
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
 
     /// How many files to process at once
     #[arg(short, long, default_value_t = 8)]
     transfers: usize,
 
+    /// Name previews from this template, rather than after the RAW, like
+    /// "{date:%Y%m%d}_{model}_{stem}.jpg". The variables are {date}, which takes a strftime format
+    /// and is when the picture was taken, {make}, {model}, {lens}, and {stem}, the name of the RAW
+    /// without its extension, and {seq}, which numbers the pictures in the order they were taken,
+    /// and takes how many digits to pad to, like {seq:4}, which is the default. Any the RAW doesn't
+    /// have are "unknown". The preview's extension replaces the template's, or is added if it has
+    /// none. Slashes make directories under the one the RAW would have gone in
+    #[arg(long, value_name = "TEMPLATE", value_parser = naming::parse_template)]
+    name_template: Option<naming::Template>,
+
+    /// Name previews by when the picture was taken, and then the name of the RAW, like
+    /// 20240513_142355_IMG_0001.jpg, so that they sort in the order they were taken even across
+    /// cameras. This is the same as --name-template "{date}_{stem}"
+    #[arg(long, conflicts_with = "name_template")]
+    rename_by_date: bool,
+
+    /// Keep the RAW's extension before the preview's, like IMG_0001.ARW.jpg, so that RAWs with the
+    /// same name but different extensions don't overwrite each other's previews
+    #[arg(long)]
+    keep_source_ext: bool,
+
+    /// Put this before the name of every preview, like raw_, for IMG_0001.jpg to be
+    /// raw_IMG_0001.jpg
+    #[arg(long, default_value = "", hide_default_value = true, value_parser = naming::parse_affix)]
+    prefix: String,
+
+    /// Put this after the name of every preview, before its extension, like _preview, for
+    /// IMG_0001.jpg to be IMG_0001_preview.jpg, so that previews can go next to finals exported
+    /// with the same names without overwriting them
+    #[arg(long, default_value = "", hide_default_value = true, value_parser = naming::parse_affix)]
+    suffix: String,
+
+    /// Percent-escape anything in the names of previews and their directories which would trip up
+    /// other systems, like newlines, colons and other characters Windows and SMB shares don't
+    /// allow, and bytes which aren't UTF-8. Percent signs are escaped too, like %25
+    #[arg(long)]
+    sanitize_names: bool,
+
+    /// Name and lay out previews by when they were taken in this time zone, like +02:00, rather
+    /// than by the camera's clock. This is for cameras set to UTC, or to wherever home is
+    #[arg(
+        long,
+        value_name = "OFFSET",
+        allow_hyphen_values = true,
+        value_parser = capture_time::parse_offset
+    )]
+    tz_offset: Option<FixedOffset>,
+
+    /// The time zone camera clocks were set to, like +00:00, for pictures whose Exif has no
+    /// OffsetTimeOriginal. The local time zone is assumed otherwise
+    #[arg(
+        long,
+        value_name = "OFFSET",
+        allow_hyphen_values = true,
+        value_parser = capture_time::parse_offset
+    )]
+    camera_tz: Option<FixedOffset>,
+
+    /// Find bursts, which are pictures taken by the same camera within this many milliseconds of
+    /// each other, and name their previews like IMG_0001_burst3_2of9.jpg, for the second of nine
+    /// pictures in the third burst. RAWs without a SubSecTimeOriginal only say when they were
+    /// taken to the second
+    #[arg(long, value_name = "MS")]
+    burst_gap: Option<u64>,
+
+    /// Put each burst in its own directory, like burst3, rather than naming its previews after it
+    #[arg(long, requires = "burst_gap")]
+    burst_dirs: bool,
+
+    /// The number {seq} starts from
+    #[arg(long, default_value_t = 1, requires = "name_template")]
+    seq_start: u64,
+
+    /// How to lay out the directories previews are written to: mirror, to match the input
+    /// directory, date, to go by when each picture was taken instead, which takes a strftime
+    /// format like date:%Y/%m/%d, which is the default, camera, to go by the model of camera, or
+    /// flat, to put them all in the output directory itself. Pictures which don't say go in
+    /// unknown
+    #[arg(long, default_value = "mirror", value_parser = naming::parse_layout)]
+    layout: naming::Layout,
+
+    /// Write all previews to the output directory itself, rather than mirroring the input
+    /// directory. This is the same as --layout flat. RAWs whose previews would have the same name
+    /// are errors, unless --on-collision says otherwise
+    #[arg(long, conflicts_with = "layout")]
+    flatten: bool,
+
+    /// Leave RAWs alone if their previews are already in the output directory, so that running
+    /// again over a growing archive only extracts the new ones. This is the default, unless
+    /// --force is given
+    #[arg(
+        long,
+        visible_alias = "skip-existing",
+        conflicts_with_all = ["force", "checksum"]
+    )]
+    no_clobber: bool,
+
+    /// Overwrite previews which are already in the output directory
+    #[arg(long)]
+    force: bool,
+
+    /// Overwrite previews which are already in the output directory only if their RAWs have been
+    /// modified since, like rsync -u. With --preserve-times, they're up to date as long as the
+    /// times are the same
+    #[arg(long, conflicts_with_all = ["force", "no_clobber"])]
+    update: bool,
+
+    /// Stamp each RAW once it's been done with a user.rawtojpg.stamp extended attribute, with
+    /// this version of rawtojpg and the RAW's size, modification time, and SHA-256, and skip RAWs
+    /// whose stamps still match, wherever their previews would go. Stamps don't record any other
+    /// options, so RAWs aren't done again if only those change. RAWs which can't be stamped, like
+    /// those on read-only cards, are done every time
+    #[arg(long, conflicts_with_all = ["exif", "delete_orphans"])]
+    stamp: bool,
+
+    /// Record which RAWs have been done in this SQLite database, with their sizes, modification
+    /// times, and whether they worked, and skip those which worked and haven't changed since, so
+    /// that an interrupted run carries on where it stopped. Like --stamp, other options aren't
+    /// recorded
+    #[cfg(feature = "state-db")]
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["exif", "delete_orphans"])]
+    state_db: Option<PathBuf>,
+
+    /// Overwrite previews which are already in the output directory only where they've changed,
+    /// like rsync -c, so that those which haven't keep their modification times
+    #[arg(long)]
+    checksum: bool,
+
+    /// Delete previews, and anything else written for RAWs, which are in the output directory but
+    /// weren't written for any of the RAWs in this run, like those of RAWs which have since been
+    /// deleted, and then any directories that leaves empty. Contact sheets, galleries, and the
+    /// --report are left alone. The input directory can't be inside the output directory
+    #[arg(long, conflicts_with = "exif")]
+    delete_orphans: bool,
+
+    /// What to do when two RAWs would have previews with the same name, like IMG_0001.ARW and
+    /// IMG_0001.DNG, or the same name in different directories with --flatten
+    #[arg(long, value_enum, default_value_t = naming::Collision::Error)]
+    on_collision: naming::Collision,
+
     /// Look for this extension in addition to the default list.
     ///
-    /// Default list: arw, cr2, crw, dng, erf, kdc, mef, mrw, nef, nrw, orf, pef, raf, raw, rw2,
-    /// rwl, sr2, srf, srw, x3f
+    /// Default list: arw, cr2, cr3, crw, dng, erf, heic, heif, hif, iiq, kdc, mef, mrw, nef, nrw, orf,
+    /// pef, raf, raw, rw2, rwl, sr2, srf, srw, tif, tiff, x3f
     #[arg(short, long)]
     extension: Option<OsString>,
+
+    /// Don't look for this extension, taking it out of the default list, whatever its case. Can be
+    /// given more than once
+    #[arg(long, value_name = "EXT")]
+    exclude_ext: Vec<OsString>,
+
+    /// Skip files and directories matching this pattern when searching input directories, like
+    /// rsync. Patterns without slashes match names anywhere, like "*.tmp", and the rest match
+    /// paths under the input directory, like "**/rejects/**". A trailing slash means only
+    /// directories match. Can be given more than once
+    #[arg(long, value_name = "PATTERN", value_parser = exclude::parse)]
+    exclude: Vec<exclude::Pattern>,
+
+    /// Only take RAWs matching this regex when searching input directories, like
+    /// '^DSC0[0-9]{4}\.ARW$'. Regexes with slashes match paths under the input directory, and the
+    /// rest match file names. Can be given more than once, in which case RAWs matching any of them
+    /// are taken
+    #[arg(long = "match", value_name = "REGEX", value_parser = exclude::parse_regex)]
+    matches: Vec<exclude::Regex>,
+
+    /// Skip RAWs matching this regex when searching input directories, the same way as --match.
+    /// Can be given more than once
+    #[arg(long, value_name = "REGEX", value_parser = exclude::parse_regex)]
+    no_match: Vec<exclude::Regex>,
+
+    /// Only search directories up to this many levels below each input directory, so 1 searches
+    /// the input directory and the directories directly in it
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Only search each input directory itself, not the directories in it. This is the same as
+    /// --max-depth 0
+    #[arg(long, conflicts_with = "max_depth")]
+    no_recursive: bool,
+
+    /// Search directories which are symlinked to, as well as taking RAWs which are. Each
+    /// directory is only searched once, however many ways there are to it, so symlink loops are
+    /// skipped
+    #[arg(long, overrides_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
+
+    /// Only take RAWs which are symlinked to, not searching directories which are. This is the
+    /// default
+    #[arg(long, overrides_with = "follow_symlinks")]
+    no_follow_symlinks: bool,
+
+    /// Don't search directories on other filesystems than the input directory they're under, like
+    /// mount points for other drives
+    #[arg(short = 'x', long)]
+    one_file_system: bool,
+
+    /// Ignore embedded images which aren't JPEGs, like the JPEG XL previews in newer DNGs, instead
+    /// of extracting them with their own extension
+    #[arg(long)]
+    jpeg_only: bool,
+
+    /// If no JPEG can be found by parsing the file, search the whole file for anything that looks
+    /// like one. This is much slower, but can recover previews from damaged files
+    #[arg(long)]
+    scan_fallback: bool,
+
+    /// Extract the Nth embedded image in each file, counting from 0 in the order they're found,
+    /// instead of the largest
+    #[arg(long, value_name = "N")]
+    preview_index: Option<usize>,
+
+    /// Treat files whose preview is smaller than this as having no usable preview, instead of
+    /// extracting a tiny thumbnail. Takes a number of bytes, or a size like 500K or 2M
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_preview_bytes: Option<u64>,
+
+    /// Treat files whose preview is smaller than this in either dimension as having no usable
+    /// preview, like 3000x2000
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_dimensions)]
+    min_dimensions: Option<(u32, u32)>,
+
+    /// Extract previews which don't meet --min-preview-bytes or --min-dimensions anyway, with a
+    /// warning, since any preview is better than none for some workflows
+    #[arg(long, visible_alias = "allow-small")]
+    best_effort: bool,
+
+    /// Warn when the preview's longest side is less than this percentage of the RAW image's, which
+    /// usually means the camera was set to embed a lower quality preview
+    #[arg(long, value_name = "PERCENT")]
+    warn_below_resolution: Option<u64>,
+
+    /// How to decide which embedded image is the largest
+    #[arg(long, value_enum, default_value_t = LargestBy::default())]
+    largest_by: LargestBy,
+
+    /// Fully decode each extracted JPEG, and reject it if that fails. This is much slower, but
+    /// catches corruption which the markers alone don't show
+    #[cfg(feature = "verify-decode")]
+    #[arg(long)]
+    verify_decode: bool,
+
+    /// Also write downscaled copies of each preview which fit in these sizes, like 256,1024,2048.
+    /// They're written as NAME.256.jpg and so on. Previews already smaller than a size are copied
+    /// as they are
+    #[cfg(feature = "resize")]
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "SIZES",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with = "all_previews"
+    )]
+    sizes: Vec<u32>,
+
+    /// Scale previews down so that their long edge is at most this many pixels
+    #[cfg(feature = "resize")]
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with = "all_previews"
+    )]
+    max_dimension: Option<u32>,
+
+    /// The format to write previews in. Previews are only decoded and encoded again if this isn't
+    /// jpg, or they have to be scaled down
+    #[cfg(feature = "resize")]
+    #[arg(long, value_enum, default_value_t = OutputFormat::Jpg, conflicts_with = "all_previews")]
+    format: OutputFormat,
+
+    /// The quality to use for previews which have to be encoded again, from 1 to 100. PNGs are
+    /// lossless, so this doesn't apply to them
+    #[cfg(feature = "resize")]
+    #[arg(long, default_value_t = 85, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+
+    /// Convert previews which their Exif says are Adobe RGB to sRGB, so that they don't look dull
+    /// in viewers which ignore colour spaces, like most browsers. This means decoding and encoding
+    /// them again, but only for previews which are Adobe RGB
+    #[cfg(feature = "resize")]
+    #[arg(long, conflicts_with = "all_previews")]
+    to_srgb: bool,
+
+    /// Composite this image, usually a PNG with transparency, onto each preview. This means
+    /// decoding and encoding every preview again
+    #[cfg(feature = "watermark")]
+    #[arg(long, value_name = "FILE", conflicts_with = "all_previews")]
+    watermark: Option<PathBuf>,
+
+    /// Which corner of the preview to put the watermark in, or the center
+    #[cfg(feature = "watermark")]
+    #[arg(long, value_enum, default_value_t)]
+    watermark_position: watermark::Position,
+
+    /// How opaque the watermark is, from 0 to 1, on top of its own transparency
+    #[cfg(feature = "watermark")]
+    #[arg(long, default_value_t = 0.5, value_parser = parse_opacity)]
+    watermark_opacity: f32,
+
+    /// Write a JSON report of where each preview came from and was written to here
+    #[arg(long, value_name = "FILE", conflicts_with = "all_previews")]
+    report: Option<PathBuf>,
+
+    /// Write the hash of everything written to this file, like sha256sums.txt, in the format
+    /// sha256sum or b3sum read, with paths relative to the output directory. The hashes come from
+    /// what's written, rather than reading it all back
+    #[arg(long, value_name = "FILE", conflicts_with = "exif")]
+    manifest: Option<PathBuf>,
+
+    /// The hash to use for --manifest
+    #[arg(long, value_enum, default_value_t = manifest::Algorithm::Sha256, requires = "manifest")]
+    manifest_algorithm: manifest::Algorithm,
+
+    /// Record each preview in this SQLite database, with the RAW it came from, when it was taken,
+    /// the camera and lens, its dimensions, and the SHA-256s of both, replacing what was there for
+    /// previews which are written again. Like --provenance, this means reading the whole RAW
+    #[cfg(feature = "catalog")]
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["all_previews", "exif"])]
+    catalog: Option<PathBuf>,
+
+    /// Compute these placeholders for each preview and include them in the --report, like
+    /// blurhash,thumbhash
+    #[cfg(feature = "placeholders")]
+    #[arg(long, value_enum, value_delimiter = ',', requires = "report")]
+    placeholders: Vec<placeholder::Kind>,
+
+    /// Compute these perceptual hashes for each preview and include them in the --report, like
+    /// dhash,phash. Previews which look alike have hashes which differ in only a few bits, which
+    /// makes it easy to find bursts and brackets
+    #[cfg(feature = "perceptual-hash")]
+    #[arg(
+        long = "perceptual-hash",
+        value_name = "KINDS",
+        value_enum,
+        value_delimiter = ',',
+        requires = "report"
+    )]
+    perceptual_hashes: Vec<perceptual_hash::Kind>,
+
+    /// Also write an index.html in each output directory, with thumbnails linking to the previews
+    /// and links to the directories under it. The smallest of --sizes is used for the thumbnails if
+    /// there are any
+    #[arg(long, conflicts_with = "all_previews")]
+    gallery: bool,
+
+    /// Also tile the previews in each output directory into contact sheets, in file name order
+    #[cfg(feature = "contact-sheet")]
+    #[arg(long, conflicts_with = "all_previews")]
+    contact_sheet: bool,
+
+    /// How many thumbnails go across each contact sheet
+    #[cfg(feature = "contact-sheet")]
+    #[arg(long, value_name = "N", default_value_t = 6, value_parser = clap::value_parser!(u32).range(1..))]
+    contact_sheet_columns: u32,
+
+    /// How many rows of thumbnails go on each contact sheet before starting another
+    #[cfg(feature = "contact-sheet")]
+    #[arg(long, value_name = "N", default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..))]
+    contact_sheet_rows: u32,
+
+    /// The width and height of each cell on a contact sheet, in pixels
+    #[cfg(feature = "contact-sheet")]
+    #[arg(long, value_name = "PIXELS", default_value_t = 300, value_parser = clap::value_parser!(u32).range(16..=4096))]
+    contact_sheet_cell: u32,
+
+    /// The format to write contact sheets in
+    #[cfg(feature = "contact-sheet")]
+    #[arg(long, value_enum, default_value_t)]
+    contact_sheet_format: contact_sheet::SheetFormat,
+
+    /// The watermark itself, loaded once before any files are processed.
+    #[cfg(feature = "watermark")]
+    #[arg(skip)]
+    watermark_image: Option<watermark::Watermark>,
+
+    /// What everything is checked against instead of being written, for the verify command.
+    #[arg(skip)]
+    verifier: Option<verify::Verifier>,
+
+    /// The inputs from the paths, with any globs expanded.
+    #[arg(skip)]
+    inputs: Vec<PathBuf>,
+
+    /// The output directory from the paths or --output.
+    #[arg(skip)]
+    output_dir: PathBuf,
+
+    /// Keep any padding after the end of the JPEG which is included in its length, instead of
+    /// trimming it
+    #[arg(long)]
+    keep_padding: bool,
+
+    /// Losslessly rewrite JPEG previews with Huffman tables optimised for them, which usually
+    /// makes them a few percent smaller
+    #[arg(long, conflicts_with = "all_previews")]
+    optimize: bool,
+
+    /// Losslessly rewrite JPEG previews as progressive JPEGs. This implies --optimize
+    #[arg(long, conflicts_with = "all_previews")]
+    progressive: bool,
+
+    /// Losslessly rotate JPEG previews to be upright, based on their Orientation, for viewers which
+    /// ignore it. Like jpegtran -trim, partial blocks at the edges which would end up on the top or
+    /// left are dropped
+    #[arg(long, conflicts_with = "all_previews")]
+    auto_rotate: bool,
+
+    /// Losslessly crop DNG previews to the DefaultCrop of the main image, for previews which
+    /// include the borders around it. The left and top can only be cropped to a multiple of 8 or
+    /// 16 pixels, so a little of the border may be left there
+    #[arg(long, conflicts_with = "all_previews")]
+    default_crop: bool,
+
+    /// Copy the Orientation from the RAW into the Exif of JPEG previews, adding a minimal Exif
+    /// segment if they have none, so that viewers show them the right way up. Unlike
+    /// --auto-rotate, the pixels aren't touched
+    #[arg(long, conflicts_with_all = ["auto_rotate", "all_previews"])]
+    copy_orientation: bool,
+
+    /// Copy the main Exif from TIFF based RAWs into JPEG previews, replacing whatever Exif they
+    /// had: the camera, lens, exposure, dates, and copyright, as well as any GPS position. The
+    /// MakerNote and anything describing the RAW data itself are left out
+    #[arg(long, conflicts_with = "all_previews")]
+    copy_exif: bool,
+
+    /// Record where each preview came from in its Exif UserComment, like "extracted-from:
+    /// IMG_0001.ARW sha256:...", so that it can be traced back to the exact RAW later. The whole
+    /// RAW has to be read to hash it, so this is slower
+    #[arg(long, conflicts_with_all = ["strip_exif", "all_previews"])]
+    provenance: bool,
+
+    /// Set the user.rawtojpg.source and user.rawtojpg.source_sha256 extended attributes on each
+    /// preview, to the absolute path of the RAW and its SHA-256, for checking and deduplicating
+    /// without sidecar files. Like --provenance, this means reading the whole RAW
+    #[arg(long, conflicts_with = "all_previews")]
+    source_xattrs: bool,
+
+    /// Remove the GPS position from the Exif of previews, whether it was copied by --copy-exif or
+    /// was in the preview already, keeping the rest of the metadata. XMP with a GPS position in it
+    /// is removed entirely, since it can't safely be edited
+    #[arg(long)]
+    strip_gps: bool,
+
+    /// Remove all metadata from previews, like Exif, XMP, IPTC, and comments, wherever it is in the
+    /// file, so that nothing is published by accident. Only what's needed to show them properly,
+    /// like ICC profiles, is kept. That includes the Orientation, so use --auto-rotate as well for
+    /// pictures taken on their side. Previews which are malformed enough that this can't be done
+    /// aren't written at all
+    #[arg(long, conflicts_with_all = ["copy_exif", "copy_orientation"])]
+    strip_exif: bool,
+
+    /// Write an XMP sidecar next to each preview, like IMG_0001.jpg.xmp, with when the picture was
+    /// taken, the camera and lens, and where the RAW is, for photo managers like digiKam and
+    /// darktable to pick up
+    #[arg(long, conflicts_with = "all_previews")]
+    xmp_sidecar: bool,
+
+    /// Write a JSON sidecar next to each preview, like IMG_0001.jpg.json, with where the RAW is,
+    /// where the preview was in it and its size, and the main Exif fields, for scripts which would
+    /// otherwise have to read the RAW again
+    #[arg(long, conflicts_with = "all_previews")]
+    json_sidecar: bool,
+
+    /// Print a JSON array describing each RAW file once they've all been done, with the same field
+    /// names and formatting as `exiftool -json`, like SourceFile and PreviewImageLength, so that
+    /// scripts which parse that can use this instead
+    #[arg(long, conflicts_with = "all_previews")]
+    json: bool,
+
+    /// Just print a line for each RAW file with when it was taken, the camera and lens, and the
+    /// exposure and ISO, separated by tabs, without writing anything. The output directory isn't
+    /// used
+    #[arg(long, conflicts_with_all = ["all_previews", "report", "gallery", "json"])]
+    exif: bool,
+
+    /// Set the access and modification times of each preview, and of anything written alongside
+    /// it, to those of the RAW it came from, rather than when it was written
+    #[arg(long)]
+    preserve_times: bool,
+
+    /// Set the access and modification times of each preview, and of anything written alongside
+    /// it, to when the picture was taken, from the DateTimeOriginal in its Exif. That's in the
+    /// local time zone unless the Exif or --camera-tz says otherwise. With --preserve-times, the
+    /// RAW's own times are used for those which don't say when they were taken
+    #[arg(long)]
+    times_from_exif: bool,
+
+    /// Write exactly the same files every time for the same RAWs and options, for archiving by
+    /// content or making reproducible tarballs. RAWs are done in the order of their paths, which
+    /// decides which is the second when two previews are named the same way, everything written
+    /// for a RAW gets the time it was taken, like --times-from-exif, and everything else, along
+    /// with the directories which are written in, gets the start of 1970
+    #[arg(long, conflicts_with = "preserve_times")]
+    deterministic: bool,
+
+    /// Copy these from each RAW onto everything written for it, like perms,owner,xattr
+    #[arg(long, value_enum, value_delimiter = ',')]
+    preserve: Vec<Preserve>,
+
+    /// Give everything written, and every directory created, this mode, in octal like 0644, over
+    /// any from --preserve. Directories are also made searchable by whoever can read them
+    #[arg(long, value_name = "MODE", value_parser = attributes::parse_mode)]
+    chmod: Option<u32>,
+
+    /// Give everything written, and every directory created, to this user and group, like
+    /// photos:photos, photos, or :photos, over any from --preserve
+    #[arg(long, value_name = "USER:GROUP", value_parser = attributes::parse_owner)]
+    chown: Option<Owner>,
+
+    /// Embed an ICC profile in JPEG previews which don't have one, so that colour managed viewers
+    /// show them correctly. This is the RAW's own profile if it has one, or a standard Adobe RGB
+    /// profile if the Exif says that's what the preview is in
+    #[arg(long, conflicts_with = "all_previews")]
+    embed_icc: bool,
+
+    /// Extract the small Exif thumbnail, rather than the largest preview. If there isn't one, the
+    /// smallest embedded image is used instead
+    #[arg(long, conflicts_with = "preview_index")]
+    thumbnail: bool,
+
+    /// Extract every embedded image, rather than just the largest, as NAME.preview0.jpg,
+    /// NAME.preview1.jpg, and so on, numbered like --preview-index
+    #[arg(
+        long,
+        conflicts_with_all = ["preview_index", "thumbnail", "min_preview_bytes", "min_dimensions"]
+    )]
+    all_previews: bool,
+
+    /// Fail on any problem with a file's structure, instead of using whatever can still be found
+    #[arg(long, overrides_with = "lenient")]
+    strict: bool,
+
+    /// Skip over broken parts of a file's structure, like bad IFDs or MakerNotes, and use whatever
+    /// can still be found. This is the default
+    #[arg(long, overrides_with = "strict")]
+    lenient: bool,
+}
+
+impl Args {
+    /// What to set on anything written which doesn't come from a single RAW, and on directories.
+    fn output_attributes(&self) -> Attributes {
+        let mut attributes = Attributes::default().with_overrides(self.chmod, self.chown);
+        if self.deterministic {
+            attributes.times = Some(epoch_times());
+        }
+        attributes
+    }
+
+    /// When a picture was taken, in the time zone it should be named for.
+    fn capture_time(&self, metadata: &Metadata) -> Option<DateTime<FixedOffset>> {
+        let time = capture_time::parse(metadata, self.camera_tz)?;
+        Some(
+            self.tz_offset
+                .map_or(time, |offset| time.with_timezone(&offset)),
+        )
+    }
+
+    /// Whether anything needs to know how the picture was taken.
+    fn needs_metadata(&self) -> bool {
+        #[cfg(feature = "catalog")]
+        if self.catalog.is_some() {
+            return true;
+        }
+        self.xmp_sidecar
+            || self.json_sidecar
+            || self.json
+            || self.times_from_exif
+            || !self.mirrors_input()
+    }
+
+    /// Whether the output directory is laid out just like the input one, in which case the
+    /// directories are all made before anything is written. Otherwise each preview's is made when
+    /// it's written.
+    fn mirrors_input(&self) -> bool {
+        self.output.is_none()
+            && self.name_template.is_none()
+            && matches!(self.layout, naming::Layout::Mirror)
+            && !self.burst_dirs
+    }
+
+    /// Whether the RAW at `relative_path` under an input directory is taken, going by --match and
+    /// --no-match.
+    fn is_selected(&self, relative_path: &Path) -> bool {
+        (self.matches.is_empty()
+            || self
+                .matches
+                .iter()
+                .any(|regex| regex.matches(relative_path)))
+            && !self
+                .no_match
+                .iter()
+                .any(|regex| regex.matches(relative_path))
+    }
+
+    /// Work out the inputs and the output directory from the paths and --output, leaving --output
+    /// only if it's the file to write a single preview to.
+    fn split_paths(&mut self) -> Result<()> {
+        let mut paths = self.paths.clone();
+        let from_stdin = paths.iter().any(|path| path == Path::new(STDIO));
+        if from_stdin {
+            ensure!(
+                paths.len() == 1 && self.files_from.is_none(),
+                "A RAW from stdin has to be the only input"
+            );
+        }
+        match self.output.take() {
+            Some(output)
+                if !from_stdin
+                    && (output.is_dir() || output.as_os_str().as_bytes().ends_with(b"/")) =>
+            {
+                self.output_dir = output;
+            }
+            Some(output) => {
+                ensure!(
+                    !self.all_previews && self.name_template.is_none(),
+                    "--output can't be a file with --all-previews, --name-template, or \
+                     --rename-by-date"
+                );
+                let parent = output.parent().filter(|dir| !dir.as_os_str().is_empty());
+                self.output_dir = parent.unwrap_or(Path::new(".")).to_path_buf();
+                self.output = Some(output);
+            }
+            None if paths.len() > 1 || (self.files_from.is_some() && !paths.is_empty()) => {
+                let output_dir = paths.pop().unwrap_or_default();
+                // Forgetting the output directory is easy, and then the last RAW would be taken as
+                // one, so only use what's there already if it really is a directory.
+                ensure!(
+                    output_dir.is_dir() || !output_dir.exists(),
+                    "{} is a file, not an output directory. Use -o to say where the previews go",
+                    output_dir.display()
+                );
+                self.output_dir = output_dir;
+            }
+            None if from_stdin => {
+                self.output = Some(PathBuf::from(STDIO));
+                self.output_dir = PathBuf::from(".");
+            }
+            None => self.output_dir = PathBuf::from("."),
+        }
+        if from_stdin {
+            self.check_unneeded_file("A RAW from stdin")?;
+        }
+        if self.output.as_deref() == Some(Path::new(STDIO)) {
+            self.check_unneeded_file("Writing to stdout")?;
+        }
+        self.inputs = expand_globs(&paths)?;
+        if let Some(files_from) = &self.files_from {
+            self.inputs.extend(read_files_from(files_from, self.null)?);
+        }
+        Ok(())
+    }
+
+    /// Make sure nothing needs the RAW or its preview to be a file, since it's going through
+    /// `what`. Neither can have anything written alongside it either.
+    fn check_unneeded_file(&self, what: &str) -> Result<()> {
+        #[cfg_attr(
+            not(any(feature = "resize", feature = "state-db", feature = "catalog")),
+            allow(unused_mut)
+        )]
+        let mut needs_file = vec![
+            ("--stamp", self.stamp),
+            ("--update", self.update),
+            ("--preserve-times", self.preserve_times),
+            ("--preserve", !self.preserve.is_empty()),
+            ("--burst-gap", self.burst_gap.is_some()),
+            ("--delete-orphans", self.delete_orphans),
+            ("--source-xattrs", self.source_xattrs),
+            ("--xmp-sidecar", self.xmp_sidecar),
+            ("--json-sidecar", self.json_sidecar),
+        ];
+        #[cfg(feature = "resize")]
+        needs_file.push(("--sizes", !self.sizes.is_empty()));
+        #[cfg(feature = "state-db")]
+        needs_file.push(("--state-db", self.state_db.is_some()));
+        #[cfg(feature = "catalog")]
+        needs_file.push(("--catalog", self.catalog.is_some()));
+        match needs_file.iter().find(|&&(_, given)| given) {
+            Some((flag, _)) => bail!("{what} can't be used with {flag}"),
+            None => Ok(()),
+        }
+    }
+
+    /// Where to write the preview of a RAW, with `extension`, unless --output says.
+    fn output_path(&self, names: &Names, extension: &str) -> PathBuf {
+        if let Some(output) = &self.output {
+            return output.clone();
+        }
+        let Names {
+            relative_path,
+            metadata,
+            seq,
+            burst,
+        } = *names;
+        let time = self.capture_time(metadata);
+        let relative_dir = relative_path.parent().unwrap_or(Path::new(""));
+        let dir = self.layout.directory(relative_dir, metadata, time);
+        let stem = relative_path.file_stem().unwrap_or_default();
+        let name = match &self.name_template {
+            Some(template) => {
+                PathBuf::from(template.render(&stem.to_string_lossy(), metadata, time, seq))
+            }
+            None => PathBuf::from(stem),
+        };
+        let mut path = dir.join(name.parent().unwrap_or(Path::new("")));
+        let mut file_name = OsString::from(&self.prefix);
+        file_name.push(name.file_name().unwrap_or_default());
+        match burst {
+            Some(burst) if self.burst_dirs => path.push(burst.directory()),
+            Some(burst) => file_name.push(burst.suffix()),
+            None => {}
+        }
+        file_name.push(&self.suffix);
+        if let Some(source) = relative_path.extension().filter(|_| self.keep_source_ext) {
+            file_name.push(".");
+            file_name.push(source);
+        }
+        file_name.push(".");
+        file_name.push(extension);
+        path.push(file_name);
+        self.output_dir.join(self.sanitized(&path))
+    }
+
+    /// `path`, under the output directory, with --sanitize-names applied if it's given.
+    fn sanitized(&self, path: &Path) -> PathBuf {
+        if self.sanitize_names {
+            naming::sanitize(path)
+        } else {
+            path.to_path_buf()
+        }
+    }
+
+    fn options(&self) -> Options {
+        Options {
+            jpeg_only: self.jpeg_only,
+            strict: self.strict,
+            preview_index: self.preview_index,
+            thumbnail: self.thumbnail,
+            largest_by: self.largest_by,
+        }
+    }
+}
+
+/// Parse a size in bytes, with an optional K or M suffix for KiB or MiB.
+fn parse_size(size: &str) -> Result<u64> {
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&size[..i], 1 << 20),
+        _ => (size, 1),
+    };
+    number
+        .parse::<u64>()?
+        .checked_mul(multiplier)
+        .context("Size is too large")
+}
+
+/// Parse dimensions like 3000x2000.
+fn parse_dimensions(dimensions: &str) -> Result<(u32, u32)> {
+    let (width, height) = dimensions
+        .split_once('x')
+        .context("Dimensions must be like 3000x2000")?;
+    Ok((width.parse()?, height.parse()?))
+}
+
+/// What the previews of a RAW file are named from.
+#[derive(Clone, Copy)]
+struct Names<'a> {
+    /// Where it is under the input directory.
+    relative_path: &'a Path,
+    metadata: &'a Metadata,
+    /// Where it comes in the order they were taken, for {seq}.
+    seq: Option<u64>,
+    /// Where it comes in a burst, if it's in one.
+    burst: Option<naming::Burst>,
+}
+
+/// What was written for a RAW file, for anything which summarises the whole run.
+#[derive(Default)]
+struct Extracted {
+    /// The RAW file it came from.
+    source: PathBuf,
+    /// Where the preview was written, or `None` for --all-previews, or if it was skipped.
+    output_file: Option<PathBuf>,
+    /// Where the smallest of the --sizes copies was written, if there are any.
+    small_file: Option<PathBuf>,
+    /// The line --exif prints for it.
+    summary: Option<String>,
+    /// What --json prints about it.
+    exiftool: Option<exiftool::Entry>,
+    /// Whether it was skipped, since --stamp or --state-db said it had already been done.
+    done_before: bool,
+    /// A thumbnail of the preview, as a JPEG, for contact sheets.
+    #[cfg(feature = "contact-sheet")]
+    thumbnail: Option<Vec<u8>>,
+    #[cfg(feature = "placeholders")]
+    placeholders: placeholder::Placeholders,
+    #[cfg(feature = "perceptual-hash")]
+    perceptual_hashes: perceptual_hash::Hashes,
+    /// What --catalog records about it.
+    #[cfg(feature = "catalog")]
+    catalog: Option<catalog::Entry>,
+}
+
+/// Parse an opacity from 0 to 1.
+#[cfg(feature = "watermark")]
+fn parse_opacity(opacity: &str) -> Result<f32> {
+    let opacity = opacity.parse()?;
+    ensure!(
+        (0.0..=1.0).contains(&opacity),
+        "Opacity must be from 0 to 1"
+    );
+    Ok(opacity)
+}
+
+/// Open the RAW at `path`, or if it's -, read it from stdin into an anonymous file first, since a
+/// pipe can't be mapped.
+async fn open_raw(path: &Path) -> Result<File> {
+    if path != Path::new(STDIO) {
+        return Ok(File::open(path).await?);
+    }
+    // SAFETY: The name is a valid C string, and memfd_create() doesn't touch anything else.
+    let fd = unsafe { libc::memfd_create(c"stdin".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to make a file for stdin");
+    }
+    // SAFETY: The fd was only just created, so nothing else owns it.
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    std::io::copy(&mut std::io::stdin().lock(), &mut file).context("Failed to read stdin")?;
+    Ok(File::from_std(file))
 }
 
 /// Map a RAW file into memory using `mmap()`. The file must be static.
@@ -56,211 +977,1434 @@ fn mmap_raw(file: File) -> Result<Mmap> {
     Ok(raw_buf)
 }
 
-/// An embedded JPEG in a RAW file.
-#[derive(Default, Eq, PartialEq)]
-struct EmbeddedJpegInfo {
-    offset: usize,
-    length: usize,
+/// Tell the kernel that we're about to read all of a range of a memory-mapped buffer.
+fn will_need(raw_buf: &Mmap, offset: u64, length: u64) -> Result<()> {
+    let offset = usize::try_from(offset)?;
+    let length = usize::try_from(length)?;
+    ensure!(
+        offset
+            .checked_add(length)
+            .is_some_and(|end| end <= raw_buf.len()),
+        "Data at offset {offset} exceeds file size"
+    );
+    raw_buf.advise_range(Advice::WillNeed, offset, length)?;
+    Ok(())
+}
+
+/// A preview extracted from a RAW file.
+struct Preview<'a> {
+    format: ImageFormat,
+    data: Cow<'a, [u8]>,
+    /// Where the preview is in the RAW, as offsets and lengths in the order they're joined. This
+    /// is empty if it was decoded by libraw rather than just copied.
+    ranges: Vec<(u64, u64)>,
 }
 
-/// Find the largest embedded JPEG in a memory-mapped RAW buffer.
-///
-/// This function parses the IFDs in the TIFF structure of the RAW file to find the largest JPEG
-/// thumbnail embedded in the file.
-///
-/// We hand roll the IFD parsing because libraries do not fit requirements. For example:
-///
-/// - kamadak-exif: Reads into a big `Vec<u8>`, which is huge for our big RAW.
-/// - quickexif: Cannot iterate over IFDs.
-fn find_largest_embedded_jpeg(raw_buf: &[u8]) -> Result<EmbeddedJpegInfo> {
-    const IFD_ENTRY_SIZE: usize = 12;
-    const TIFF_MAGIC_LE: &[u8] = b"II*\0";
-    const TIFF_MAGIC_BE: &[u8] = b"MM\0*";
-    const JPEG_TAG: u16 = 0x201;
-    const JPEG_LENGTH_TAG: u16 = 0x202;
-
-    let is_le = &raw_buf[0..4] == TIFF_MAGIC_LE;
+fn extract_jpeg<'a>(raw_buf: &'a Mmap, args: &Args) -> Result<Preview<'a>> {
+    let jpeg = rawtojpg::find_largest_embedded_jpeg(raw_buf, &args.options());
+
+    #[cfg(feature = "libraw-fallback")]
+    if jpeg.is_err() {
+        // If libraw can't find anything either, the error from our own parser is more useful.
+        if let Ok(thumbnail) = libraw::extract_thumbnail(raw_buf) {
+            return Ok(Preview {
+                format: ImageFormat::Jpeg,
+                data: Cow::Owned(thumbnail),
+                ranges: Vec::new(),
+            });
+        }
+    }
+
+    let jpeg = match jpeg {
+        Err(err) if args.scan_fallback => {
+            raw_buf.advise(Advice::Sequential)?;
+            rawtojpg::scan_for_largest_jpeg(raw_buf).ok_or(err)?
+        }
+        jpeg => jpeg?,
+    };
+    for (offset, length) in jpeg.ranges() {
+        will_need(raw_buf, offset, length)?;
+    }
+    Ok(Preview {
+        format: jpeg.format(),
+        data: jpeg.data(raw_buf)?,
+        ranges: jpeg.ranges().collect(),
+    })
+}
+
+/// Make sure an extracted preview looks intact, and decodes if --verify-decode says to check.
+#[cfg_attr(not(feature = "verify-decode"), allow(unused_variables))]
+fn check_intact(args: &Args, format: ImageFormat, data: &[u8]) -> Result<()> {
+    format.check_complete(data)?;
+    #[cfg(feature = "verify-decode")]
+    if args.verify_decode && format == ImageFormat::Jpeg {
+        decode::verify(data)?;
+    }
+    Ok(())
+}
+
+/// Make sure an extracted preview meets --min-preview-bytes and --min-dimensions.
+fn check_minimums(args: &Args, format: ImageFormat, data: &[u8]) -> Result<()> {
+    if let Some(min_bytes) = args.min_preview_bytes {
+        ensure!(
+            u64::try_from(data.len())? >= min_bytes,
+            "Preview is only {} bytes, less than the minimum of {min_bytes}",
+            data.len()
+        );
+    }
+    if let Some((min_width, min_height)) = args.min_dimensions {
+        let (width, height) = format
+            .dimensions(data)
+            .context("Preview has no readable dimensions")?;
+        ensure!(
+            width >= min_width && height >= min_height,
+            "Preview is only {width}x{height}, less than the minimum of {min_width}x{min_height}"
+        );
+    }
+    Ok(())
+}
+
+/// Make sure a preview's longest side is at least `percent` of the RAW image's. If either size
+/// can't be read, there's nothing to compare, so that's fine too.
+fn check_resolution(percent: u64, raw_buf: &[u8], format: ImageFormat, data: &[u8]) -> Result<()> {
+    let (Some((raw_width, raw_height)), Some((width, height))) =
+        (rawtojpg::raw_dimensions(raw_buf), format.dimensions(data))
+    else {
+        return Ok(());
+    };
+    let longest = u64::from(width.max(height));
     ensure!(
-        is_le || &raw_buf[0..4] == TIFF_MAGIC_BE,
-        "Not a valid TIFF file"
+        longest.saturating_mul(100) >= raw_width.max(raw_height).saturating_mul(percent),
+        "Preview is only {width}x{height}, less than {percent}% of the RAW image's \
+         {raw_width}x{raw_height}"
     );
+    Ok(())
+}
 
-    let read_u16 = if is_le {
-        LittleEndian::read_u16
-    } else {
-        BigEndian::read_u16
+/// Work out what lossless cropping and rotation a preview needs.
+fn lossless_rewrite(args: &Args, raw_buf: &[u8], format: ImageFormat, data: &[u8]) -> Rewrite {
+    let mut rewrite = Rewrite {
+        progressive: args.progressive,
+        ..Rewrite::default()
     };
+    if args.default_crop {
+        rewrite.crop = format
+            .dimensions(data)
+            .and_then(|(width, height)| rawtojpg::default_crop(raw_buf, width, height));
+    }
+    if args.auto_rotate {
+        rewrite.transform =
+            rawtojpg::orientation(raw_buf, data).and_then(Transform::from_orientation);
+    }
+    rewrite
+}
+
+/// Write `buf` to `output_file`, and then set `attributes` on it, adding it to the --manifest. With
+/// --checksum, a file which already has exactly `buf` in it is left as it is, other than the
+/// attributes, so that its modification time doesn't change. When verifying, `buf` is only checked
+/// against what's there.
+async fn write_file(
+    args: &Args,
+    outputs: &naming::Outputs,
+    output_file: &Path,
+    buf: &[u8],
+    attributes: &Attributes,
+) -> Result<()> {
+    if let Some(verifier) = &args.verifier {
+        return verifier.check(output_file, buf).await;
+    }
+    if output_file == Path::new(STDIO) {
+        std::io::stdout().write_all(buf)?;
+        return Ok(());
+    }
+    if let Some(manifest) = &outputs.manifest {
+        manifest.add(output_file, buf);
+    }
+    if args.checksum && is_unchanged(output_file, buf).await? {
+        if !attributes.is_empty() {
+            attributes.apply(&File::open(output_file).await?.into_std().await)?;
+        }
+        return Ok(());
+    }
+    let mut out_file = File::create(output_file).await?;
+    out_file.write_all(buf).await?;
+    if !attributes.is_empty() {
+        attributes.apply(&out_file.into_std().await)?;
+    }
+    Ok(())
+}
+
+/// Create `dir` and any of its parents which don't exist yet, giving each one which is created the
+/// --chmod and --chown. Nothing is created when verifying.
+async fn create_dir_all(args: &Args, dir: &Path) -> Result<()> {
+    if args.verifier.is_some() {
+        return Ok(());
+    }
+    let attributes = args.output_attributes();
+    let mut created = Vec::new();
+    if !attributes.is_empty() {
+        for ancestor in dir.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+            if fs::try_exists(ancestor).await? {
+                break;
+            }
+            created.push(ancestor);
+        }
+    }
+    fs::create_dir_all(dir).await?;
+    for dir in created.into_iter().rev() {
+        attributes.apply_to_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Whether the file at `path` already has exactly `buf` in it.
+async fn is_unchanged(path: &Path, buf: &[u8]) -> Result<bool> {
+    match fs::metadata(path).await {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+        Ok(metadata) if metadata.len() != buf.len() as u64 => return Ok(false),
+        Ok(_) => {}
+    }
+    Ok(fs::read(path).await? == buf)
+}
+
+/// Work out where to write a preview of the RAW at `entry_path` with `extension`, and claim it,
+/// giving `None` if another RAW already has it and --on-collision says to skip, or if it's already
+/// there and --force wasn't given. Its directory is
+/// made too, unless it's one of those made before anything is written.
+async fn output_file(
+    args: &Args,
+    names: &Names<'_>,
+    outputs: &naming::Outputs,
+    entry_path: &Path,
+    extension: &str,
+) -> Result<Option<PathBuf>> {
+    let path = args.output_path(names, extension);
+    // Nothing else can be written to stdout, so there's nothing to claim it from.
+    if path == Path::new(STDIO) {
+        return Ok(Some(path));
+    }
+    outputs.wait_turn(entry_path).await;
+    let output_file = match outputs.claim(&path, entry_path)? {
+        naming::Claim::Write(output_file) => output_file,
+        naming::Claim::Collision => {
+            eprintln!(
+                "Skipping file {}: {} is the name of the preview of another RAW too",
+                entry_path.display(),
+                path.display()
+            );
+            return Ok(None);
+        }
+        naming::Claim::Exists => return Ok(None),
+    };
+    if !args.mirrors_input() {
+        if let Some(parent) = output_file.parent() {
+            create_dir_all(args, parent).await?;
+        }
+    }
+    Ok(Some(output_file))
+}
+
+/// The extension the preview of a RAW is written with, which is --format's for JPEGs, since
+/// they're converted to it.
+#[cfg_attr(not(feature = "resize"), allow(unused_variables))]
+fn output_extension(args: &Args, format: ImageFormat) -> &'static str {
+    #[cfg(feature = "resize")]
+    if format == ImageFormat::Jpeg {
+        return args.format.extension();
+    }
+    format.extension()
+}
 
-    let read_u32 = if is_le {
-        LittleEndian::read_u32
+/// Process a single RAW file to extract the embedded JPEG, and then write the extracted JPEG to
+/// the output directory.
+async fn process_file(
+    args: &'static Args,
+    entry_path: &Path,
+    relative_path: &Path,
+    seq: Option<u64>,
+    burst: Option<naming::Burst>,
+    outputs: &naming::Outputs,
+) -> Result<Extracted> {
+    let in_file = open_raw(entry_path).await?;
+    let source_metadata = if args.stamp {
+        let metadata = in_file.metadata().await?;
+        if is_stamped(entry_path, &metadata)? {
+            return Ok(Extracted {
+                source: entry_path.to_path_buf(),
+                done_before: true,
+                ..Extracted::default()
+            });
+        }
+        Some(metadata)
+    } else {
+        None
+    };
+    let mut attributes = args.output_attributes();
+    if args.preserve_times || !args.preserve.is_empty() {
+        let metadata = in_file.metadata().await?;
+        let times = attributes.times;
+        attributes = Attributes::from_source(entry_path, &metadata, &args.preserve)?
+            .with_overrides(args.chmod, args.chown);
+        attributes.times = times;
+        if args.preserve_times {
+            attributes.times = Some(
+                FileTimes::new()
+                    .set_accessed(metadata.accessed()?)
+                    .set_modified(metadata.modified()?),
+            );
+        }
+    }
+    let raw_buf = mmap_raw(in_file)?;
+    if args.exif {
+        let metadata = quick_metadata(args, &raw_buf);
+        return Ok(Extracted {
+            source: entry_path.to_path_buf(),
+            summary: Some(summary::line(entry_path, &metadata)),
+            ..Extracted::default()
+        });
+    }
+    if args.all_previews {
+        let metadata = if args.needs_metadata() {
+            quick_metadata(args, &raw_buf)
+        } else {
+            Metadata::default()
+        };
+        if args.times_from_exif {
+            attributes.times = capture_times(args, &metadata).or(attributes.times);
+        }
+        let names = Names {
+            relative_path,
+            metadata: &metadata,
+            seq,
+            burst,
+        };
+        write_all_previews(args, &raw_buf, entry_path, &names, outputs, &attributes).await?;
+        if let Some(metadata) = &source_metadata {
+            raw_buf.advise(Advice::Sequential)?;
+            set_stamp(entry_path, metadata, &checksum::sha256(&raw_buf))?;
+        }
+        return Ok(Extracted::default());
+    }
+    let Preview {
+        format,
+        data: mut jpeg_buf,
+        ranges,
+    } = extract_jpeg(&raw_buf, args)?;
+    let dimensions = format.dimensions(&jpeg_buf);
+    if !args.keep_padding {
+        format.trim_padding(&mut jpeg_buf);
+    }
+    check_intact(args, format, &jpeg_buf).context("No usable preview")?;
+    if let Err(err) = check_minimums(args, format, &jpeg_buf) {
+        if !args.best_effort {
+            return Err(err.context("No usable preview"));
+        }
+        eprintln!("Warning for file {}: {err:#}", entry_path.display());
+    }
+    if let Some(percent) = args.warn_below_resolution {
+        if let Err(err) = check_resolution(percent, &raw_buf, format, &jpeg_buf) {
+            eprintln!("Warning for file {}: {err:#}", entry_path.display());
+        }
+    }
+    // This is read before anything below can strip the preview's Exif.
+    let metadata = if args.needs_metadata() {
+        rawtojpg::metadata(&raw_buf, &jpeg_buf)
+    } else {
+        Metadata::default()
+    };
+    if args.times_from_exif {
+        attributes.times = capture_times(args, &metadata).or(attributes.times);
+    }
+    let exiftool = args.json.then(|| {
+        let orientation = rawtojpg::orientation(&raw_buf, &jpeg_buf);
+        exiftool::Entry::new(entry_path, metadata.clone(), orientation, &ranges)
+    });
+    let names = Names {
+        relative_path,
+        metadata: &metadata,
+        seq,
+        burst,
+    };
+    let extension = output_extension(args, format);
+    let output_file = output_file(args, &names, outputs, entry_path, extension).await?;
+    outputs.end_turn(entry_path);
+    let Some(output_file) = output_file else {
+        return Ok(Extracted {
+            source: entry_path.to_path_buf(),
+            exiftool,
+            ..Extracted::default()
+        });
+    };
+    // This goes first, so that --auto-rotate resets the Orientation it copies.
+    if args.copy_exif {
+        if let Some(exif) = rawtojpg::raw_exif(&raw_buf, !args.strip_gps) {
+            format
+                .set_exif(&mut jpeg_buf, &exif)
+                .context("Failed to copy Exif")?;
+        }
+    }
+    // Hashing means reading the whole RAW, so it's only done once, and only if something needs it.
+    #[cfg(feature = "catalog")]
+    let catalog = args.catalog.is_some();
+    #[cfg(not(feature = "catalog"))]
+    let catalog = false;
+    let source_sha256 = if args.provenance || args.source_xattrs || args.stamp || catalog {
+        raw_buf.advise(Advice::Sequential)?;
+        Some(checksum::sha256(&raw_buf))
     } else {
-        BigEndian::read_u32
+        None
     };
+    if let Some(sha256) = source_sha256.as_deref().filter(|_| args.provenance) {
+        let name = entry_path.file_name().unwrap_or_default().to_string_lossy();
+        let comment = format!("extracted-from: {name} sha256:{sha256}");
+        format
+            .set_user_comment(&mut jpeg_buf, &comment)
+            .context("Failed to record provenance")?;
+    }
+    if args.strip_gps {
+        format.strip_gps(&mut jpeg_buf);
+    }
+    let rewrite = lossless_rewrite(args, &raw_buf, format, &jpeg_buf);
+    if rewrite.crop.is_some() || rewrite.transform.is_some() || args.optimize || args.progressive {
+        ensure!(
+            format == ImageFormat::Jpeg,
+            "Can't losslessly rewrite {} previews",
+            format.extension()
+        );
+        let rewritten =
+            lossless::rewrite(&jpeg_buf, &rewrite).context("Failed to rewrite preview")?;
+        jpeg_buf = Cow::Owned(rewritten);
+    }
+    if args.copy_orientation {
+        if let Some(orientation) = rawtojpg::orientation(&raw_buf, &jpeg_buf) {
+            format.set_orientation(&mut jpeg_buf, orientation);
+        }
+    }
+    if args.embed_icc {
+        if let Some(profile) = rawtojpg::icc_profile(&raw_buf, &jpeg_buf) {
+            format
+                .set_icc_profile(&mut jpeg_buf, &profile)
+                .context("Failed to embed ICC profile")?;
+        }
+    }
+    if args.strip_exif {
+        format.strip_metadata(&mut jpeg_buf)?;
+    }
 
-    let mut next_ifd_offset = read_u32(&raw_buf[4..8]).try_into()?;
-    let mut largest_jpeg = EmbeddedJpegInfo::default();
+    let mut extracted = Extracted {
+        source: entry_path.to_path_buf(),
+        exiftool,
+        ..Extracted::default()
+    };
+    #[cfg(any(
+        feature = "contact-sheet",
+        feature = "placeholders",
+        feature = "perceptual-hash"
+    ))]
+    if let Err(err) = analyse_preview(args, &raw_buf, format, &jpeg_buf, &mut extracted) {
+        eprintln!(
+            "Warning for file {}: Failed to analyse preview: {err:#}",
+            entry_path.display()
+        );
+    }
 
-    while next_ifd_offset != 0 {
-        let cursor = &raw_buf[next_ifd_offset..];
-        let num_entries = read_u16(&cursor[..2]).into();
-        let entries_cursor = &cursor[2..];
+    #[cfg(feature = "resize")]
+    let converted = {
+        let encoding = resize::Encoding {
+            format: args.format,
+            quality: args.quality,
+            from_adobe_rgb: args.to_srgb
+                && rawtojpg::color_space(&raw_buf, &jpeg_buf)
+                    == Some(rawtojpg::ColorSpace::AdobeRgb),
+            #[cfg(feature = "watermark")]
+            watermark: args.watermark_image.as_ref(),
+        };
+        if args.max_dimension.is_some() || !args.sizes.is_empty() || !encoding.keeps_original() {
+            Some(
+                write_converted(
+                    args,
+                    format,
+                    encoding,
+                    &jpeg_buf,
+                    &output_file,
+                    outputs,
+                    &attributes,
+                )
+                .await?,
+            )
+        } else {
+            None
+        }
+    };
+    #[cfg(not(feature = "resize"))]
+    let converted = None;
+    #[cfg(feature = "catalog")]
+    let was_converted = converted.is_some();
+    let (output_file, small_file) = match converted {
+        Some(written) => written,
+        None => {
+            write_file(args, outputs, &output_file, &jpeg_buf, &attributes).await?;
+            (output_file, None)
+        }
+    };
+    let source_xattrs = args.source_xattrs && args.verifier.is_none();
+    if let Some(sha256) = source_sha256.as_deref().filter(|_| source_xattrs) {
+        set_source_xattrs(&output_file, &entry_path.canonicalize()?, sha256)
+            .with_context(|| format!("Failed to set xattrs on {}", output_file.display()))?;
+    }
+    if args.xmp_sidecar || args.json_sidecar {
+        let source = entry_path.canonicalize()?;
+        if args.xmp_sidecar {
+            let xmp = xmp::render(&metadata, &source);
+            write_file(
+                args,
+                outputs,
+                &sidecar_path(&output_file, "xmp"),
+                xmp.as_bytes(),
+                &attributes,
+            )
+            .await?;
+        }
+        if args.json_sidecar {
+            let sidecar = json_sidecar::Sidecar {
+                source: source.to_string_lossy().into_owned(),
+                output: output_file.to_string_lossy().into_owned(),
+                preview: json_sidecar::Preview::new(format, &ranges, dimensions),
+                exif: (&metadata).into(),
+            };
+            write_file(
+                args,
+                outputs,
+                &sidecar_path(&output_file, "json"),
+                &sidecar.render()?,
+                &attributes,
+            )
+            .await?;
+        }
+    }
+    if let Some((metadata, sha256)) = source_metadata.as_ref().zip(source_sha256.as_deref()) {
+        set_stamp(entry_path, metadata, sha256)?;
+    }
+    #[cfg(feature = "catalog")]
+    if let Some(source_sha256) = source_sha256.filter(|_| catalog) {
+        // Converted previews aren't kept once they're written, so they're read back. Only the
+        // dimensions of JPEGs can be read without decoding them.
+        let (written, written_format) = if was_converted {
+            let format = (output_extension(args, format) == ImageFormat::Jpeg.extension())
+                .then_some(ImageFormat::Jpeg);
+            (Cow::Owned(fs::read(&output_file).await?), format)
+        } else {
+            (Cow::Borrowed(&*jpeg_buf), Some(format))
+        };
+        extracted.catalog = Some(catalog::Entry {
+            path: output_file.canonicalize()?,
+            source: entry_path.canonicalize()?,
+            taken_at: args.capture_time(&metadata),
+            make: metadata.make.clone(),
+            model: metadata.model.clone(),
+            lens: metadata.lens.clone(),
+            dimensions: written_format.and_then(|format| format.dimensions(&written)),
+            sha256: checksum::sha256(&written),
+            source_sha256,
+        });
+    }
+    extracted.output_file = Some(output_file);
+    extracted.small_file = small_file;
+    Ok(extracted)
+}
 
-        let mut cur_offset = None;
-        let mut cur_length = None;
+/// Read the metadata of a RAW without extracting its preview. Only the Exif is read from that, so
+/// there's no need for the rest of it to be read in, or for libraw.
+fn quick_metadata(args: &Args, raw_buf: &Mmap) -> rawtojpg::Metadata {
+    let preview = rawtojpg::find_largest_embedded_jpeg(raw_buf, &args.options()).ok();
+    let preview = preview
+        .and_then(|jpeg| jpeg.data(raw_buf).ok())
+        .unwrap_or_default();
+    rawtojpg::metadata(raw_buf, &preview)
+}
 
-        for entry in entries_cursor
-            .chunks_exact(IFD_ENTRY_SIZE)
-            .take(num_entries)
-        {
-            let tag = read_u16(&entry[..2]);
+/// The times to give everything written for a RAW for --times-from-exif, if it says when it was
+/// taken.
+fn capture_times(args: &Args, metadata: &Metadata) -> Option<FileTimes> {
+    let time = SystemTime::from(args.capture_time(metadata)?);
+    Some(FileTimes::new().set_accessed(time).set_modified(time))
+}
 
-            match tag {
-                JPEG_TAG => cur_offset = Some(read_u32(&entry[8..12]).try_into()?),
-                JPEG_LENGTH_TAG => cur_length = Some(read_u32(&entry[8..12]).try_into()?),
-                _ => {}
-            }
+/// The times --deterministic gives to everything which isn't for a particular RAW.
+fn epoch_times() -> FileTimes {
+    FileTimes::new()
+        .set_accessed(UNIX_EPOCH)
+        .set_modified(UNIX_EPOCH)
+}
 
-            if let (Some(offset), Some(length)) = (cur_offset, cur_length) {
-                if length > largest_jpeg.length {
-                    largest_jpeg = EmbeddedJpegInfo { offset, length };
-                }
+/// Give the directories under `out_dir` which `written` are in, and `out_dir` itself, the
+/// --deterministic times. This has to be done last, since writing anything in a directory changes
+/// its modification time.
+fn reset_dir_times(out_dir: &Path, written: impl Iterator<Item = PathBuf>) -> Result<()> {
+    let mut dirs = HashSet::new();
+    for path in written {
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(out_dir) || !dirs.insert(dir.to_path_buf()) {
                 break;
             }
         }
+    }
+    for dir in dirs {
+        std::fs::File::open(&dir)
+            .and_then(|dir| dir.set_times(epoch_times()))
+            .with_context(|| format!("Failed to set times on {}", dir.display()))?;
+    }
+    Ok(())
+}
 
-        next_ifd_offset = read_u32(&cursor[2 + num_entries * IFD_ENTRY_SIZE..][..4]).try_into()?;
+/// Record the RAW a preview came from in extended attributes on it. Filesystems which don't
+/// support them are skipped, since the point is to avoid having files anywhere else.
+fn set_source_xattrs(output_file: &Path, source: &Path, sha256: &str) -> Result<()> {
+    let xattrs = [
+        (SOURCE_XATTR, source.as_os_str().as_bytes()),
+        (SOURCE_SHA256_XATTR, sha256.as_bytes()),
+    ];
+    for (name, value) in xattrs {
+        match xattr::set(output_file, name, value) {
+            Err(err) if err.kind() == std::io::ErrorKind::Unsupported => return Ok(()),
+            result => result?,
+        }
     }
+    Ok(())
+}
 
-    ensure!(
-        largest_jpeg != EmbeddedJpegInfo::default(),
-        "No JPEG data found"
-    );
-    ensure!(
-        largest_jpeg.offset + largest_jpeg.length <= raw_buf.len(),
-        "JPEG data exceeds file size"
-    );
+/// The start of what --stamp records on a RAW with `metadata`, which is everything but its hash.
+/// That's all that's checked on later runs, so that they don't have to read the whole RAW again.
+fn stamp_prefix(metadata: &std::fs::Metadata) -> Result<String> {
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok(format!(
+        "rawtojpg {} size={} mtime={}.{:09} ",
+        env!("CARGO_PKG_VERSION"),
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    ))
+}
 
-    Ok(largest_jpeg)
+/// Whether the RAW at `path`, with `metadata`, has a --stamp saying it's already been done as it
+/// is now.
+fn is_stamped(path: &Path, metadata: &std::fs::Metadata) -> Result<bool> {
+    let stamp = match xattr::get(path, STAMP_XATTR) {
+        Err(err) if err.kind() == std::io::ErrorKind::Unsupported => return Ok(false),
+        stamp => stamp?,
+    };
+    let prefix = stamp_prefix(metadata)?;
+    Ok(stamp.is_some_and(|stamp| stamp.starts_with(prefix.as_bytes())))
 }
 
-fn extract_jpeg(raw_buf: &Mmap) -> Result<&[u8]> {
-    let jpeg = find_largest_embedded_jpeg(raw_buf)?;
-    raw_buf.advise_range(Advice::WillNeed, jpeg.offset, jpeg.length)?;
-    Ok(&raw_buf[jpeg.offset..jpeg.offset + jpeg.length])
+/// Stamp the RAW at `path`, with `metadata` and `sha256`, as done for --stamp. RAWs which can't
+/// have extended attributes set on them are skipped, since they'll just be done again next time.
+fn set_stamp(path: &Path, metadata: &std::fs::Metadata, sha256: &str) -> Result<()> {
+    let stamp = format!("{}sha256={sha256}", stamp_prefix(metadata)?);
+    match xattr::set(path, STAMP_XATTR, stamp.as_bytes()) {
+        Err(err)
+            if matches!(
+                err.kind(),
+                std::io::ErrorKind::Unsupported
+                    | std::io::ErrorKind::PermissionDenied
+                    | std::io::ErrorKind::ReadOnlyFilesystem
+            ) =>
+        {
+            Ok(())
+        }
+        result => Ok(result?),
+    }
 }
 
-async fn write_file(output_file: &Path, buf: &[u8]) -> Result<()> {
-    let mut out_file = File::create(output_file).await?;
-    out_file.write_all(buf).await?;
-    Ok(())
+/// Where to write a sidecar of `output_file`, which has `extension` added after its own, like
+/// IMG_0001.jpg.xmp.
+fn sidecar_path(output_file: &Path, extension: &str) -> PathBuf {
+    let mut path = output_file.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
 }
 
-/// Process a single RAW file to extract the embedded JPEG, and then write the extracted JPEG to
-/// the output directory.
-async fn process_file(entry_path: &Path, out_dir: &Path, relative_path: &Path) -> Result<()> {
-    let in_file = File::open(entry_path).await?;
-    let raw_buf = mmap_raw(in_file)?;
-    let jpeg_buf = extract_jpeg(&raw_buf)?;
-    let mut output_file = out_dir.join(relative_path);
-    output_file.set_extension("jpg");
-    write_file(&output_file, jpeg_buf).await?;
+/// Work out everything about a preview which needs it decoded, for contact sheets and the report.
+/// It's only decoded once, however many of them are wanted.
+#[cfg(any(
+    feature = "contact-sheet",
+    feature = "placeholders",
+    feature = "perceptual-hash"
+))]
+fn analyse_preview(
+    args: &Args,
+    raw_buf: &[u8],
+    format: ImageFormat,
+    data: &[u8],
+    extracted: &mut Extracted,
+) -> Result<()> {
+    let mut wanted = false;
+    #[cfg(feature = "contact-sheet")]
+    {
+        wanted |= args.contact_sheet;
+    }
+    #[cfg(feature = "placeholders")]
+    {
+        wanted |= !args.placeholders.is_empty();
+    }
+    #[cfg(feature = "perceptual-hash")]
+    {
+        wanted |= !args.perceptual_hashes.is_empty();
+    }
+    if !wanted || format != ImageFormat::Jpeg {
+        return Ok(());
+    }
+
+    // Once previews have been rotated, the RAW's Orientation no longer applies to them.
+    let orientation = (!args.auto_rotate)
+        .then(|| rawtojpg::orientation(raw_buf, data))
+        .flatten();
+    let image = resize::decode_upright(data, orientation)?;
+
+    #[cfg(feature = "contact-sheet")]
+    if args.contact_sheet {
+        let thumbnail = contact_sheet::thumbnail(&image, args.contact_sheet_cell, args.quality)?;
+        extracted.thumbnail = Some(thumbnail);
+    }
+    #[cfg(feature = "placeholders")]
+    if !args.placeholders.is_empty() {
+        extracted.placeholders = placeholder::compute(&image, &args.placeholders)?;
+    }
+    #[cfg(feature = "perceptual-hash")]
+    if !args.perceptual_hashes.is_empty() {
+        extracted.perceptual_hashes = perceptual_hash::compute(&image, &args.perceptual_hashes);
+    }
     Ok(())
 }
 
-/// Recursively process a directory of RAW files, extracting embedded JPEGs and writing them to the
-/// output directory.
-///
-/// This function recursively searches the input directory for RAW files with valid extensions,
-/// processes each file to extract the embedded JPEG, and writes the JPEGs to the corresponding
-/// location in the output directory. The directory structure relative to the input directory is
-/// maintained.
-async fn process_directory(
-    in_dir: &Path,
-    out_dir: &'static Path,
-    ext: Option<OsString>,
-    transfers: usize,
+/// Write a preview which has to be decoded first, either to scale it down, convert it to another
+/// format or colour space, or make smaller copies of it. Returns where the preview itself was
+/// written, since its extension depends on the format, and where its smallest copy was.
+#[cfg(feature = "resize")]
+async fn write_converted(
+    args: &Args,
+    format: ImageFormat,
+    encoding: resize::Encoding,
+    data: &[u8],
+    output_file: &Path,
+    outputs: &naming::Outputs,
+    attributes: &Attributes,
+) -> Result<(PathBuf, Option<PathBuf>)> {
+    ensure!(
+        format == ImageFormat::Jpeg,
+        "Can't scale down or convert {} previews",
+        format.extension()
+    );
+    // Encoding drops all of the metadata, so put back any Exif which was added, unless its colour
+    // space is no longer right.
+    let exif = ((args.copy_exif || args.provenance)
+        && args.format == OutputFormat::Jpg
+        && !encoding.from_adobe_rgb)
+        .then(|| format.exif(data))
+        .flatten();
+    let with_exif = |image: Vec<u8>| -> Result<Vec<u8>> {
+        let mut image = Cow::Owned(image);
+        if let Some(exif) = exif {
+            format
+                .set_exif(&mut image, exif)
+                .context("Failed to copy Exif")?;
+        }
+        Ok(image.into_owned())
+    };
+
+    let output_file = output_file.with_extension(args.format.extension());
+    let converted = resize::convert(data, args.max_dimension, encoding)?
+        .map(with_exif)
+        .transpose()?;
+    write_file(
+        args,
+        outputs,
+        &output_file,
+        converted.as_deref().unwrap_or(data),
+        attributes,
+    )
+    .await?;
+
+    let mut small_file: Option<(u32, PathBuf)> = None;
+    for (size, copy) in resize::renditions(data, &args.sizes, encoding)? {
+        let copy = match copy {
+            Cow::Owned(copy) => Cow::Owned(with_exif(copy)?),
+            borrowed => borrowed,
+        };
+        let extension = format!("{size}.{}", args.format.extension());
+        let copy_file = output_file.with_extension(extension);
+        write_file(args, outputs, &copy_file, &copy, attributes).await?;
+        if small_file
+            .as_ref()
+            .is_none_or(|(smallest, _)| size < *smallest)
+        {
+            small_file = Some((size, copy_file));
+        }
+    }
+    Ok((output_file, small_file.map(|(_, path)| path)))
+}
+
+/// Write every embedded image in a RAW file to the output directory, numbered in the order they
+/// were found. Images which don't look intact are still written, since the point is to see
+/// everything, but with a warning.
+async fn write_all_previews(
+    args: &Args,
+    raw_buf: &Mmap,
+    entry_path: &Path,
+    names: &Names<'_>,
+    outputs: &naming::Outputs,
+    attributes: &Attributes,
 ) -> Result<()> {
-    let valid_extensions = [
-        "arw", "cr2", "crw", "dng", "erf", "kdc", "mef", "mrw", "nef", "nrw", "orf", "pef", "raf",
-        "raw", "rw2", "rwl", "sr2", "srf", "srw", "x3f",
-    ]
-    .iter()
-    .flat_map(|&ext| [OsString::from(ext), OsString::from(ext.to_uppercase())])
-    .chain(ext.into_iter())
-    .collect::<HashSet<_>>();
+    let jpegs = rawtojpg::find_embedded_jpegs(raw_buf, &args.options())?;
+    ensure!(!jpegs.is_empty(), "No JPEG data found");
 
-    let mut entries = Vec::new();
-    let mut dir_queue = vec![in_dir.to_path_buf()];
+    // These are all claimed before any are written, so that with --deterministic the next RAW
+    // doesn't have to wait for them to be written before it can claim its own.
+    let mut output_files = Vec::new();
+    for (index, jpeg) in jpegs.iter().enumerate() {
+        let extension = format!("preview{index}.{}", jpeg.format().extension());
+        output_files.push(output_file(args, names, outputs, entry_path, &extension).await?);
+    }
+    outputs.end_turn(entry_path);
 
-    while let Some(current_dir) = dir_queue.pop() {
+    for (index, (jpeg, output_file)) in jpegs.iter().zip(output_files).enumerate() {
+        let Some(output_file) = output_file else {
+            continue;
+        };
+        for (offset, length) in jpeg.ranges() {
+            will_need(raw_buf, offset, length)?;
+        }
+        let mut data = jpeg.data(raw_buf)?;
+        if !args.keep_padding {
+            jpeg.format().trim_padding(&mut data);
+        }
+        if args.strip_gps {
+            jpeg.format().strip_gps(&mut data);
+        }
+        if args.strip_exif {
+            if let Err(err) = jpeg.format().strip_metadata(&mut data) {
+                eprintln!(
+                    "Warning for file {}, preview {index}: {err:#}",
+                    entry_path.display()
+                );
+                continue;
+            }
+        }
+        if let Err(err) = jpeg.format().check_complete(&data) {
+            eprintln!(
+                "Warning for file {}, preview {index}: {err:#}",
+                entry_path.display()
+            );
+        }
+        write_file(args, outputs, &output_file, &data, attributes).await?;
+    }
+    Ok(())
+}
+
+/// When a RAW was taken, and with what camera, for anything which needs to know what order they
+/// were all taken in before any of them can be named.
+struct Capture<'a> {
+    path: &'a PathBuf,
+    time: Option<DateTime<FixedOffset>>,
+    camera: (Option<String>, Option<String>),
+}
+
+/// Read when each of the RAWs in `entries` was taken, and sort them into the order they were
+/// taken, and by path for any taken at the same time. Those which don't say when they were taken
+/// come last.
+async fn capture_order<'a>(args: &Args, entries: &'a [PathBuf]) -> Result<Vec<Capture<'a>>> {
+    let mut captures = Vec::with_capacity(entries.len());
+    for path in entries {
+        let in_file = File::open(path)
+            .await
+            .with_context(|| format!("Error reading file {}", path.display()))?;
+        let metadata = quick_metadata(args, &mmap_raw(in_file)?);
+        captures.push(Capture {
+            path,
+            time: args.capture_time(&metadata),
+            camera: (metadata.make, metadata.model),
+        });
+    }
+    captures.sort_by_key(|capture| (capture.time.is_none(), capture.time, capture.path));
+    Ok(captures)
+}
+
+/// Process a RAW file like process_file, unless --state-db says it's been done already, and then
+/// record how it went.
+#[cfg(feature = "state-db")]
+async fn process_file_with_state(
+    args: &'static Args,
+    state: Option<&state::State>,
+    entry_path: &Path,
+    relative_path: &Path,
+    seq: Option<u64>,
+    burst: Option<naming::Burst>,
+    outputs: &naming::Outputs,
+) -> Result<Extracted> {
+    let Some(state) = state else {
+        return process_file(args, entry_path, relative_path, seq, burst, outputs).await;
+    };
+    let metadata = fs::metadata(entry_path).await?;
+    if state.is_done(entry_path, &metadata)? {
+        return Ok(Extracted {
+            source: entry_path.to_path_buf(),
+            done_before: true,
+            ..Extracted::default()
+        });
+    }
+    let result = process_file(args, entry_path, relative_path, seq, burst, outputs).await;
+    state.record(entry_path, &metadata, result.as_ref().err())?;
+    result
+}
+
+/// Number the RAWs for {seq}, in the order they were taken.
+fn sequence_numbers(args: &Args, order: &[Capture]) -> HashMap<PathBuf, u64> {
+    (args.seq_start..)
+        .zip(order)
+        .map(|(seq, capture)| (capture.path.clone(), seq))
+        .collect()
+}
+
+/// Find the bursts for --burst-gap: runs of two or more pictures from the same camera, each taken
+/// within `gap` of the last. They're numbered in the order they were started.
+fn bursts(gap: TimeDelta, order: &[Capture]) -> HashMap<PathBuf, naming::Burst> {
+    let mut groups: Vec<Vec<&PathBuf>> = Vec::new();
+    // The last picture from each camera, and the group it went in.
+    let mut last: HashMap<_, (DateTime<FixedOffset>, usize)> = HashMap::new();
+    for capture in order {
+        let Some(time) = capture.time else {
+            continue;
+        };
+        match last.get_mut(&capture.camera) {
+            Some((last_time, group)) if time - *last_time <= gap => {
+                groups[*group].push(capture.path);
+                *last_time = time;
+            }
+            _ => {
+                last.insert(&capture.camera, (time, groups.len()));
+                groups.push(vec![capture.path]);
+            }
+        }
+    }
+    let bursts = groups.into_iter().filter(|group| group.len() > 1);
+    (1..)
+        .zip(bursts)
+        .flat_map(|(number, group)| {
+            let len = group.len();
+            group.into_iter().enumerate().map(move |(index, path)| {
+                let burst = naming::Burst {
+                    number,
+                    index: index + 1,
+                    len,
+                };
+                (path.clone(), burst)
+            })
+        })
+        .collect()
+}
+
+/// The RAWs to process, with their paths relative to the input they were found in, which is what
+/// their previews are named from.
+#[derive(Default)]
+struct Raws {
+    paths: Vec<PathBuf>,
+    relative_paths: HashMap<PathBuf, PathBuf>,
+}
+
+impl Raws {
+    /// Add the RAW at `path`, unless it's already been found through another input.
+    fn add(&mut self, path: PathBuf, relative_path: PathBuf) {
+        if !self.relative_paths.contains_key(&path) {
+            self.relative_paths.insert(path.clone(), relative_path);
+            self.paths.push(path);
+        }
+    }
+}
+
+/// Find the RAWs in `input`, which is either a directory to search recursively, down to
+/// --max-depth, for files with `extensions`, or a RAW itself, which is taken as it is, whatever its
+/// extension, and named as if it were in a directory on its own. If the output mirrors the input,
+/// the output directories are made for the directories RAWs are found in. Gives whether `input` was
+/// a directory.
+async fn find_raws(
+    args: &Args,
+    input: &Path,
+    extensions: &HashSet<OsString>,
+    raws: &mut Raws,
+) -> Result<bool> {
+    if input == Path::new(STDIO) {
+        raws.add(input.to_path_buf(), input.to_path_buf());
+        return Ok(false);
+    }
+    let metadata = fs::metadata(input)
+        .await
+        .with_context(|| format!("Failed to read {}", input.display()))?;
+    if !metadata.is_dir() {
+        let file_name = input.file_name().unwrap_or_default();
+        raws.add(input.to_path_buf(), PathBuf::from(file_name));
+        return Ok(false);
+    }
+
+    // The device and inode of each directory searched, with --follow-symlinks, so that none are
+    // searched twice. The device is also what --one-file-system goes by.
+    let mut searched_dirs = HashSet::from([(metadata.dev(), metadata.ino())]);
+    let mut dir_queue = vec![(input.to_path_buf(), 0)];
+    while let Some((current_dir, depth)) = dir_queue.pop() {
         let mut read_dir = fs::read_dir(&current_dir).await?;
         let mut found_raw = false;
 
         while let Some(entry) = read_dir.next_entry().await? {
             let path = entry.path();
-            if entry.file_type().await?.is_dir() {
-                dir_queue.push(path);
-            } else if path
-                .extension()
-                .map_or(false, |ext| valid_extensions.contains(ext))
+            let relative_path = path.strip_prefix(input)?.to_path_buf();
+            let file_type = entry.file_type().await?;
+            let mut is_dir = file_type.is_dir();
+            if (args.follow_symlinks || args.one_file_system)
+                && (is_dir || (args.follow_symlinks && file_type.is_symlink()))
+            {
+                // Broken symlinks are left to fail when they're read, as they do otherwise.
+                if let Ok(target) = fs::metadata(&path).await {
+                    is_dir = target.is_dir();
+                    if is_dir && args.one_file_system && target.dev() != metadata.dev() {
+                        continue;
+                    }
+                    if is_dir
+                        && args.follow_symlinks
+                        && !searched_dirs.insert((target.dev(), target.ino()))
+                    {
+                        eprintln!(
+                            "Skipping {}, which is a directory already being searched",
+                            path.display()
+                        );
+                        continue;
+                    }
+                }
+            }
+            if args
+                .exclude
+                .iter()
+                .any(|pattern| pattern.matches(&relative_path, is_dir))
+            {
+                continue;
+            }
+            if is_dir {
+                if args.max_depth.is_none_or(|max_depth| depth < max_depth) {
+                    dir_queue.push((path, depth + 1));
+                }
+            } else if path.extension().is_some_and(|ext| extensions.contains(ext))
+                && args.is_selected(&relative_path)
             {
                 found_raw = true;
-                entries.push(path);
+                raws.add(path, relative_path);
             }
         }
 
-        if found_raw {
-            let relative_dir = current_dir.strip_prefix(in_dir)?;
-            let output_subdir = out_dir.join(relative_dir);
-            fs::create_dir_all(&output_subdir).await?;
+        if found_raw && !args.exif && args.mirrors_input() {
+            let relative_dir = current_dir.strip_prefix(input)?;
+            let output_subdir = args.output_dir.join(args.sanitized(relative_dir));
+            create_dir_all(args, &output_subdir).await?;
         }
     }
+    Ok(true)
+}
+
+/// Expand any of `inputs` which don't exist but look like globs, like misc/IMG_*.ARW, for when
+/// they were quoted, or come from somewhere without a shell.
+fn expand_globs(inputs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut expanded = Vec::new();
+    for input in inputs {
+        let pattern = input
+            .to_str()
+            .filter(|pattern| pattern.contains(['*', '?', '[']) && !input.exists());
+        let Some(pattern) = pattern else {
+            expanded.push(input.clone());
+            continue;
+        };
+        let matches = glob::glob(pattern)
+            .with_context(|| format!("Invalid glob {pattern}"))?
+            .collect::<Result<Vec<_>, _>>()?;
+        ensure!(!matches.is_empty(), "Nothing matches {pattern}");
+        expanded.extend(matches);
+    }
+    Ok(expanded)
+}
+
+/// Read the inputs listed in `path` for --files-from, or on stdin if it's -, separated by NULs if
+/// `null`, or by newlines otherwise. Empty lines are skipped.
+fn read_files_from(path: &Path, null: bool) -> Result<Vec<PathBuf>> {
+    let data = if path == Path::new("-") {
+        let mut data = Vec::new();
+        std::io::stdin().read_to_end(&mut data)?;
+        data
+    } else {
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?
+    };
+    let separator = if null { b'\0' } else { b'\n' };
+    Ok(data
+        .split(|&byte| byte == separator)
+        .filter(|input| !input.is_empty())
+        .map(|input| PathBuf::from(OsStr::from_bytes(input)))
+        .collect())
+}
 
-    let progress_bar = ProgressBar::new(entries.len().try_into()?);
+/// Recursively process directories of RAW files, extracting embedded JPEGs and writing them to
+/// the output directory.
+///
+/// This function recursively searches each input directory for RAW files with valid extensions,
+/// processes each file to extract the embedded JPEG, and writes the JPEGs to the corresponding
+/// location in the output directory. The directory structure relative to each input directory is
+/// maintained.
+async fn process_directory(args: &'static Args) -> Result<()> {
+    let out_dir = &args.output_dir;
+    if args.delete_orphans {
+        let out_dir = out_dir.canonicalize()?;
+        for input in &args.inputs {
+            ensure!(
+                !input.canonicalize()?.starts_with(&out_dir),
+                "--delete-orphans can't be used with an input directory inside the output \
+                 directory"
+            );
+        }
+    }
+    let valid_extensions = [
+        "arw", "cr2", "cr3", "crw", "dng", "erf", "heic", "heif", "hif", "iiq", "kdc", "mef",
+        "mrw", "nef", "nrw", "orf", "pef", "raf", "raw", "rw2", "rwl", "sr2", "srf", "srw", "tif",
+        "tiff", "x3f",
+    ]
+    .iter()
+    .flat_map(|&ext| [OsString::from(ext), OsString::from(ext.to_uppercase())])
+    .chain(args.extension.clone())
+    .filter(|ext| {
+        !args
+            .exclude_ext
+            .iter()
+            .any(|excluded| excluded.eq_ignore_ascii_case(ext))
+    })
+    .collect::<HashSet<_>>();
+
+    let mut raws = Raws::default();
+    let mut all_dirs = true;
+    for input in &args.inputs {
+        all_dirs &= find_raws(args, input, &valid_extensions, &mut raws).await?;
+    }
+    ensure!(
+        args.output.is_none() || (args.inputs.len() == 1 && !all_dirs),
+        "--output can only be a file with a single RAW file, so end it with a slash if it's \
+         meant to be a directory which doesn't exist yet"
+    );
+    // Otherwise every other preview in the output directory would look like an orphan.
+    ensure!(
+        !args.delete_orphans || all_dirs,
+        "--delete-orphans can only be used with input directories"
+    );
+    let Raws {
+        paths: mut entries,
+        mut relative_paths,
+    } = raws;
+
+    // The progress bar would get in the way of the lines --exif prints.
+    let progress_bar = if args.exif {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(entries.len().try_into()?)
+    };
     progress_bar.set_style(
         ProgressStyle::default_bar()
             .template("{pos}/{len} [{bar}] (ETA: {eta})")?
             .progress_chars("##-"),
     );
 
-    let semaphore = Arc::new(Semaphore::new(transfers));
+    if args.deterministic {
+        entries.sort();
+    }
+
+    let semaphore = Arc::new(Semaphore::new(args.transfers));
     let mut tasks = Vec::new();
 
+    let uses_seq = args
+        .name_template
+        .as_ref()
+        .is_some_and(naming::Template::uses_seq);
+    let (sequence, bursts) = if uses_seq || args.burst_gap.is_some() {
+        let order = capture_order(args, &entries).await?;
+        let bursts = args.burst_gap.map(|gap| {
+            let gap = TimeDelta::milliseconds(gap.try_into().unwrap_or(i64::MAX));
+            bursts(gap, &order)
+        });
+        (sequence_numbers(args, &order), bursts.unwrap_or_default())
+    } else {
+        (HashMap::new(), HashMap::new())
+    };
+    let existing = if args.verifier.is_some() {
+        naming::Existing::Overwrite
+    } else if args.update {
+        naming::Existing::Update
+    } else if (args.force || args.checksum) && !args.no_clobber {
+        naming::Existing::Overwrite
+    } else {
+        naming::Existing::Keep
+    };
+    let manifest = args
+        .manifest
+        .as_ref()
+        .filter(|_| args.verifier.is_none())
+        .map(|_| manifest::Manifest::new(args.manifest_algorithm));
+    let turns = args.deterministic.then(|| naming::Turns::new(&entries));
+    let outputs = Arc::new(naming::Outputs::new(
+        args.on_collision,
+        existing,
+        manifest,
+        turns,
+    ));
+    #[cfg(feature = "state-db")]
+    let state = args
+        .state_db
+        .as_deref()
+        .map(state::State::open)
+        .transpose()?
+        .map(Arc::new);
     for in_path in entries {
+        let seq = sequence.get(&in_path).copied();
+        let burst = bursts.get(&in_path).copied();
+        let outputs = outputs.clone();
         let semaphore = semaphore.clone();
-        let relative_path = in_path.strip_prefix(in_dir)?.to_path_buf();
+        let relative_path = relative_paths.remove(&in_path).unwrap_or_default();
         let progress_bar = progress_bar.clone();
+        #[cfg(feature = "state-db")]
+        let state = state.clone();
+        // Permits are handed out in order, so that with --deterministic, a RAW never waits for its
+        // turn while one before it is waiting for a permit.
+        let permit = semaphore.acquire_owned().await?;
         let task = tokio::spawn(async move {
-            let permit = semaphore.acquire_owned().await?;
-            let result = process_file(&in_path, out_dir, &relative_path).await;
+            #[cfg(feature = "state-db")]
+            let result = process_file_with_state(
+                args,
+                state.as_deref(),
+                &in_path,
+                &relative_path,
+                seq,
+                burst,
+                &outputs,
+            )
+            .await;
+            #[cfg(not(feature = "state-db"))]
+            let result = process_file(args, &in_path, &relative_path, seq, burst, &outputs).await;
+            let result =
+                result.with_context(|| format!("Error processing file {}", in_path.display()));
+            outputs.end_turn(&in_path);
             drop(permit);
             progress_bar.inc(1);
             if let Err(e) = &result {
-                eprintln!("Error processing file {}: {:?}", in_path.display(), e);
+                eprintln!("{e:#}");
             }
             result
         });
         tasks.push(task);
     }
 
+    let mut extracted = Vec::new();
     for task in tasks {
-        task.await??;
+        let task = task.await??;
+        if let Some(summary) = &task.summary {
+            println!("{summary}");
+        }
+        extracted.push(task);
     }
 
     progress_bar.finish();
+    if args.delete_orphans {
+        let keep: Vec<_> = [&args.report, &args.manifest]
+            .into_iter()
+            .filter_map(Option::as_deref)
+            .collect();
+        let deleted = orphans::delete(out_dir, &outputs.stems(), &keep).await?;
+        if deleted > 0 {
+            eprintln!("Deleted {deleted} files which weren't written for any of the RAWs");
+        }
+    }
+    match extracted
+        .iter()
+        .filter(|extracted| extracted.done_before)
+        .count()
+    {
+        0 => {}
+        1 => eprintln!("Skipped 1 RAW which had been done already"),
+        done => eprintln!("Skipped {done} RAWs which had been done already"),
+    }
+    match (outputs.kept(), existing) {
+        (0, _) => {}
+        (1, naming::Existing::Update) => eprintln!("Skipped 1 preview which was up to date"),
+        (kept, naming::Existing::Update) => {
+            eprintln!("Skipped {kept} previews which were up to date")
+        }
+        (1, _) => {
+            eprintln!("Skipped 1 preview which was already there; use --force to overwrite it")
+        }
+        (kept, _) => eprintln!(
+            "Skipped {kept} previews which were already there; use --force to overwrite them"
+        ),
+    }
+
+    if let Some(report_file) = &args.report {
+        let entries = extracted.iter().filter_map(report::Entry::new).collect();
+        write_file(
+            args,
+            &outputs,
+            report_file,
+            &report::render(entries)?,
+            &args.output_attributes(),
+        )
+        .await?;
+    }
+
+    #[cfg(feature = "catalog")]
+    if let Some(catalog_file) = &args.catalog {
+        let entries: Vec<_> = extracted
+            .iter()
+            .filter_map(|extracted| extracted.catalog.as_ref())
+            .collect();
+        catalog::write(catalog_file, &entries)?;
+    }
+
+    if args.json {
+        let entries = extracted
+            .iter_mut()
+            .filter_map(|extracted| extracted.exiftool.take())
+            .collect();
+        std::io::stdout().write_all(&exiftool::render(entries)?)?;
+    }
+
+    if args.gallery {
+        let pictures: Vec<_> = extracted
+            .iter()
+            .filter_map(|extracted| {
+                Some(gallery::Picture {
+                    output_file: extracted.output_file.as_deref()?,
+                    small_file: extracted.small_file.as_deref(),
+                })
+            })
+            .collect();
+        for (path, html) in gallery::render(out_dir, &pictures) {
+            write_file(
+                args,
+                &outputs,
+                &path,
+                html.as_bytes(),
+                &args.output_attributes(),
+            )
+            .await?;
+        }
+    }
+
+    #[cfg(feature = "contact-sheet")]
+    if args.contact_sheet {
+        let thumbnails: BTreeMap<_, _> = extracted
+            .iter()
+            .filter_map(|extracted| {
+                Some((
+                    extracted.output_file.as_deref()?,
+                    extracted.thumbnail.as_deref()?,
+                ))
+            })
+            .collect();
+        let layout = contact_sheet::Layout {
+            columns: args.contact_sheet_columns,
+            rows: args.contact_sheet_rows,
+            cell: args.contact_sheet_cell,
+            format: args.contact_sheet_format,
+            quality: args.quality,
+        };
+        for (path, data) in contact_sheet::render(&thumbnails, layout)? {
+            write_file(args, &outputs, &path, &data, &args.output_attributes()).await?;
+        }
+    }
+
+    // This goes last so that it has everything else in it. It's rendered before it's written, so
+    // it doesn't have itself in it.
+    if let (Some(manifest_file), Some(manifest)) = (&args.manifest, &outputs.manifest) {
+        write_file(
+            args,
+            &outputs,
+            manifest_file,
+            &manifest.render(out_dir),
+            &args.output_attributes(),
+        )
+        .await?;
+    }
+
+    if args.deterministic && args.verifier.is_none() {
+        let written = outputs.stems().into_iter().chain(
+            [&args.report, &args.manifest]
+                .into_iter()
+                .flatten()
+                .cloned(),
+        );
+        reset_dir_times(out_dir, written)?;
+    }
 
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
-
+    let cli = Cli::parse();
+    let (args, verifying) = match cli.command {
+        Some(Command::Verify(args)) => (args, true),
+        None => (cli.args.expect("clap requires the arguments"), false),
+    };
     // We would need a copy for each task otherwise, so better just to make it &'static
-    let output_dir = Box::leak(Box::new(args.output_dir));
+    let args = Box::leak(Box::new(args));
+    if args.flatten {
+        args.layout = naming::Layout::Flat;
+    }
+    if args.deterministic {
+        args.times_from_exif = true;
+    }
+    if args.no_recursive {
+        args.max_depth = Some(0);
+    }
+    if args.rename_by_date {
+        args.name_template = Some(naming::parse_template(naming::BY_DATE)?);
+    }
+    args.split_paths()?;
+    if verifying {
+        ensure!(
+            !args.exif && !args.stamp && !args.delete_orphans,
+            "verify can't be used with --exif, --stamp, or --delete-orphans"
+        );
+        #[cfg(feature = "state-db")]
+        ensure!(
+            args.state_db.is_none(),
+            "verify can't be used with --state-db"
+        );
+        #[cfg(feature = "catalog")]
+        ensure!(
+            args.catalog.is_none(),
+            "verify can't be used with --catalog"
+        );
+        args.verifier = Some(verify::Verifier::new(
+            args.manifest.as_deref(),
+            args.manifest_algorithm,
+            &args.output_dir,
+        )?);
+    }
+    #[cfg(feature = "watermark")]
+    if let Some(path) = &args.watermark {
+        args.watermark_image = Some(watermark::Watermark::load(
+            path,
+            args.watermark_position,
+            args.watermark_opacity,
+        )?);
+    }
 
-    fs::create_dir_all(&output_dir).await?;
-    process_directory(&args.input_dir, output_dir, args.extension, args.transfers).await?;
+    if !args.exif {
+        create_dir_all(args, &args.output_dir).await?;
+    }
+    process_directory(args).await?;
+    if let Some(verifier) = &args.verifier {
+        verifier.finish()?;
+    }
 
     Ok(())
 }
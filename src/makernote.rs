@@ -0,0 +1,143 @@
+use crate::source::Source;
+use crate::tiff::Tiff;
+use crate::EmbeddedJpegInfo;
+use anyhow::{Context, Result};
+
+/// Sony MakerNotes usually start with one of these, but ARW files often have no header at all.
+const SONY_HEADERS: &[&[u8]] = &[b"SONY DSC \0\0\0", b"SONY CAM \0\0\0"];
+
+/// Newer Nikon MakerNotes start with this, a version, and then a whole TIFF header of their own.
+const NIKON_HEADER: &[u8] = b"Nikon\0\x02";
+const NIKON_TIFF_OFFSET: u64 = 10;
+
+/// The ways vendors lay out their MakerNotes. Most are a TIFF IFD in one form or another, but the
+/// header in front of it and what its offsets are relative to differ between vendors.
+#[derive(Clone, Copy)]
+pub enum Layout {
+    Sony,
+    Canon,
+    Nikon,
+}
+
+/// Find the embedded JPEGs referenced by `tag` in a MakerNote. Which camera the file is from, and
+/// so which layout and tag to use, comes from the quirks table.
+pub fn find_jpegs<S: Source + ?Sized>(
+    tiff: &Tiff<S>,
+    layout: Layout,
+    tag: u16,
+    offset: u64,
+    candidates: &mut Vec<EmbeddedJpegInfo>,
+) -> Result<()> {
+    // Only the start is needed, to see which header it has.
+    let makernote = tiff
+        .read_up_to(offset, 12)
+        .with_context(|| format!("MakerNote offset {offset} exceeds file size"))?;
+
+    match layout {
+        Layout::Sony => {
+            let header = SONY_HEADERS
+                .iter()
+                .find(|header| makernote.starts_with(header))
+                .map_or(Ok(0), |header| u64::try_from(header.len()))?;
+            find_sony_jpegs(tiff, offset + header, tag, candidates)
+        }
+        Layout::Canon => find_canon_jpegs(tiff, offset, tag, candidates),
+        Layout::Nikon if makernote.starts_with(NIKON_HEADER) => {
+            find_nikon_jpegs(tiff, offset + NIKON_TIFF_OFFSET, tag, candidates)
+        }
+        Layout::Nikon => Ok(()),
+    }
+}
+
+/// Add a candidate, but only if it really is a JPEG. MakerNote tags are much less consistently
+/// used than standard ones, so we check rather than trusting them.
+fn push_if_jpeg<S: Source + ?Sized>(
+    tiff: &Tiff<S>,
+    offset: u64,
+    length: u64,
+    candidates: &mut Vec<EmbeddedJpegInfo>,
+) -> Result<()> {
+    if tiff.starts_with_soi(offset) {
+        candidates.push(EmbeddedJpegInfo::new(offset, length));
+    }
+    Ok(())
+}
+
+/// Sony's PreviewImage is an undefined array containing the whole JPEG, with its offset relative to
+/// the start of the TIFF.
+fn find_sony_jpegs<S: Source + ?Sized>(
+    tiff: &Tiff<S>,
+    ifd_offset: u64,
+    tag: u16,
+    candidates: &mut Vec<EmbeddedJpegInfo>,
+) -> Result<()> {
+    let (entries, _) = tiff.read_ifd(ifd_offset)?;
+    for entry in entries.filter(|entry| entry.tag == tag) {
+        push_if_jpeg(
+            tiff,
+            tiff.entry_data_offset(&entry),
+            entry.count,
+            candidates,
+        )?;
+    }
+    Ok(())
+}
+
+/// Canon's PreviewImageInfo is an array of longs: the size of the array in bytes, the quality, the
+/// length, width and height, and then the offset of the JPEG relative to the start of the TIFF.
+fn find_canon_jpegs<S: Source + ?Sized>(
+    tiff: &Tiff<S>,
+    ifd_offset: u64,
+    tag: u16,
+    candidates: &mut Vec<EmbeddedJpegInfo>,
+) -> Result<()> {
+    let (entries, _) = tiff.read_ifd(ifd_offset)?;
+    for entry in entries.filter(|entry| entry.tag == tag) {
+        if let Some(&[_, _, length, _, _, offset, ..]) = tiff.entry_uints(&entry).as_deref() {
+            push_if_jpeg(tiff, offset, length, candidates)?;
+        }
+    }
+    Ok(())
+}
+
+/// Nikon's MakerNote contains its own TIFF, and all offsets are relative to that instead. The
+/// preview is in a separate IFD pointed to by NikonPreview.
+fn find_nikon_jpegs<S: Source + ?Sized>(
+    outer: &Tiff<S>,
+    tiff_offset: u64,
+    preview_ifd_tag: u16,
+    candidates: &mut Vec<EmbeddedJpegInfo>,
+) -> Result<()> {
+    const JPEG_TAG: u16 = 0x201;
+    const JPEG_LENGTH_TAG: u16 = 0x202;
+
+    let tiff = outer
+        .nested(tiff_offset)
+        .context("Truncated Nikon MakerNote")?;
+    let (entries, _) = tiff.read_ifd(tiff.first_ifd_offset())?;
+    let Some(preview_ifd_offset) = entries
+        .filter(|entry| entry.tag == preview_ifd_tag)
+        .find_map(|entry| tiff.entry_uint(&entry))
+    else {
+        return Ok(());
+    };
+
+    let mut jpeg_offset = None;
+    let mut jpeg_length = None;
+    let (entries, _) = tiff.read_ifd(preview_ifd_offset)?;
+    for entry in entries {
+        match entry.tag {
+            JPEG_TAG => jpeg_offset = tiff.entry_uint(&entry),
+            JPEG_LENGTH_TAG => jpeg_length = tiff.entry_uint(&entry),
+            _ => {}
+        }
+    }
+
+    if let (Some(offset), Some(length)) = (jpeg_offset, jpeg_length) {
+        let offset = offset
+            .checked_add(tiff_offset)
+            .context("Nikon preview offset overflows")?;
+        push_if_jpeg(outer, offset, length, candidates)?;
+    }
+    Ok(())
+}
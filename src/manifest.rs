@@ -0,0 +1,131 @@
+use crate::checksum;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Which hash --manifest uses.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum Algorithm {
+    /// In the format sha256sum reads
+    #[default]
+    Sha256,
+    /// In the format b3sum reads
+    Blake3,
+}
+
+impl Algorithm {
+    pub fn hash(self, data: &[u8]) -> String {
+        match self {
+            Self::Sha256 => checksum::sha256(data),
+            Self::Blake3 => checksum::blake3(data),
+        }
+    }
+}
+
+/// The hashes of everything written in a run, for --manifest.
+pub struct Manifest {
+    algorithm: Algorithm,
+    entries: Mutex<Vec<(PathBuf, String)>>,
+}
+
+impl Manifest {
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self {
+            algorithm,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Record that `data` was written to `path`.
+    pub fn add(&self, path: &Path, data: &[u8]) {
+        let hash = self.algorithm.hash(data);
+        let mut entries = self.entries.lock().expect("lock shouldn't be poisoned");
+        entries.push((path.to_path_buf(), hash));
+    }
+
+    /// Render the manifest, sorted by path, with paths relative to `root` where they're under it,
+    /// so that `sha256sum -c` or `b3sum -c` can check it from there.
+    pub fn render(&self, root: &Path) -> Vec<u8> {
+        let mut entries = self.entries.lock().expect("lock shouldn't be poisoned");
+        entries.sort();
+        let mut out = Vec::new();
+        for (path, hash) in entries.iter() {
+            let path = path.strip_prefix(root).unwrap_or(path).as_os_str();
+            out.extend_from_slice(&line(hash, path));
+        }
+        out
+    }
+}
+
+/// Read a manifest which was written by --manifest, or by sha256sum or b3sum, giving the hash of
+/// each file in it. Relative paths are taken to be under `root`.
+pub fn parse(data: &[u8], root: &Path) -> Result<HashMap<PathBuf, String>> {
+    let mut hashes = HashMap::new();
+    for (number, line) in data.split(|&byte| byte == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let (escaped, line) = match line.strip_prefix(b"\\") {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        // sha256sum puts a star before the path instead of a space for files it read in binary.
+        let split = line
+            .windows(2)
+            .position(|pair| pair == b"  " || pair == b" *");
+        let (hash, path) = split
+            .map(|split| (&line[..split], &line[split + 2..]))
+            .with_context(|| format!("Line {} of the manifest has no path", number + 1))?;
+        let path = if escaped {
+            unescape(path)
+        } else {
+            path.to_vec()
+        };
+        let hash = String::from_utf8_lossy(hash).to_ascii_lowercase();
+        hashes.insert(root.join(OsStr::from_bytes(&path)), hash);
+    }
+    Ok(hashes)
+}
+
+fn unescape(path: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(&byte) = bytes.next() {
+        match (byte, bytes.as_slice().first()) {
+            (b'\\', Some(b'\\')) => {
+                unescaped.push(b'\\');
+                bytes.next();
+            }
+            (b'\\', Some(b'n')) => {
+                unescaped.push(b'\n');
+                bytes.next();
+            }
+            (byte, _) => unescaped.push(byte),
+        }
+    }
+    unescaped
+}
+
+/// One line of the manifest. Like sha256sum, names with backslashes or newlines have them escaped,
+/// and the line starts with a backslash to say so.
+fn line(hash: &str, path: &OsStr) -> Vec<u8> {
+    let path = path.as_bytes();
+    let mut line = Vec::with_capacity(hash.len() + path.len() + 4);
+    if path.iter().any(|&byte| byte == b'\\' || byte == b'\n') {
+        line.push(b'\\');
+    }
+    line.extend_from_slice(hash.as_bytes());
+    line.extend_from_slice(b"  ");
+    for &byte in path {
+        match byte {
+            b'\\' => line.extend_from_slice(b"\\\\"),
+            b'\n' => line.extend_from_slice(b"\\n"),
+            byte => line.push(byte),
+        }
+    }
+    line.push(b'\n');
+    line
+}
@@ -0,0 +1,63 @@
+use crate::{tiff, EmbeddedJpegInfo};
+use anyhow::{bail, ensure, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+const MRW_MAGIC: &[u8] = b"\0MRM";
+
+pub fn is_mrw(buf: &[u8]) -> bool {
+    buf.starts_with(MRW_MAGIC)
+}
+
+/// Find all embedded JPEGs in a Minolta MRW file.
+///
+/// MRW files start with an MRM block, which contains a sequence of sub-blocks, each with a four
+/// byte tag and a big endian u32 length. One of them, TTW, contains a complete TIFF structure with
+/// offsets relative to the start of the block, which we then parse as usual.
+pub fn find_jpegs(
+    raw_buf: &[u8],
+    strict: bool,
+    candidates: &mut Vec<EmbeddedJpegInfo>,
+) -> Result<()> {
+    const BLOCK_HEADER_SIZE: usize = 8;
+
+    let mrm_len: usize = BigEndian::read_u32(
+        raw_buf
+            .get(4..BLOCK_HEADER_SIZE)
+            .context("Truncated MRW header")?,
+    )
+    .try_into()?;
+    let mrm = raw_buf
+        .get(BLOCK_HEADER_SIZE..BLOCK_HEADER_SIZE + mrm_len)
+        .context("MRM block exceeds file size")?;
+
+    let mut pos = 0;
+    while pos + BLOCK_HEADER_SIZE <= mrm.len() {
+        let tag = &mrm[pos..pos + 4];
+        let len: usize = BigEndian::read_u32(&mrm[pos + 4..pos + BLOCK_HEADER_SIZE]).try_into()?;
+        let data_start = pos + BLOCK_HEADER_SIZE;
+        ensure!(len <= mrm.len() - data_start, "MRW block exceeds MRM block");
+
+        if tag == b"\0TTW" {
+            let tiff_offset = BLOCK_HEADER_SIZE + data_start;
+            let first = candidates.len();
+            // Even if parsing fails, any candidates found so far still need fixing up. Those whose
+            // offsets can't be, because they'd overflow, are dropped unless we're being strict.
+            let result = tiff::find_jpegs(&mrm[data_start..data_start + len], strict, candidates);
+            let base = tiff_offset.try_into()?;
+            let mut shifted = Vec::new();
+            for mut jpeg in candidates.drain(first..) {
+                match jpeg.shift(base) {
+                    Ok(()) => shifted.push(jpeg),
+                    Err(err) if strict => return Err(err),
+                    Err(_) => {}
+                }
+            }
+            candidates.extend(shifted);
+            return result;
+        }
+
+        pos = data_start + len;
+    }
+
+    bail!("No TIFF block found in MRW file")
+}
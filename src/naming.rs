@@ -0,0 +1,525 @@
+use crate::manifest::Manifest;
+use anyhow::{bail, ensure, Context, Result};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset};
+use rawtojpg::Metadata;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+/// What's used for variables the RAW has no value for, so that it's still clear where they go.
+const UNKNOWN: &str = "unknown";
+
+/// How {date} is shown if the template doesn't say.
+const DEFAULT_DATE_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+/// The template --rename-by-date uses.
+pub const BY_DATE: &str = "{date}_{stem}";
+
+/// How many digits {seq} is padded to if the template doesn't say.
+const DEFAULT_SEQ_DIGITS: usize = 4;
+
+/// Characters which Windows, and so SMB shares, can't have in names, and which --sanitize-names
+/// escapes, along with percent signs, so that names stay distinct once they're escaped.
+const UNSAFE_CHARS: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*', '%'];
+
+/// The directories --layout date makes if it isn't given a format.
+const DEFAULT_LAYOUT_FORMAT: &str = "%Y/%m/%d";
+
+/// How to lay out the directories under the output directory.
+#[derive(Clone, Debug, Default)]
+pub enum Layout {
+    /// The same as the input directory
+    #[default]
+    Mirror,
+    /// By when each picture was taken, with a strftime format
+    Date(String),
+    /// By the model of camera each picture was taken with
+    Camera,
+    /// All in the output directory itself
+    Flat,
+}
+
+/// Parse a --layout, like mirror, date, date:%Y/%m, camera, or flat.
+pub fn parse_layout(layout: &str) -> Result<Layout> {
+    match layout.split_once(':') {
+        None if layout == "mirror" => Ok(Layout::Mirror),
+        None if layout == "date" => Ok(Layout::Date(DEFAULT_LAYOUT_FORMAT.to_string())),
+        None if layout == "camera" => Ok(Layout::Camera),
+        None if layout == "flat" => Ok(Layout::Flat),
+        Some(("date", format)) => {
+            check_date_format(format)?;
+            ensure!(
+                !format.starts_with('/') && !format.split('/').any(|part| part == ".."),
+                "Layout must stay inside the output directory"
+            );
+            Ok(Layout::Date(format.to_string()))
+        }
+        _ => bail!("Layout must be mirror, date, date:FORMAT, camera, or flat, not {layout}"),
+    }
+}
+
+impl Layout {
+    /// The directory under the output directory to put the preview of a RAW in, given the one it
+    /// was in under the input directory, and when it was taken.
+    pub fn directory(
+        &self,
+        relative_dir: &Path,
+        metadata: &Metadata,
+        time: Option<DateTime<FixedOffset>>,
+    ) -> PathBuf {
+        match self {
+            Self::Mirror => relative_dir.to_path_buf(),
+            Self::Date(format) => PathBuf::from(format_date(time, format)),
+            Self::Camera => {
+                let camera = metadata.model.as_ref().or(metadata.make.as_ref());
+                PathBuf::from(clean(camera.map_or(UNKNOWN, String::as_str)))
+            }
+            Self::Flat => PathBuf::new(),
+        }
+    }
+}
+
+/// What to do when two RAWs would have previews with the same name.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum Collision {
+    /// Fail on the second RAW
+    #[default]
+    Error,
+    /// Leave the second RAW out, with a warning
+    Skip,
+    /// Write both, so that whichever is written last wins
+    Overwrite,
+    /// Number the second preview, like IMG_0001-1.jpg, and so on for any after it
+    Number,
+}
+
+/// What to do with previews which are already there from before the run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Existing {
+    Keep,
+    Overwrite,
+    /// Overwrite them only if the RAW has been modified since they were, like rsync -u
+    Update,
+}
+
+/// Where a preview should go, once it's been claimed.
+pub enum Claim {
+    /// Write it here
+    Write(PathBuf),
+    /// Leave it out, since another RAW's preview has the same name
+    Collision,
+    /// Leave it out, since it's already there, from before this run, and `Existing` says to keep it
+    Exists,
+}
+
+/// The previews which are being written, so that two RAWs which are named the same way, like
+/// DCIM/100MSDCF/DSC00001.ARW and DCIM/101MSDCF/DSC00001.ARW with --flatten, don't overwrite each
+/// other's previews unless they're told to. Which RAW counts as the second is whichever gets
+/// there last, unless there are `turns`. Previews which were already there before the run are
+/// handled as `existing` says. Everything which is written is also added to the --manifest, if
+/// there is one.
+pub struct Outputs {
+    on_collision: Collision,
+    existing: Existing,
+    pub manifest: Option<Manifest>,
+    turns: Option<Turns>,
+    claimed: Mutex<HashSet<PathBuf>>,
+    kept: AtomicUsize,
+}
+
+impl Outputs {
+    pub fn new(
+        on_collision: Collision,
+        existing: Existing,
+        manifest: Option<Manifest>,
+        turns: Option<Turns>,
+    ) -> Self {
+        Self {
+            on_collision,
+            existing,
+            manifest,
+            turns,
+            claimed: Mutex::default(),
+            kept: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait until it's the turn of the RAW at `source` to claim its previews, if there are turns.
+    pub async fn wait_turn(&self, source: &Path) {
+        if let Some(turns) = &self.turns {
+            turns.wait(source).await;
+        }
+    }
+
+    /// Say that the RAW at `source` has claimed all of its previews, or won't be claiming any,
+    /// letting the next one have its turn. This can be called more than once.
+    pub fn end_turn(&self, source: &Path) {
+        if let Some(turns) = &self.turns {
+            turns.end(source);
+        }
+    }
+
+    /// Claim `path` for the preview of the RAW at `source`, and say where it should actually be
+    /// written, if anywhere.
+    pub fn claim(&self, path: &Path, source: &Path) -> Result<Claim> {
+        let mut claimed = self.claimed.lock().expect("lock shouldn't be poisoned");
+        let mut candidate = path.to_path_buf();
+        for number in 1.. {
+            if claimed.insert(candidate.clone()) {
+                if self.keeps(&candidate, source)? {
+                    self.kept.fetch_add(1, Ordering::Relaxed);
+                    return Ok(Claim::Exists);
+                }
+                return Ok(Claim::Write(candidate));
+            }
+            match self.on_collision {
+                Collision::Error => bail!(
+                    "{} is the name of the preview of another RAW too, so it wasn't written for {}",
+                    path.display(),
+                    source.display()
+                ),
+                Collision::Skip => return Ok(Claim::Collision),
+                Collision::Overwrite => return Ok(Claim::Write(candidate)),
+                Collision::Number => candidate = numbered(path, number),
+            }
+        }
+        unreachable!("there can't be more previews than numbers")
+    }
+
+    /// Every claimed path without its extension, which other files written for the same RAW are
+    /// named after.
+    pub fn stems(&self) -> HashSet<PathBuf> {
+        let claimed = self.claimed.lock().expect("lock shouldn't be poisoned");
+        claimed.iter().map(|path| path.with_extension("")).collect()
+    }
+
+    /// Whether a preview at `path` from before the run should be kept, rather than the RAW at
+    /// `source` being written over it.
+    fn keeps(&self, path: &Path, source: &Path) -> Result<bool> {
+        Ok(match self.existing {
+            Existing::Keep => path.try_exists()?,
+            Existing::Overwrite => false,
+            Existing::Update => match path.metadata() {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+                metadata => metadata?.modified()? >= source.metadata()?.modified()?,
+            },
+        })
+    }
+
+    /// How many previews were left alone because they were already there.
+    pub fn kept(&self) -> usize {
+        self.kept.load(Ordering::Relaxed)
+    }
+}
+
+/// The order RAWs claim their previews in for --deterministic, so that which one counts as the
+/// second when two are named the same way is always the same. Each RAW's turn comes once all of
+/// those before it have had theirs.
+pub struct Turns {
+    order: HashMap<PathBuf, usize>,
+    ended: Mutex<Vec<bool>>,
+    next: watch::Sender<usize>,
+}
+
+impl Turns {
+    pub fn new(order: &[PathBuf]) -> Self {
+        Self {
+            order: order.iter().cloned().zip(0..).collect(),
+            ended: Mutex::new(vec![false; order.len()]),
+            next: watch::Sender::new(0),
+        }
+    }
+
+    async fn wait(&self, source: &Path) {
+        let Some(&turn) = self.order.get(source) else {
+            return;
+        };
+        let mut next = self.next.subscribe();
+        // This can only fail if the sender is dropped, and we have it.
+        let _ = next.wait_for(|&next| next >= turn).await;
+    }
+
+    fn end(&self, source: &Path) {
+        let Some(&turn) = self.order.get(source) else {
+            return;
+        };
+        let mut ended = self.ended.lock().expect("lock shouldn't be poisoned");
+        ended[turn] = true;
+        let mut next = *self.next.borrow();
+        while ended.get(next).is_some_and(|&ended| ended) {
+            next += 1;
+        }
+        self.next.send_replace(next);
+    }
+}
+
+/// `path` with `number` after its stem, like IMG_0001-1.jpg.
+fn numbered(path: &Path, number: u64) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!("-{number}"));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+fn check_date_format(format: &str) -> Result<()> {
+    ensure!(
+        !StrftimeItems::new(format).any(|item| item == Item::Error),
+        "Invalid date format {format}"
+    );
+    Ok(())
+}
+
+/// When the picture was taken, in `format`, or "unknown" if the RAW doesn't say.
+fn format_date(time: Option<DateTime<FixedOffset>>, format: &str) -> String {
+    match time {
+        Some(time) => time.format(format).to_string(),
+        None => UNKNOWN.to_string(),
+    }
+}
+
+/// The variables a --name-template can use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Variable {
+    /// When the picture was taken, which can be followed by a strftime format, like {date:%Y%m%d}
+    Date,
+    Make,
+    Model,
+    Lens,
+    /// The name of the RAW without its extension
+    Stem,
+    /// Where the picture comes in the order they were taken, which can be followed by how many
+    /// digits to pad it to, like {seq:6}
+    Seq,
+}
+
+impl Variable {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "date" => Self::Date,
+            "make" => Self::Make,
+            "model" => Self::Model,
+            "lens" => Self::Lens,
+            "stem" => Self::Stem,
+            "seq" => Self::Seq,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Variable(Variable, Option<String>),
+}
+
+/// A --name-template, like "{date:%Y%m%d}_{model}_{stem}.jpg".
+#[derive(Clone, Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+    /// Whether the template ends with its own extension, which is then replaced by the preview's,
+    /// rather than having it added.
+    has_extension: bool,
+}
+
+/// Parse a --name-template. Variables go in braces, and literal braces are doubled, like {{.
+pub fn parse_template(template: &str) -> Result<Template> {
+    ensure!(
+        !template.starts_with('/') && !template.split('/').any(|part| part == ".."),
+        "Name template must stay inside the output directory"
+    );
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest
+                    .find('}')
+                    .with_context(|| format!("Unclosed {{ in name template {template}"))?;
+                let (name, format) = match rest[..end].split_once(':') {
+                    Some((name, format)) => (name, Some(format)),
+                    None => (&rest[..end], None),
+                };
+                let variable = Variable::from_name(name)
+                    .with_context(|| format!("Unknown variable {{{name}}} in name template"))?;
+                match (variable, format) {
+                    (_, None) => {}
+                    (Variable::Date, Some(format)) => check_date_format(format)?,
+                    (Variable::Seq, Some(digits)) => {
+                        ensure!(
+                            digits.parse::<usize>().is_ok(),
+                            "{{seq}} must be followed by a number of digits, not {digits}"
+                        );
+                    }
+                    (_, Some(_)) => bail!("Only {{date}} and {{seq}} can have formats"),
+                }
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(Part::Variable(variable, format.map(str::to_string)));
+                chars = rest[end + 1..].chars();
+            }
+            '}' => bail!("Unmatched }} in name template {template}"),
+            c => literal.push(c),
+        }
+    }
+    let has_extension = literal.rsplit_once('.').is_some_and(|(_, extension)| {
+        !extension.is_empty() && extension.chars().all(|c| c.is_ascii_alphanumeric())
+    });
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    Ok(Template {
+        parts,
+        has_extension,
+    })
+}
+
+impl Template {
+    /// Whether the template has a {seq}, which means every RAW has to be read before any of them
+    /// can be named.
+    pub fn uses_seq(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Variable(Variable::Seq, _)))
+    }
+
+    /// Render the template for the RAW named `stem`, which was taken at `time` and is number `seq`
+    /// in the order they were taken, giving a path relative to the output directory, without the
+    /// template's extension, if it has one. Values can't add directories, since any slashes in
+    /// them are replaced, but the template itself can.
+    pub fn render(
+        &self,
+        stem: &str,
+        metadata: &Metadata,
+        time: Option<DateTime<FixedOffset>>,
+        seq: Option<u64>,
+    ) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Literal(literal) => {
+                    out.push_str(literal);
+                    continue;
+                }
+                Part::Variable(Variable::Date, format) => Some(format_date(
+                    time,
+                    format.as_deref().unwrap_or(DEFAULT_DATE_FORMAT),
+                )),
+                Part::Variable(Variable::Make, _) => metadata.make.clone(),
+                Part::Variable(Variable::Model, _) => metadata.model.clone(),
+                Part::Variable(Variable::Lens, _) => metadata.lens.clone(),
+                Part::Variable(Variable::Stem, _) => Some(stem.to_string()),
+                Part::Variable(Variable::Seq, digits) => seq.map(|seq| {
+                    let digits = digits.as_deref().and_then(|digits| digits.parse().ok());
+                    format!(
+                        "{seq:0width$}",
+                        width = digits.unwrap_or(DEFAULT_SEQ_DIGITS)
+                    )
+                }),
+            };
+            out.push_str(&clean(value.as_deref().unwrap_or(UNKNOWN)));
+        }
+
+        if self.has_extension {
+            if let Some(dot) = out.rfind('.') {
+                out.truncate(dot);
+            }
+        }
+        out
+    }
+}
+
+/// Where a picture comes in a burst, for --burst-gap.
+#[derive(Clone, Copy, Debug)]
+pub struct Burst {
+    /// Which burst it's in, counting from 1 in the order they were taken
+    pub number: u64,
+    /// Where it comes in the burst, counting from 1
+    pub index: usize,
+    pub len: usize,
+}
+
+impl Burst {
+    /// What's added to the names of previews in the burst, like _burst3_2of9.
+    pub fn suffix(&self) -> String {
+        format!("_burst{}_{}of{}", self.number, self.index, self.len)
+    }
+
+    /// The directory --burst-dirs puts the burst in, like burst3.
+    pub fn directory(&self) -> String {
+        format!("burst{}", self.number)
+    }
+}
+
+/// Parse a --prefix or --suffix, which can't have slashes, since it's only meant to change the
+/// names of previews, not where they go.
+pub fn parse_affix(affix: &str) -> Result<String> {
+    ensure!(
+        !affix.contains(['/', '\0']),
+        "Prefixes and suffixes can't have slashes or NULs: {affix}"
+    );
+    Ok(affix.to_string())
+}
+
+/// Make a value safe to put in a path, so that it can only ever be part of one file name.
+fn clean(value: &str) -> String {
+    let value = value.replace(['/', '\0'], "_");
+    match value.as_str() {
+        "" | "." | ".." => format!("_{value}"),
+        _ => value,
+    }
+}
+
+/// Make every name in `path` safe to copy anywhere, for --sanitize-names, by percent-escaping
+/// control characters like newlines, characters Windows can't have, like colons, dots and spaces
+/// at the end of names, which it drops, and bytes which aren't UTF-8.
+pub fn sanitize(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) => sanitize_name(name),
+            component => component.as_os_str().to_os_string(),
+        })
+        .collect()
+}
+
+fn sanitize_name(name: &OsStr) -> OsString {
+    let mut out = String::new();
+    let escape = |out: &mut String, bytes: &[u8]| {
+        for byte in bytes {
+            write!(out, "%{byte:02X}").expect("writing to a String can't fail");
+        }
+    };
+    for chunk in name.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c.is_control() || UNSAFE_CHARS.contains(&c) {
+                escape(&mut out, c.encode_utf8(&mut [0; 4]).as_bytes());
+            } else {
+                out.push(c);
+            }
+        }
+        escape(&mut out, chunk.invalid());
+    }
+    let trailing = out.split_off(out.trim_end_matches(['.', ' ']).len());
+    escape(&mut out, trailing.as_bytes());
+    OsString::from(out)
+}
@@ -0,0 +1,98 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// The extensions of everything which can be written for a RAW: previews, converted copies, and
+/// sidecars. Nothing else is ever deleted.
+const EXTENSIONS: &[&str] = &["jpg", "jxl", "png", "webp", "avif", "xmp", "json"];
+
+/// What contact sheets are called, which are left alone even though they're JPEGs.
+const CONTACT_SHEET_PREFIX: &str = "contact-sheet";
+
+/// Delete everything under `out_dir` which looks like it was written for a RAW, but wasn't
+/// written for any of those in this run, and then any directories that leaves empty. Files are
+/// kept if their names start with one of `stems` followed by a dot, which covers the previews
+/// themselves, like DSC00001.jpg, and everything named after them, like DSC00001.1024.jpg and
+/// DSC00001.jpg.xmp. Anything in `keep`, like the --report, is kept too. Gives how many files were
+/// deleted.
+pub async fn delete(out_dir: &Path, stems: &HashSet<PathBuf>, keep: &[&Path]) -> Result<usize> {
+    let mut keep_canonical = Vec::new();
+    for path in keep {
+        if let Ok(path) = fs::canonicalize(path).await {
+            keep_canonical.push(path);
+        }
+    }
+    let mut deleted = 0;
+    let mut dirs = Vec::new();
+    let mut dir_queue = vec![out_dir.to_path_buf()];
+    // Directories which have had something deleted from them, which are the only ones removed if
+    // they end up empty, so that empty directories which were already there are left alone.
+    let mut emptied = HashSet::new();
+
+    while let Some(current_dir) = dir_queue.pop() {
+        let mut read_dir = fs::read_dir(&current_dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dir_queue.push(path);
+            } else if file_type.is_file()
+                && is_orphan(&path, stems)
+                && !is_kept(&path, &keep_canonical).await
+            {
+                fs::remove_file(&path).await?;
+                deleted += 1;
+                emptied.insert(current_dir.clone());
+            }
+        }
+        dirs.push(current_dir);
+    }
+
+    // Directories always come after their parents, so going backwards empties children first.
+    for dir in dirs.iter().rev().filter(|&dir| dir != out_dir) {
+        if !emptied.contains(dir) {
+            continue;
+        }
+        match fs::remove_dir(dir).await {
+            Ok(()) => {
+                if let Some(parent) = dir.parent() {
+                    emptied.insert(parent.to_path_buf());
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::DirectoryNotEmpty => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(deleted)
+}
+
+fn is_orphan(path: &Path, stems: &HashSet<PathBuf>) -> bool {
+    let name = path.file_name().unwrap_or_default().as_bytes();
+    let written = path
+        .extension()
+        .is_some_and(|extension| EXTENSIONS.iter().any(|&known| extension == known));
+    if !written || name.starts_with(CONTACT_SHEET_PREFIX.as_bytes()) {
+        return false;
+    }
+    let owned = name
+        .iter()
+        .enumerate()
+        .filter(|&(_, &byte)| byte == b'.')
+        .any(|(dot, _)| stems.contains(&path.with_file_name(OsStr::from_bytes(&name[..dot]))));
+    !owned
+}
+
+/// Whether `path` is one of `keep`, which are canonical, so that it doesn't matter how either was
+/// written. Only paths with the same name as one of them need to be looked up.
+async fn is_kept(path: &Path, keep: &[PathBuf]) -> bool {
+    if !keep.iter().any(|kept| kept.file_name() == path.file_name()) {
+        return false;
+    }
+    fs::canonicalize(path)
+        .await
+        .is_ok_and(|path| keep.contains(&path))
+}
@@ -0,0 +1,91 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage};
+use serde::Serialize;
+use std::f64::consts::PI;
+
+/// The kinds of perceptual hash which can be computed for a preview.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Kind {
+    /// Whether each pixel is brighter than the one to its right, at 9 by 8. Quick, and good at
+    /// finding frames from the same burst
+    Dhash,
+    /// Whether each of the lowest frequencies is above the median, from a DCT at 32 by 32. Slower,
+    /// but less affected by changes in exposure or contrast, like from brackets
+    Phash,
+}
+
+/// Each hash in hex, since that's how most tools which compare them expect them.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Hashes {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dhash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phash: Option<String>,
+}
+
+/// How many pixels across and down the low frequencies used by pHash are.
+const PHASH_LOW: usize = 8;
+/// The size pHash scales down to before its DCT.
+const PHASH_SIZE: usize = 32;
+
+/// Compute each of `kinds` for a preview.
+pub fn compute(image: &DynamicImage, kinds: &[Kind]) -> Hashes {
+    let mut hashes = Hashes::default();
+    for kind in kinds {
+        match kind {
+            Kind::Dhash => hashes.dhash = Some(format!("{:016x}", dhash(image))),
+            Kind::Phash => hashes.phash = Some(format!("{:016x}", phash(image))),
+        }
+    }
+    hashes
+}
+
+fn grey(image: &DynamicImage, width: u32, height: u32) -> GrayImage {
+    image
+        .resize_exact(width, height, FilterType::Triangle)
+        .into_luma8()
+}
+
+fn dhash(image: &DynamicImage) -> u64 {
+    let small = grey(image, 9, 8);
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = hash << 1 | u64::from(brighter);
+        }
+    }
+    hash
+}
+
+fn phash(image: &DynamicImage) -> u64 {
+    let small = grey(image, PHASH_SIZE as u32, PHASH_SIZE as u32);
+    let pixels: Vec<f64> = small.pixels().map(|pixel| f64::from(pixel[0])).collect();
+
+    // Only the lowest frequencies are needed, so this is just the DCT-II for those, along the rows
+    // and then down the columns.
+    let basis: Vec<Vec<f64>> = (0..PHASH_LOW)
+        .map(|frequency| {
+            (0..PHASH_SIZE)
+                .map(|n| (PI / PHASH_SIZE as f64 * (n as f64 + 0.5) * frequency as f64).cos())
+                .collect()
+        })
+        .collect();
+    let rows: Vec<[f64; PHASH_LOW]> = pixels
+        .chunks(PHASH_SIZE)
+        .map(|row| std::array::from_fn(|u| row.iter().zip(&basis[u]).map(|(a, b)| a * b).sum()))
+        .collect();
+    let mut low = Vec::with_capacity(PHASH_LOW * PHASH_LOW);
+    for column_basis in &basis {
+        for u in 0..PHASH_LOW {
+            let sum = rows.iter().zip(column_basis).map(|(row, b)| row[u] * b);
+            low.push(sum.sum::<f64>());
+        }
+    }
+
+    let mut sorted = low.clone();
+    sorted.sort_by(f64::total_cmp);
+    let median = (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.0;
+    low.iter()
+        .fold(0, |hash, &value| hash << 1 | u64::from(value > median))
+}
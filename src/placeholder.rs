@@ -0,0 +1,73 @@
+use anyhow::{Context, Result};
+use image::DynamicImage;
+use serde::Serialize;
+
+/// The kinds of placeholder which can be computed for a preview, for showing while it loads.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Kind {
+    Blurhash,
+    Thumbhash,
+}
+
+/// How many components across and down BlurHashes have. 4 by 3 is the usual choice for landscape
+/// photos.
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+/// ThumbHash can't encode anything bigger than this, and neither hash gains anything from more.
+const MAX_SIZE: u32 = 100;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Placeholders {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    /// The ThumbHash in base64, which is how it's normally passed around.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbhash: Option<String>,
+}
+
+/// Compute each of `kinds` for a preview.
+pub fn compute(image: &DynamicImage, kinds: &[Kind]) -> Result<Placeholders> {
+    let image = image.thumbnail(MAX_SIZE, MAX_SIZE).into_rgba8();
+    let (width, height) = image.dimensions();
+
+    let mut placeholders = Placeholders::default();
+    for kind in kinds {
+        match kind {
+            Kind::Blurhash => {
+                let (x, y) = BLURHASH_COMPONENTS;
+                let hash = blurhash::encode(x, y, width, height, &image)
+                    .context("Failed to compute BlurHash")?;
+                placeholders.blurhash = Some(hash);
+            }
+            Kind::Thumbhash => {
+                let hash = thumbhash::rgba_to_thumb_hash(width as usize, height as usize, &image);
+                placeholders.thumbhash = Some(base64(&hash));
+            }
+        }
+    }
+    Ok(placeholders)
+}
+
+/// Standard base64, with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for index in 0..4 {
+            if index <= chunk.len() {
+                let sextet = (group >> (18 - 6 * index)) & 0x3f;
+                out.push(char::from(ALPHABET[sextet as usize]));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,81 @@
+use crate::makernote::Layout;
+
+/// Where a camera keeps a preview which isn't referenced from the standard TIFF tags, or which
+/// they don't mark as the one to use.
+#[derive(Clone, Copy)]
+pub enum Preview {
+    /// A tag in the MakerNote, which is read according to the vendor's MakerNote layout.
+    MakerNoteTag(Layout, u16),
+    /// The image in this SubIFD of IFD0, counting from 0, which is preferred over any other.
+    SubIfd(usize),
+    /// A JPEG at a fixed offset in the file, which goes to the end of the file if there's no
+    /// length.
+    FixedOffset { offset: u64, length: Option<u64> },
+}
+
+/// The previews to look for in files from a camera, or a whole range of them.
+pub struct Quirk {
+    /// The start of the Make tag.
+    make: &'static [u8],
+    /// The start of the Model tag, or `None` to match every model from `make`.
+    model: Option<&'static [u8]>,
+    pub previews: &'static [Preview],
+}
+
+/// Every camera which needs something beyond the standard TIFF tags to find its best preview.
+///
+/// Entries for specific models win over the entry for their make, so they need to list everything
+/// from it which still applies. If a camera isn't here, we still find whatever the standard tags
+/// point to.
+const QUIRKS: &[Quirk] = &[
+    Quirk {
+        make: b"SONY",
+        model: None,
+        // PreviewImage
+        previews: &[Preview::MakerNoteTag(Layout::Sony, 0x2001)],
+    },
+    Quirk {
+        make: b"Canon",
+        model: None,
+        // PreviewImageInfo
+        previews: &[Preview::MakerNoteTag(Layout::Canon, 0xb6)],
+    },
+    Quirk {
+        make: b"NIKON",
+        model: None,
+        // NikonPreview
+        previews: &[Preview::MakerNoteTag(Layout::Nikon, 0x11)],
+    },
+    Quirk {
+        make: b"NIKON",
+        model: Some(b"NIKON D850"),
+        // JpgFromRaw, which is full size, and then NikonPreview
+        previews: &[
+            Preview::SubIfd(0),
+            Preview::MakerNoteTag(Layout::Nikon, 0x11),
+        ],
+    },
+    Quirk {
+        make: b"OLYMPUS",
+        model: Some(b"SP550UZ"),
+        // A 640x480 preview at the end of the file, which nothing points to
+        previews: &[Preview::FixedOffset {
+            offset: 0xa39800,
+            length: None,
+        }],
+    },
+];
+
+/// Find the quirks for a camera from the Make and Model tags in IFD0, preferring an entry for the
+/// model to one for the whole make.
+pub fn find(make: &[u8], model: Option<&[u8]>) -> Option<&'static Quirk> {
+    QUIRKS
+        .iter()
+        .filter(|quirk| {
+            make.starts_with(quirk.make)
+                && quirk
+                    .model
+                    .is_none_or(|prefix| model.is_some_and(|model| model.starts_with(prefix)))
+        })
+        .max_by_key(|quirk| quirk.model.map_or(0, |prefix| prefix.len() + 1))
+}
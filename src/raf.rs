@@ -0,0 +1,29 @@
+use crate::EmbeddedJpegInfo;
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW ";
+
+pub fn is_raf(buf: &[u8]) -> bool {
+    buf.starts_with(RAF_MAGIC)
+}
+
+/// Find the embedded JPEG in a Fujifilm RAF file.
+///
+/// RAF files are not TIFF based. They start with a fixed size header containing the magic, format
+/// version, and camera model, followed by a directory which has the offset and length of the JPEG
+/// preview as big endian u32s at 0x54 and 0x58.
+pub fn find_jpegs(raw_buf: &[u8], candidates: &mut Vec<EmbeddedJpegInfo>) -> Result<()> {
+    const JPEG_OFFSET_POS: usize = 0x54;
+
+    let dir = raw_buf
+        .get(JPEG_OFFSET_POS..JPEG_OFFSET_POS + 8)
+        .context("Truncated RAF header")?;
+
+    candidates.push(EmbeddedJpegInfo::new(
+        BigEndian::read_u32(&dir[..4]).into(),
+        BigEndian::read_u32(&dir[4..]).into(),
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use crate::Extracted;
+use anyhow::Result;
+use serde::Serialize;
+
+#[cfg(feature = "perceptual-hash")]
+use crate::perceptual_hash::Hashes;
+#[cfg(feature = "placeholders")]
+use crate::placeholder::Placeholders;
+
+/// What --report records about each preview.
+#[derive(Serialize)]
+pub struct Entry {
+    /// The RAW file the preview came from.
+    pub source: String,
+    /// Where the preview was written.
+    pub output: String,
+    #[cfg(feature = "placeholders")]
+    #[serde(flatten)]
+    pub placeholders: Placeholders,
+    #[cfg(feature = "perceptual-hash")]
+    #[serde(flatten)]
+    pub perceptual_hashes: Hashes,
+}
+
+impl Entry {
+    /// Make the entry for a RAW file, or `None` if no single preview was written for it.
+    pub fn new(extracted: &Extracted) -> Option<Self> {
+        Some(Self {
+            source: extracted.source.to_string_lossy().into_owned(),
+            output: extracted
+                .output_file
+                .as_ref()?
+                .to_string_lossy()
+                .into_owned(),
+            #[cfg(feature = "placeholders")]
+            placeholders: extracted.placeholders.clone(),
+            #[cfg(feature = "perceptual-hash")]
+            perceptual_hashes: extracted.perceptual_hashes.clone(),
+        })
+    }
+}
+
+/// Render the report as a JSON array, sorted by source file so that runs can be compared.
+pub fn render(mut entries: Vec<Entry>) -> Result<Vec<u8>> {
+    entries.sort_by(|a, b| a.source.cmp(&b.source));
+    let mut json = serde_json::to_vec_pretty(&entries)?;
+    json.push(b'\n');
+    Ok(json)
+}
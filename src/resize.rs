@@ -0,0 +1,229 @@
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat};
+use std::borrow::Cow;
+
+#[cfg(feature = "watermark")]
+use crate::watermark::Watermark;
+
+/// How hard the AVIF encoder tries, from 1 to 10. Slower speeds than this take much longer for
+/// very little gain.
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 6;
+
+/// The formats previews can be written in. Anything other than JPEG depends on the encoder for it
+/// being built in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Jpg,
+    #[cfg(feature = "png")]
+    Png,
+    #[cfg(feature = "webp")]
+    Webp,
+    #[cfg(feature = "avif")]
+    Avif,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpg => "jpg",
+            #[cfg(feature = "png")]
+            Self::Png => "png",
+            #[cfg(feature = "webp")]
+            Self::Webp => "webp",
+            #[cfg(feature = "avif")]
+            Self::Avif => "avif",
+        }
+    }
+}
+
+/// How previews which have to be decoded are encoded again.
+#[derive(Clone, Copy, Debug)]
+pub struct Encoding {
+    pub format: OutputFormat,
+    pub quality: u8,
+    /// Convert the preview from Adobe RGB to sRGB after decoding it.
+    pub from_adobe_rgb: bool,
+    /// Composite this onto the preview after decoding it, before any scaling.
+    #[cfg(feature = "watermark")]
+    pub watermark: Option<&'static Watermark>,
+}
+
+impl Encoding {
+    /// Whether a preview which doesn't need scaling can be used exactly as it is.
+    pub fn keeps_original(self) -> bool {
+        #[cfg(feature = "watermark")]
+        if self.watermark.is_some() {
+            return false;
+        }
+        self.format == OutputFormat::Jpg && !self.from_adobe_rgb
+    }
+}
+
+fn decode(data: &[u8], encoding: Encoding) -> Result<DynamicImage> {
+    let mut image = image::load_from_memory_with_format(data, ImageFormat::Jpeg)
+        .context("Failed to decode preview for resizing")?;
+    if encoding.from_adobe_rgb {
+        image = adobe_rgb_to_srgb(&image);
+    }
+    #[cfg(feature = "watermark")]
+    if let Some(watermark) = encoding.watermark {
+        let mut rgb = image.into_rgb8();
+        watermark.apply(&mut rgb);
+        image = DynamicImage::ImageRgb8(rgb);
+    }
+    Ok(image)
+}
+
+/// Decode a JPEG preview and turn it upright according to `orientation`, an Exif Orientation, for
+/// anything which shows it without any metadata.
+#[cfg(any(
+    feature = "contact-sheet",
+    feature = "placeholders",
+    feature = "perceptual-hash"
+))]
+pub fn decode_upright(data: &[u8], orientation: Option<u16>) -> Result<DynamicImage> {
+    let mut image = image::load_from_memory_with_format(data, ImageFormat::Jpeg)
+        .context("Failed to decode preview")?;
+    if let Some(orientation) = orientation
+        .and_then(|orientation| u8::try_from(orientation).ok())
+        .and_then(image::metadata::Orientation::from_exif)
+    {
+        image.apply_orientation(orientation);
+    }
+    Ok(image)
+}
+
+/// Adobe RGB's transfer function is a pure power curve.
+const ADOBE_RGB_GAMMA: f32 = 563.0 / 256.0;
+
+/// Linear Adobe RGB to linear sRGB. Both have a D65 white point, so this is just how their
+/// primaries relate, and the sRGB green is the only one which differs.
+const ADOBE_RGB_TO_SRGB: [[f32; 3]; 3] = [
+    [1.398_356, -0.398_356, 0.0],
+    [0.0, 1.0, 0.0],
+    [0.0, -0.042_929, 1.042_929],
+];
+
+/// How finely linear values are quantised before applying the sRGB transfer function. Darker
+/// values need much more precision than 8 bits once linear, but this is plenty.
+const LINEAR_STEPS: usize = 4096;
+
+/// Convert an Adobe RGB image to sRGB. Colours more saturated than sRGB can show are clipped.
+fn adobe_rgb_to_srgb(image: &DynamicImage) -> DynamicImage {
+    let to_linear: Vec<f32> = (0..=255u8)
+        .map(|value| (f32::from(value) / 255.0).powf(ADOBE_RGB_GAMMA))
+        .collect();
+    let to_srgb: Vec<u8> = (0..LINEAR_STEPS)
+        .map(|step| {
+            let linear = step as f32 / (LINEAR_STEPS - 1) as f32;
+            let encoded = if linear <= 0.003_130_8 {
+                linear * 12.92
+            } else {
+                1.055 * linear.powf(1.0 / 2.4) - 0.055
+            };
+            (encoded * 255.0).round() as u8
+        })
+        .collect();
+
+    let mut rgb = image.to_rgb8();
+    for pixel in rgb.pixels_mut() {
+        let linear = pixel.0.map(|value| to_linear[usize::from(value)]);
+        for (out, row) in pixel.0.iter_mut().zip(ADOBE_RGB_TO_SRGB) {
+            let value: f32 = row.iter().zip(linear).map(|(a, b)| a * b).sum();
+            *out = to_srgb[(value.clamp(0.0, 1.0) * (LINEAR_STEPS - 1) as f32).round() as usize];
+        }
+    }
+    DynamicImage::ImageRgb8(rgb)
+}
+
+fn encode(image: &DynamicImage, encoding: Encoding) -> Result<Vec<u8>> {
+    let Encoding {
+        format, quality, ..
+    } = encoding;
+    let mut out = Vec::new();
+    match format {
+        OutputFormat::Jpg => {
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality))?;
+        }
+        #[cfg(feature = "png")]
+        OutputFormat::Png => {
+            image.write_with_encoder(image::codecs::png::PngEncoder::new(&mut out))?;
+        }
+        #[cfg(feature = "webp")]
+        OutputFormat::Webp => {
+            let rgb = image.to_rgb8();
+            let encoder = webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height());
+            out.extend_from_slice(&encoder.encode(quality.into()));
+        }
+        #[cfg(feature = "avif")]
+        OutputFormat::Avif => {
+            image.write_with_encoder(image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                &mut out, AVIF_SPEED, quality,
+            ))?;
+        }
+    }
+    Ok(out)
+}
+
+/// Scale `image` down to fit in `size` by `size`, keeping its aspect ratio, and encode it.
+fn encode_scaled(image: &DynamicImage, size: u32, encoding: Encoding) -> Result<Vec<u8>> {
+    encode(&image.thumbnail(size, size), encoding)
+        .with_context(|| format!("Failed to encode {size} pixel copy of preview"))
+}
+
+fn fits(image: &DynamicImage, size: u32) -> bool {
+    image.width().max(image.height()) <= size
+}
+
+/// Make a copy of a JPEG which fits in each of `sizes`. The JPEG is only decoded once. If it
+/// already fits in a size and is staying a JPEG, the original is used as is, rather than being
+/// scaled up.
+pub fn renditions<'a>(
+    data: &'a [u8],
+    sizes: &[u32],
+    encoding: Encoding,
+) -> Result<Vec<(u32, Cow<'a, [u8]>)>> {
+    let image = decode(data, encoding)?;
+    sizes
+        .iter()
+        .map(|&size| {
+            if !fits(&image, size) {
+                Ok((size, Cow::Owned(encode_scaled(&image, size, encoding)?)))
+            } else if encoding.keeps_original() {
+                Ok((size, Cow::Borrowed(data)))
+            } else {
+                let converted = encode(&image, encoding).context("Failed to convert preview")?;
+                Ok((size, Cow::Owned(converted)))
+            }
+        })
+        .collect()
+}
+
+/// Convert a JPEG to another format or colour space, first scaling it down so that its long edge
+/// is at most `max_dimension`. Returns `None` if it's already small enough and nothing else about
+/// it needs to change, in which case it isn't decoded at all.
+pub fn convert(
+    data: &[u8],
+    max_dimension: Option<u32>,
+    encoding: Encoding,
+) -> Result<Option<Vec<u8>>> {
+    let small_enough = |max_dimension| {
+        rawtojpg::ImageFormat::Jpeg
+            .dimensions(data)
+            .is_some_and(|(width, height)| width.max(height) <= max_dimension)
+    };
+    if encoding.keeps_original() && max_dimension.is_none_or(small_enough) {
+        return Ok(None);
+    }
+
+    let image = decode(data, encoding)?;
+    match max_dimension {
+        Some(size) if !fits(&image, size) => encode_scaled(&image, size, encoding).map(Some),
+        _ if encoding.keeps_original() => Ok(None),
+        _ => encode(&image, encoding)
+            .context("Failed to convert preview")
+            .map(Some),
+    }
+}
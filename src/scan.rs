@@ -0,0 +1,37 @@
+use crate::{jpeg, EmbeddedJpegInfo, JPEG_SOI};
+
+/// Search the whole buffer for the largest well formed JPEG, ignoring the container entirely.
+///
+/// This is a last resort for files which are too damaged to parse, like those from truncated card
+/// reads. It has to read the whole file, so it's much slower than parsing, and can't tell previews
+/// apart from other JPEGs.
+pub fn find_largest_jpeg(raw_buf: &[u8]) -> Option<EmbeddedJpegInfo> {
+    // SOI is always followed by another marker, which makes false positives much rarer.
+    const SOI_AND_MARKER: &[u8] = &[JPEG_SOI[0], JPEG_SOI[1], 0xff];
+
+    let mut largest: Option<(usize, usize)> = None;
+    let mut pos = 0;
+
+    while let Some(found) = raw_buf[pos..]
+        .windows(SOI_AND_MARKER.len())
+        .position(|window| window == SOI_AND_MARKER)
+    {
+        let offset = pos + found;
+        match jpeg::stream_length(&raw_buf[offset..]) {
+            Some(length) => {
+                if largest.is_none_or(|(_, largest_length)| length > largest_length) {
+                    largest = Some((offset, length));
+                }
+                // Anything inside this JPEG, like an Exif thumbnail, is going to be smaller.
+                pos = offset + length;
+            }
+            None => pos = offset + 1,
+        }
+    }
+
+    let (offset, length) = largest?;
+    Some(EmbeddedJpegInfo::new(
+        offset.try_into().ok()?,
+        length.try_into().ok()?,
+    ))
+}
@@ -0,0 +1,96 @@
+use anyhow::{ensure, Context, Result};
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Something a RAW file can be read from at any offset, like a buffer or a seekable file.
+///
+/// Buffers hand out slices of themselves, so reading from them never copies. Anything else copies
+/// just the bytes which are asked for, which lets the TIFF parser read the IFDs and the preview
+/// without the rest of the file.
+pub trait Source {
+    /// The length of the whole file.
+    fn len(&self) -> u64;
+
+    /// Whether the file is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Read the `length` bytes at `offset`, failing if they aren't all in the file.
+    fn read_at(&self, offset: u64, length: u64) -> Result<Cow<'_, [u8]>>;
+}
+
+impl Source for [u8] {
+    fn len(&self) -> u64 {
+        // usize is never wider than 64 bits on anything Rust supports.
+        self.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, length: u64) -> Result<Cow<'_, [u8]>> {
+        crate::get_range(self, offset, length)
+            .map(Cow::Borrowed)
+            .with_context(|| format!("Data at offset {offset} exceeds file size"))
+    }
+}
+
+impl Source for Mmap {
+    fn len(&self) -> u64 {
+        Source::len(&self[..])
+    }
+
+    fn read_at(&self, offset: u64, length: u64) -> Result<Cow<'_, [u8]>> {
+        self[..].read_at(offset, length)
+    }
+}
+
+/// A `Source` for anything which can be read and seeked, like a file which can't be memory-mapped.
+pub struct ReaderSource<R> {
+    reader: RefCell<R>,
+    len: u64,
+}
+
+impl<R: Read + Seek> ReaderSource<R> {
+    /// Wrap `reader`, working out its length by seeking to the end.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            reader: RefCell::new(reader),
+            len,
+        })
+    }
+}
+
+impl<R: Read + Seek> Source for ReaderSource<R> {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&self, offset: u64, length: u64) -> Result<Cow<'_, [u8]>> {
+        ensure!(
+            offset
+                .checked_add(length)
+                .is_some_and(|end| end <= self.len),
+            "Data at offset {offset} exceeds file size"
+        );
+        let mut data = vec![0; length.try_into()?];
+        let mut reader = self.reader.borrow_mut();
+        reader.seek(SeekFrom::Start(offset))?;
+        reader
+            .read_exact(&mut data)
+            .with_context(|| format!("Failed to read data at offset {offset}"))?;
+        Ok(Cow::Owned(data))
+    }
+}
+
+/// Read up to `max` bytes at `offset`, stopping early at the end of the file. Returns `None` if
+/// `offset` is past the end.
+pub(crate) fn read_up_to<S: Source + ?Sized>(
+    source: &S,
+    offset: u64,
+    max: u64,
+) -> Option<Cow<'_, [u8]>> {
+    let available = source.len().checked_sub(offset)?;
+    source.read_at(offset, available.min(max)).ok()
+}
@@ -0,0 +1,96 @@
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs::Metadata;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The RAWs which have been done, for --state-db. Each RAW gets a row once it's done, whether it
+/// worked or not, so that an interrupted run can pick up where it stopped. Paths are stored as
+/// their bytes, since they don't have to be UTF-8.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS inputs (
+        path BLOB PRIMARY KEY,
+        size INTEGER NOT NULL,
+        mtime_ns INTEGER NOT NULL,
+        result TEXT NOT NULL,
+        error TEXT,
+        done_at INTEGER NOT NULL
+    );
+";
+
+pub struct State {
+    connection: Mutex<Connection>,
+}
+
+impl State {
+    /// Open the state database at `path`, creating it if it isn't there.
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open state database {}", path.display()))?;
+        // A row is written for every RAW, so this avoids syncing to disk for each one. The
+        // database can't be corrupted by a crash in WAL mode, only lose the last few rows, which
+        // just means those RAWs are done again.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Whether the RAW at `path`, with `metadata`, was done without errors as it is now.
+    pub fn is_done(&self, path: &Path, metadata: &Metadata) -> Result<bool> {
+        let connection = self.connection.lock().expect("lock shouldn't be poisoned");
+        let done = connection
+            .query_row(
+                "SELECT 1 FROM inputs
+                 WHERE path = ?1 AND size = ?2 AND mtime_ns = ?3 AND result = 'ok'",
+                params![key(path)?, size(metadata), mtime_ns(metadata)],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(done.is_some())
+    }
+
+    /// Record that the RAW at `path`, with `metadata`, was done, and the error if it failed.
+    pub fn record(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        error: Option<&anyhow::Error>,
+    ) -> Result<()> {
+        let error = error.map(|err| format!("{err:#}"));
+        let connection = self.connection.lock().expect("lock shouldn't be poisoned");
+        connection.execute(
+            "INSERT OR REPLACE INTO inputs (path, size, mtime_ns, result, error, done_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, unixepoch())",
+            params![
+                key(path)?,
+                size(metadata),
+                mtime_ns(metadata),
+                if error.is_some() { "error" } else { "ok" },
+                error,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// RAWs are looked up by their absolute paths, so that it doesn't matter how the input directory
+/// was given.
+fn key(path: &Path) -> Result<Vec<u8>> {
+    Ok(path.canonicalize()?.as_os_str().as_bytes().to_vec())
+}
+
+fn size(metadata: &Metadata) -> i64 {
+    i64::try_from(metadata.len()).unwrap_or(i64::MAX)
+}
+
+fn mtime_ns(metadata: &Metadata) -> i64 {
+    metadata
+        .mtime()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(metadata.mtime_nsec())
+}
@@ -0,0 +1,66 @@
+use crate::exiftool;
+use rawtojpg::Metadata;
+use std::path::Path;
+
+/// What's shown for fields the RAW doesn't have, so that every line has the same columns.
+const MISSING: &str = "-";
+
+/// The line --exif prints for a RAW file: its path, when it was taken, the camera, the lens and
+/// focal length, the shutter speed and aperture, and the ISO, separated by tabs so that it can be
+/// cut up or sorted.
+pub fn line(source: &Path, metadata: &Metadata) -> String {
+    let date =
+        metadata
+            .date_time_original
+            .as_ref()
+            .map(|date| match &metadata.offset_time_original {
+                Some(offset) => format!("{date}{offset}"),
+                None => date.clone(),
+            });
+    let fields = [
+        Some(source.to_string_lossy().into_owned()),
+        date,
+        camera(metadata),
+        metadata.lens.clone(),
+        metadata
+            .focal_length
+            .map(|focal_length| format!("{}mm", focal_length.round())),
+        exposure(metadata),
+        metadata.iso.map(|iso| format!("ISO {iso}")),
+    ];
+    fields
+        .iter()
+        .map(|field| field.as_deref().unwrap_or(MISSING))
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+/// The make and model, without the make if the model already starts with it, like "Canon EOS R5"
+/// or "NIKON Z 6" from NIKON CORPORATION.
+fn camera(metadata: &Metadata) -> Option<String> {
+    match (&metadata.make, &metadata.model) {
+        (Some(make), Some(model)) => {
+            let brand = make.split_whitespace().next().unwrap_or(make);
+            let repeated = model
+                .get(..brand.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(brand));
+            Some(if repeated {
+                model.clone()
+            } else {
+                format!("{make} {model}")
+            })
+        }
+        (make, model) => make.clone().or_else(|| model.clone()),
+    }
+}
+
+fn exposure(metadata: &Metadata) -> Option<String> {
+    let shutter = metadata
+        .exposure_time
+        .map(|seconds| format!("{}s", exiftool::exposure_time(seconds)));
+    let aperture = metadata.f_number.map(|f_number| format!("f/{f_number:.1}"));
+    match (shutter, aperture) {
+        (Some(shutter), Some(aperture)) => Some(format!("{shutter} {aperture}")),
+        (shutter, aperture) => shutter.or(aperture),
+    }
+}
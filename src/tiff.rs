@@ -0,0 +1,1196 @@
+//! A minimal, zero-copy reader for TIFF structures, which is what rawtojpg uses to walk the IFDs
+//! of most RAW formats.
+//!
+//! Nothing is read until it's asked for, and nothing is copied, so this is also useful for reading
+//! other tags from big files without reading the whole thing. For example, to get the Model from
+//! IFD0, look for tag 0x110 in the entries from `read_ifd(tiff.first_ifd_offset())`, and read it
+//! with `entry_ascii`.
+
+use crate::quirks::{self, Preview, Quirk};
+use crate::source::{self, Source};
+use crate::{get_range, makernote, ColorSpace, EmbeddedJpegInfo, ImageFormat, Metadata, JPEG_SOI};
+use anyhow::{bail, ensure, Context, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+/// TIFF magic, plus the vendor variants which replace the TIFF version number (0x2a) with their
+/// own: Panasonic RW2 uses 0x55, and Olympus ORF uses "RO", "RS", or "OR".
+const TIFF_MAGIC_LE: &[&[u8]] = &[b"II*\0", b"IIU\0", b"IIRO", b"IIRS"];
+const TIFF_MAGIC_BE: &[&[u8]] = &[b"MM\0*", b"MMOR"];
+
+/// BigTIFF uses version 43 (0x2b), followed by the offset size, which is always 8.
+const BIGTIFF_MAGIC_LE: &[u8] = b"II+\0\x08\0\0\0";
+const BIGTIFF_MAGIC_BE: &[u8] = b"MM\0+\0\x08\0\0";
+
+pub const TYPE_BYTE: u16 = 1;
+pub const TYPE_ASCII: u16 = 2;
+pub const TYPE_SHORT: u16 = 3;
+pub const TYPE_LONG: u16 = 4;
+pub const TYPE_RATIONAL: u16 = 5;
+pub const TYPE_SBYTE: u16 = 6;
+pub const TYPE_UNDEFINED: u16 = 7;
+pub const TYPE_SSHORT: u16 = 8;
+pub const TYPE_SLONG: u16 = 9;
+pub const TYPE_SRATIONAL: u16 = 10;
+pub const TYPE_FLOAT: u16 = 11;
+pub const TYPE_DOUBLE: u16 = 12;
+pub const TYPE_IFD: u16 = 13;
+pub const TYPE_LONG8: u16 = 16;
+pub const TYPE_IFD8: u16 = 18;
+
+const IMAGE_WIDTH_TAG: u16 = 0x100;
+const IMAGE_LENGTH_TAG: u16 = 0x101;
+pub const ORIENTATION_TAG: u16 = 0x112;
+const SUB_IFDS_TAG: u16 = 0x14a;
+const NEW_SUBFILE_TYPE_TAG: u16 = 0xfe;
+pub(crate) const EXIF_IFD_TAG: u16 = 0x8769;
+pub(crate) const GPS_IFD_TAG: u16 = 0x8825;
+pub(crate) const INTEROP_IFD_TAG: u16 = 0xa005;
+
+/// Real files have a handful of IFDs, so this is just to put a bound on how much work a malicious
+/// file can make us do.
+const MAX_IFDS: usize = 1024;
+
+/// The size of a single value of a TIFF type, for the types we know how to read.
+fn type_size(kind: u16) -> Option<usize> {
+    match kind {
+        TYPE_BYTE | TYPE_ASCII | TYPE_SBYTE | TYPE_UNDEFINED => Some(1),
+        TYPE_SHORT | TYPE_SSHORT => Some(2),
+        TYPE_LONG | TYPE_SLONG | TYPE_FLOAT | TYPE_IFD => Some(4),
+        TYPE_RATIONAL | TYPE_SRATIONAL | TYPE_DOUBLE | TYPE_LONG8 | TYPE_IFD8 => Some(8),
+        _ => None,
+    }
+}
+
+/// The byte order and layout of a TIFF structure, and the source it's read from.
+pub struct Tiff<'a, S: Source + ?Sized = [u8]> {
+    source: &'a S,
+    /// Where the structure starts in `source`, which its offsets are relative to.
+    base: u64,
+    first_ifd_offset: u64,
+    read_u16: fn(&[u8]) -> u16,
+    read_u32: fn(&[u8]) -> u32,
+    read_u64: fn(&[u8]) -> u64,
+    /// BigTIFF uses 8 byte offsets and counts, rather than 4 byte ones.
+    big: bool,
+}
+
+impl<S: Source + ?Sized> Clone for Tiff<'_, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: Source + ?Sized> Copy for Tiff<'_, S> {}
+
+/// A single entry in an IFD. `value` is the raw value field, which either contains the value
+/// itself, or the offset to it if it doesn't fit.
+pub struct IfdEntry {
+    pub tag: u16,
+    /// The TIFF type of the values, like `TYPE_SHORT`.
+    pub kind: u16,
+    /// The number of values, not bytes.
+    pub count: u64,
+    value: [u8; 8],
+    /// Where `value` is in the structure.
+    position: u64,
+}
+
+/// An iterator over the entries of an IFD, from `Tiff::read_ifd`.
+pub struct IfdIter<'a, S: Source + ?Sized = [u8]> {
+    tiff: Tiff<'a, S>,
+    entries: Cow<'a, [u8]>,
+    /// Where `entries` is in the structure.
+    position: u64,
+    next: usize,
+}
+
+impl<S: Source + ?Sized> Clone for IfdIter<'_, S> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            ..*self
+        }
+    }
+}
+
+impl<S: Source + ?Sized> Iterator for IfdIter<'_, S> {
+    type Item = IfdEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry_size = self.tiff.entry_size();
+        let start = self.next * entry_size;
+        let entry = self.entries.get(start..start + entry_size)?;
+        self.next += 1;
+        Some(self.tiff.parse_entry(entry, self.position + start as u64))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.entries.len() / self.tiff.entry_size() - self.next;
+        (len, Some(len))
+    }
+}
+
+impl<S: Source + ?Sized> ExactSizeIterator for IfdIter<'_, S> {}
+
+impl<'a, S: Source + ?Sized> Tiff<'a, S> {
+    /// Read the header of a TIFF or BigTIFF structure at the start of `source`. Offsets in the
+    /// structure are relative to the start of `source`.
+    pub fn new(source: &'a S) -> Result<Self> {
+        Self::at(source, 0)
+    }
+
+    /// Read the header of a TIFF structure embedded at `offset` in this one, like the one in Nikon
+    /// MakerNotes. Offsets in it are relative to where it starts.
+    pub fn nested(&self, offset: u64) -> Result<Self> {
+        let base = self
+            .base
+            .checked_add(offset)
+            .with_context(|| format!("TIFF offset {offset} exceeds file size"))?;
+        Self::at(self.source, base)
+    }
+
+    fn at(source: &'a S, base: u64) -> Result<Self> {
+        let header = source::read_up_to(source, base, 16).unwrap_or_default();
+        let has_magic = |magics: &[&[u8]]| magics.iter().any(|magic| header.starts_with(magic));
+        let (is_le, big) = if has_magic(TIFF_MAGIC_LE) {
+            (true, false)
+        } else if has_magic(TIFF_MAGIC_BE) {
+            (false, false)
+        } else if header.starts_with(BIGTIFF_MAGIC_LE) {
+            (true, true)
+        } else if header.starts_with(BIGTIFF_MAGIC_BE) {
+            (false, true)
+        } else {
+            bail!("Not a valid TIFF file");
+        };
+        let header_len = if big { 16 } else { 8 };
+        ensure!(header.len() >= header_len, "Truncated TIFF header");
+
+        let mut tiff = if is_le {
+            Self {
+                source,
+                base,
+                first_ifd_offset: 0,
+                read_u16: LittleEndian::read_u16,
+                read_u32: LittleEndian::read_u32,
+                read_u64: LittleEndian::read_u64,
+                big,
+            }
+        } else {
+            Self {
+                source,
+                base,
+                first_ifd_offset: 0,
+                read_u16: BigEndian::read_u16,
+                read_u32: BigEndian::read_u32,
+                read_u64: BigEndian::read_u64,
+                big,
+            }
+        };
+        tiff.first_ifd_offset = if big {
+            (tiff.read_u64)(&header[8..16])
+        } else {
+            (tiff.read_u32)(&header[4..8]).into()
+        };
+        Ok(tiff)
+    }
+
+    fn offset_size(&self) -> usize {
+        if self.big {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn entry_size(&self) -> usize {
+        4 + 2 * self.offset_size()
+    }
+
+    fn read_offset(&self, bytes: &[u8]) -> u64 {
+        if self.big {
+            (self.read_u64)(bytes)
+        } else {
+            (self.read_u32)(bytes).into()
+        }
+    }
+
+    /// Whether this is a BigTIFF, with 8 byte offsets and counts.
+    pub fn is_big(&self) -> bool {
+        self.big
+    }
+
+    /// Whether the structure is little endian.
+    pub fn is_little_endian(&self) -> bool {
+        (self.read_u16)(&[1, 0]) == 1
+    }
+
+    /// The source the TIFF structure is in.
+    pub fn source(&self) -> &'a S {
+        self.source
+    }
+
+    /// The offset of IFD0.
+    pub fn first_ifd_offset(&self) -> u64 {
+        self.first_ifd_offset
+    }
+
+    /// Read the `length` bytes at `offset` in the structure.
+    pub fn read(&self, offset: u64, length: u64) -> Result<Cow<'a, [u8]>> {
+        let start = self
+            .base
+            .checked_add(offset)
+            .with_context(|| format!("Data at offset {offset} exceeds file size"))?;
+        self.source.read_at(start, length)
+    }
+
+    /// Read up to `max` bytes at `offset` in the structure, stopping early at the end of the
+    /// source, like for checking an image's signature.
+    pub(crate) fn read_up_to(&self, offset: u64, max: u64) -> Option<Cow<'a, [u8]>> {
+        source::read_up_to(self.source, self.base.checked_add(offset)?, max)
+    }
+
+    /// Whether there's a JPEG SOI marker at `offset` in the structure.
+    pub(crate) fn starts_with_soi(&self, offset: u64) -> bool {
+        self.read_up_to(offset, JPEG_SOI.len() as u64)
+            .is_some_and(|data| data.starts_with(JPEG_SOI))
+    }
+
+    /// Read the IFD at `offset`, returning its entries and the offset of the next IFD, which is 0 if
+    /// this is the last one.
+    pub fn read_ifd(&self, offset: u64) -> Result<(IfdIter<'a, S>, u64)> {
+        let count_size: u64 = if self.big { 8 } else { 2 };
+        let exceeds_file_size = || format!("IFD offset {offset} exceeds file size");
+
+        let count = self
+            .read(offset, count_size)
+            .with_context(exceeds_file_size)?;
+        let num_entries = if self.big {
+            (self.read_u64)(&count)
+        } else {
+            (self.read_u16)(&count).into()
+        };
+
+        // The count was read from just before here, so this can't overflow.
+        let position = offset + count_size;
+        let length = num_entries
+            .checked_mul(self.entry_size() as u64)
+            .and_then(|length| length.checked_add(self.offset_size() as u64))
+            .with_context(|| format!("IFD at offset {offset} has too many entries"))?;
+        let mut entries = self
+            .read(position, length)
+            .with_context(exceeds_file_size)?;
+        let entries_end = entries.len() - self.offset_size();
+        let next_ifd_offset = self.read_offset(&entries[entries_end..]);
+        match &mut entries {
+            Cow::Borrowed(entries) => *entries = &entries[..entries_end],
+            Cow::Owned(entries) => entries.truncate(entries_end),
+        }
+
+        let entries = IfdIter {
+            tiff: *self,
+            entries,
+            position,
+            next: 0,
+        };
+        Ok((entries, next_ifd_offset))
+    }
+
+    fn parse_entry(&self, entry: &[u8], position: u64) -> IfdEntry {
+        let offset_size = self.offset_size();
+        let mut value = [0; 8];
+        value[..offset_size].copy_from_slice(&entry[4 + offset_size..]);
+        IfdEntry {
+            tag: (self.read_u16)(&entry[..2]),
+            kind: (self.read_u16)(&entry[2..4]),
+            count: self.read_offset(&entry[4..4 + offset_size]),
+            value,
+            position: position + 4 + offset_size as u64,
+        }
+    }
+
+    /// Read the first value of an integer entry.
+    pub fn entry_uint(&self, entry: &IfdEntry) -> Option<u64> {
+        self.entry_uint_values(entry)?.next()
+    }
+
+    /// Read the offset of an entry's data, for entries which are too big to be stored inline.
+    pub fn entry_data_offset(&self, entry: &IfdEntry) -> u64 {
+        self.read_offset(&entry.value)
+    }
+
+    /// Where an entry's data is in the structure, and its length, whether it's stored inline or
+    /// not.
+    fn entry_range(&self, entry: &IfdEntry) -> Option<(u64, u64)> {
+        let length = entry
+            .count
+            .checked_mul(type_size(entry.kind)?.try_into().ok()?)?;
+        if length <= self.offset_size().try_into().ok()? {
+            Some((entry.position, length))
+        } else {
+            Some((self.entry_data_offset(entry), length))
+        }
+    }
+
+    /// Read the raw bytes of an entry's data, whether they're stored inline or not.
+    pub fn entry_bytes(&self, entry: &IfdEntry) -> Option<Cow<'a, [u8]>> {
+        let (offset, length) = self.entry_range(entry)?;
+        if length <= self.offset_size().try_into().ok()? {
+            // Inline values were read along with the entry, so there's no need to read them again.
+            return entry
+                .value
+                .get(..length.try_into().ok()?)
+                .map(|value| Cow::Owned(value.to_vec()));
+        }
+        self.read(offset, length).ok()
+    }
+
+    /// Read an ASCII entry, up to the first NUL.
+    pub fn entry_ascii(&self, entry: &IfdEntry) -> Option<String> {
+        if entry.kind != TYPE_ASCII {
+            return None;
+        }
+        let bytes = self.entry_bytes(entry)?;
+        let end = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(bytes.len());
+        String::from_utf8(bytes[..end].to_vec()).ok()
+    }
+
+    /// Read all of the values of an integer entry, whether they're stored inline or not.
+    pub fn entry_uints(&self, entry: &IfdEntry) -> Option<Vec<u64>> {
+        Some(self.entry_uint_values(entry)?.collect())
+    }
+
+    /// Read all of the values of an integer or rational entry as floats.
+    pub fn entry_numbers(&self, entry: &IfdEntry) -> Option<Vec<f64>> {
+        if entry.kind != TYPE_RATIONAL {
+            return Some(
+                self.entry_uint_values(entry)?
+                    .map(|value| value as f64)
+                    .collect(),
+            );
+        }
+        self.entry_bytes(entry)?
+            .chunks_exact(8)
+            .map(|value| {
+                let numerator = (self.read_u32)(&value[..4]);
+                let denominator = (self.read_u32)(&value[4..]);
+                (denominator != 0).then(|| f64::from(numerator) / f64::from(denominator))
+            })
+            .collect()
+    }
+
+    /// Read the values of an integer entry one at a time, without collecting them.
+    pub fn entry_uint_values(&self, entry: &IfdEntry) -> Option<impl Iterator<Item = u64> + '_> {
+        let size = match entry.kind {
+            TYPE_SHORT | TYPE_LONG | TYPE_IFD | TYPE_LONG8 | TYPE_IFD8 => type_size(entry.kind)?,
+            _ => return None,
+        };
+        let data = self.entry_bytes(entry)?;
+
+        Some((0..data.len() / size).map(move |index| {
+            let value = &data[index * size..];
+            match size {
+                2 => (self.read_u16)(value).into(),
+                4 => (self.read_u32)(value).into(),
+                _ => (self.read_u64)(value),
+            }
+        }))
+    }
+}
+
+/// Find all embedded JPEGs in a TIFF based RAW buffer.
+///
+/// This function parses the IFDs in the TIFF structure of the RAW file to find the JPEG
+/// thumbnails embedded in the file. Both classic TIFF and BigTIFF are supported. As well as the
+/// main IFD chain, we also look in SubIFDs, since that's where NEF and DNG usually keep their
+/// full size previews, and in the Exif IFD, which some cameras use for preview pointers too.
+///
+/// We hand roll the IFD parsing because libraries do not fit requirements. For example:
+///
+/// - kamadak-exif: Reads into a big `Vec<u8>`, which is huge for our big RAW.
+/// - quickexif: Cannot iterate over IFDs.
+///
+/// As well as the usual JPEGInterchangeFormat pointers, we also consider IFDs whose image is a
+/// single strip containing a JPEG, which is how some formats (like Phase One IIQ) store previews,
+/// and how plain JPEG compressed TIFFs store their main image. In the latter case, the strip may be
+/// an abbreviated JPEG which relies on the tables in the IFD's JPEGTables. Single tiles are treated
+/// the same way, since that's how DNG 1.7 usually stores JPEG XL previews. JPEG compressed images
+/// split over several strips are also considered, see `find_multi_strip_jpeg`.
+///
+/// For DNGs, we use NewSubfileType to only consider previews, and prefer a full resolution preview
+/// over anything else.
+///
+/// If `strict` is set, any problem with the structure, like an IFD which can't be read or which is
+/// referenced twice, is an error. Otherwise, we skip whatever is broken and carry on with the rest,
+/// only returning an error if we don't find anything at all.
+pub(crate) fn find_jpegs<S: Source + ?Sized>(
+    source: &S,
+    strict: bool,
+    candidates: &mut Vec<EmbeddedJpegInfo>,
+) -> Result<()> {
+    const STRIP_OFFSETS_TAG: u16 = 0x111;
+    const STRIP_BYTE_COUNTS_TAG: u16 = 0x117;
+    const TILE_OFFSETS_TAG: u16 = 0x144;
+    const TILE_BYTE_COUNTS_TAG: u16 = 0x145;
+    const JPEG_TABLES_TAG: u16 = 0x15b;
+    const JPEG_TAG: u16 = 0x201;
+    const JPEG_LENGTH_TAG: u16 = 0x202;
+    /// Panasonic's JpgFromRaw, an undefined array containing the whole JPEG.
+    const RW2_JPEG_TAG: u16 = 0x2e;
+    const MAKE_TAG: u16 = 0x10f;
+    const MODEL_TAG: u16 = 0x110;
+    const MAKER_NOTE_TAG: u16 = 0x927c;
+    const COMPRESSION_TAG: u16 = 0x103;
+    const COMPRESSION_OLD_JPEG: u64 = 6;
+    const COMPRESSION_JPEG: u64 = 7;
+    /// Lossy JPEG, as used by DNG 1.4 and later.
+    const COMPRESSION_LOSSY_JPEG: u64 = 34892;
+    /// JPEG XL, both the draft value and the one from DNG 1.7.
+    const COMPRESSION_JXL_DRAFT: u64 = 50002;
+    const COMPRESSION_JXL: u64 = 52546;
+    /// The NewSubfileType bit which marks an image as a reduced resolution version of another.
+    const SUBFILE_REDUCED_RESOLUTION: u64 = 1;
+    const DNG_VERSION_TAG: u16 = 0xc612;
+
+    let tiff = Tiff::new(source)?;
+    let first_candidate = candidates.len();
+
+    // IFDs can point to each other in a cycle, either maliciously or through corruption, so keep
+    // track of which ones we've already seen.
+    let mut ifd_queue = vec![tiff.first_ifd_offset()];
+    let mut seen_ifds = HashSet::new();
+    // IFD1 is where Exif puts the thumbnail, so we mark it for anyone who wants that rather than
+    // the largest preview.
+    let mut ifd1_offset = None;
+    // Where else to look for previews depends on which camera the file is from, which is in IFD0.
+    // IFD0 is always processed first, and the MakerNote is in the Exif IFD, so we always know it
+    // by then.
+    let mut make = None;
+    let mut model = None;
+    let mut quirk: Option<&Quirk> = None;
+    // The SubIFD whose image the quirks say to prefer, if any.
+    let mut preferred_ifd = None;
+    // DNG marks which images are previews, and how big they and the main image are, so we can do
+    // better than just picking the most bytes. The DNGVersion tag is also always in IFD0.
+    let mut is_dng = false;
+    let mut main_pixels = None;
+    let mut dng_previews = Vec::new();
+    // If we don't find anything, the first thing we skipped over is probably why.
+    let mut skipped_error = None;
+
+    while let Some(ifd_offset) = ifd_queue.pop() {
+        if ifd_offset == 0 {
+            continue;
+        }
+        if !seen_ifds.insert(ifd_offset) {
+            ensure!(
+                !strict,
+                "IFD at offset {ifd_offset} is referenced more than once"
+            );
+            continue;
+        }
+        if seen_ifds.len() > MAX_IFDS {
+            ensure!(!strict, "More than {MAX_IFDS} IFDs");
+            break;
+        }
+
+        let (entries, next_ifd_offset) = match tiff.read_ifd(ifd_offset) {
+            Ok(ifd) => ifd,
+            Err(err) if strict => return Err(err),
+            Err(err) => {
+                skipped_error.get_or_insert(err);
+                continue;
+            }
+        };
+        let mut cur_offset = None;
+        let mut cur_length = None;
+        let mut strip_offsets = None;
+        let mut strip_lengths = None;
+        let mut tiled = false;
+        let mut compression = None;
+        let mut jpeg_tables = None;
+        let mut new_subfile_type = 0;
+        let mut width = None;
+        let mut height = None;
+        let mut sub_ifds = Vec::new();
+        let ifd_start = candidates.len();
+
+        for entry in entries {
+            match entry.tag {
+                STRIP_OFFSETS_TAG => strip_offsets = Some(entry),
+                STRIP_BYTE_COUNTS_TAG => strip_lengths = Some(entry),
+                TILE_OFFSETS_TAG => (strip_offsets, tiled) = (Some(entry), true),
+                TILE_BYTE_COUNTS_TAG => strip_lengths = Some(entry),
+                COMPRESSION_TAG => compression = tiff.entry_uint(&entry),
+                NEW_SUBFILE_TYPE_TAG => new_subfile_type = tiff.entry_uint(&entry).unwrap_or(0),
+                IMAGE_WIDTH_TAG => width = tiff.entry_uint(&entry),
+                IMAGE_LENGTH_TAG => height = tiff.entry_uint(&entry),
+                DNG_VERSION_TAG => is_dng = true,
+                JPEG_TABLES_TAG => {
+                    jpeg_tables = Some((tiff.entry_data_offset(&entry), entry.count))
+                }
+                JPEG_TAG => cur_offset = tiff.entry_uint(&entry),
+                JPEG_LENGTH_TAG => cur_length = tiff.entry_uint(&entry),
+                RW2_JPEG_TAG => candidates.push(EmbeddedJpegInfo::new(
+                    tiff.entry_data_offset(&entry),
+                    entry.count,
+                )),
+                SUB_IFDS_TAG => {
+                    sub_ifds = tiff.entry_uints(&entry).unwrap_or_default();
+                    ifd_queue.extend(&sub_ifds);
+                }
+                EXIF_IFD_TAG => ifd_queue.extend(tiff.entry_uint(&entry)),
+                MAKE_TAG => make = tiff.entry_bytes(&entry),
+                MODEL_TAG => model = tiff.entry_bytes(&entry),
+                MAKER_NOTE_TAG => {
+                    let Some(quirk) = quirk else {
+                        continue;
+                    };
+                    let offset = tiff.entry_data_offset(&entry);
+                    for &preview in quirk.previews {
+                        let Preview::MakerNoteTag(layout, tag) = preview else {
+                            continue;
+                        };
+                        let result = makernote::find_jpegs(&tiff, layout, tag, offset, candidates);
+                        // MakerNotes are often mangled by software which rewrites metadata, so a
+                        // broken one shouldn't stop us using the previews we can find elsewhere.
+                        match result {
+                            Err(err) if strict => return Err(err),
+                            Err(err) => {
+                                skipped_error.get_or_insert(err);
+                            }
+                            Ok(()) => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Compression is optional for JPEGInterchangeFormat, but if it's there and isn't JPEG, the
+        // pointer is wrong. Strips and tiles must be compressed in the format they look like.
+        let is_jpeg_compression = |compression: Option<u64>| {
+            compression.is_none_or(|compression| {
+                matches!(
+                    compression,
+                    COMPRESSION_OLD_JPEG | COMPRESSION_JPEG | COMPRESSION_LOSSY_JPEG
+                )
+            })
+        };
+        let is_strip_compression = |format| match format {
+            ImageFormat::Jpeg => is_jpeg_compression(compression),
+            ImageFormat::Jxl => compression.is_none_or(|compression| {
+                matches!(compression, COMPRESSION_JXL_DRAFT | COMPRESSION_JXL)
+            }),
+        };
+
+        let jpeg = cur_offset
+            .zip(cur_length)
+            .filter(|_| is_jpeg_compression(compression));
+        if let Some((offset, length)) = jpeg {
+            candidates.push(EmbeddedJpegInfo {
+                thumbnail: ifd1_offset == Some(ifd_offset),
+                ..EmbeddedJpegInfo::new(offset, length)
+            });
+        }
+
+        let is_preview = new_subfile_type & SUBFILE_REDUCED_RESOLUTION != 0;
+        let pixels = width
+            .zip(height)
+            .map(|(width, height)| width.saturating_mul(height));
+        if is_dng && new_subfile_type == 0 {
+            main_pixels = pixels;
+        }
+
+        // In DNGs, only previews are candidates. Anything else, like the main image, can be JPEG
+        // compressed too, but it's RAW data rather than something viewable.
+        let strips_start = candidates.len();
+        let strips = strip_offsets
+            .zip(strip_lengths)
+            .filter(|_| !is_dng || is_preview);
+        if let Some((offsets, lengths)) = strips {
+            if offsets.count == 1 && lengths.count == 1 {
+                if let (Some(offset), Some(length)) =
+                    (tiff.entry_uint(&offsets), tiff.entry_uint(&lengths))
+                {
+                    candidates.extend(
+                        sniff_strip(&tiff, offset, length, jpeg_tables)
+                            .filter(|strip| is_strip_compression(strip.format)),
+                    );
+                }
+            } else if !tiled && matches!(compression, Some(COMPRESSION_OLD_JPEG | COMPRESSION_JPEG))
+            {
+                candidates.extend(find_multi_strip_jpeg(&tiff, &offsets, &lengths)?);
+            }
+        }
+        if let Some(pixels) = pixels.filter(|_| is_dng && candidates.len() > strips_start) {
+            dng_previews.push((strips_start, pixels));
+        }
+
+        if Some(ifd_offset) == preferred_ifd {
+            for candidate in &mut candidates[ifd_start..] {
+                candidate.preferred = true;
+            }
+        }
+        if ifd_offset == tiff.first_ifd_offset() {
+            ifd1_offset = Some(next_ifd_offset);
+            quirk = make
+                .as_deref()
+                .and_then(|make| quirks::find(make, model.as_deref()));
+            for &preview in quirk.map_or(&[][..], |quirk| quirk.previews) {
+                match preview {
+                    Preview::SubIfd(index) => preferred_ifd = sub_ifds.get(index).copied(),
+                    Preview::FixedOffset { offset, length } => {
+                        if tiff.starts_with_soi(offset) {
+                            // The SOI was read, so the offset is inside the file.
+                            let length = length.unwrap_or(source.len() - offset);
+                            candidates.push(EmbeddedJpegInfo::new(offset, length));
+                        }
+                    }
+                    Preview::MakerNoteTag(..) => {}
+                }
+            }
+        }
+        ifd_queue.push(next_ifd_offset);
+    }
+
+    // If there's a full resolution preview, that's the one the camera (or raw converter) intends
+    // to be shown, even if a smaller one happens to take up more bytes.
+    let best_preview = dng_previews
+        .into_iter()
+        .max_by_key(|&(_, pixels)| pixels)
+        .filter(|&(_, pixels)| main_pixels.is_some_and(|main_pixels| pixels >= main_pixels));
+    if let Some((index, _)) = best_preview {
+        candidates[index].preferred = true;
+    }
+
+    match skipped_error {
+        Some(err) if candidates.len() == first_candidate => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Find the width and height of the largest image in the IFD chain or SubIFDs, which is usually the
+/// RAW data itself. Anything which can't be read is skipped.
+pub(crate) fn largest_image_dimensions(raw_buf: &[u8]) -> Option<(u64, u64)> {
+    let tiff = Tiff::new(raw_buf).ok()?;
+    let mut largest: Option<(u64, u64)> = None;
+
+    for_each_ifd(&tiff, |entries| {
+        let mut width = None;
+        let mut height = None;
+        for entry in entries {
+            match entry.tag {
+                IMAGE_WIDTH_TAG => width = tiff.entry_uint(&entry),
+                IMAGE_LENGTH_TAG => height = tiff.entry_uint(&entry),
+                _ => {}
+            }
+        }
+
+        let area = |(width, height): (u64, u64)| width.saturating_mul(height);
+        if let Some(dimensions) = width.zip(height) {
+            if largest.is_none_or(|largest| area(dimensions) > area(largest)) {
+                largest = Some(dimensions);
+            }
+        }
+    });
+
+    largest
+}
+
+/// The DefaultCrop of a DNG's main image. The origin is relative to the top left of the area.
+pub(crate) struct DefaultCrop {
+    /// The size of the ActiveArea, or the whole image if there isn't one.
+    pub area: (f64, f64),
+    pub origin: (f64, f64),
+    pub size: (f64, f64),
+}
+
+/// Find the DefaultCrop of a DNG's main image.
+pub(crate) fn default_crop(raw_buf: &[u8]) -> Option<DefaultCrop> {
+    const DEFAULT_CROP_ORIGIN_TAG: u16 = 0xc61f;
+    const DEFAULT_CROP_SIZE_TAG: u16 = 0xc620;
+    /// The top, left, bottom, and right of the part of the sensor which has image data.
+    const ACTIVE_AREA_TAG: u16 = 0xc68d;
+
+    let tiff = Tiff::new(raw_buf).ok()?;
+    let mut crop = None;
+
+    for_each_ifd(&tiff, |entries| {
+        let mut is_main = true;
+        let (mut width, mut height) = (None, None);
+        let (mut active_area, mut origin, mut size) = (None, None, None);
+        for entry in entries {
+            match entry.tag {
+                NEW_SUBFILE_TYPE_TAG => is_main = tiff.entry_uint(&entry) == Some(0),
+                IMAGE_WIDTH_TAG => width = tiff.entry_uint(&entry),
+                IMAGE_LENGTH_TAG => height = tiff.entry_uint(&entry),
+                ACTIVE_AREA_TAG => active_area = tiff.entry_numbers(&entry),
+                DEFAULT_CROP_ORIGIN_TAG => origin = tiff.entry_numbers(&entry),
+                DEFAULT_CROP_SIZE_TAG => size = tiff.entry_numbers(&entry),
+                _ => {}
+            }
+        }
+        if !is_main || crop.is_some() {
+            return;
+        }
+
+        let area = match active_area.as_deref() {
+            Some(&[top, left, bottom, right]) => Some((right - left, bottom - top)),
+            _ => width
+                .zip(height)
+                .map(|(width, height)| (width as f64, height as f64)),
+        };
+        if let (Some(area), Some(&[x, y]), Some(&[width, height])) =
+            (area, origin.as_deref(), size.as_deref())
+        {
+            crop = Some(DefaultCrop {
+                area,
+                origin: (x, y),
+                size: (width, height),
+            });
+        }
+    });
+
+    crop.filter(|crop| crop.area.0 > 0.0 && crop.area.1 > 0.0)
+}
+
+/// Call `f` with the entries of every IFD in the main chain and their SubIFDs, skipping any which
+/// can't be read.
+fn for_each_ifd<'a>(tiff: &Tiff<'a>, mut f: impl FnMut(IfdIter<'a>)) {
+    let mut ifd_queue = vec![tiff.first_ifd_offset()];
+    let mut seen_ifds = HashSet::new();
+
+    while let Some(ifd_offset) = ifd_queue.pop() {
+        if ifd_offset == 0 || !seen_ifds.insert(ifd_offset) {
+            continue;
+        }
+        if seen_ifds.len() > MAX_IFDS {
+            break;
+        }
+        let Ok((entries, next_ifd_offset)) = tiff.read_ifd(ifd_offset) else {
+            continue;
+        };
+
+        for entry in entries.clone().filter(|entry| entry.tag == SUB_IFDS_TAG) {
+            ifd_queue.extend(tiff.entry_uints(&entry).unwrap_or_default());
+        }
+        f(entries);
+        ifd_queue.push(next_ifd_offset);
+    }
+}
+
+/// Find the Orientation in IFD0, and where its value is stored.
+fn find_orientation(tiff: &Tiff) -> Option<(u16, usize)> {
+    let (mut entries, _) = tiff.read_ifd(tiff.first_ifd_offset()).ok()?;
+    let entry = entries.find(|entry| {
+        entry.tag == ORIENTATION_TAG && entry.kind == TYPE_SHORT && entry.count == 1
+    })?;
+    let orientation = u16::try_from(tiff.entry_uint(&entry)?).ok()?;
+    // Short values are always stored in the entry itself, so this is within the buffer.
+    Some((orientation, usize::try_from(entry.position).ok()?))
+}
+
+/// Read the Orientation from IFD0 of the TIFF structure at the start of `buf`.
+pub(crate) fn orientation(buf: &[u8]) -> Option<u16> {
+    find_orientation(&Tiff::new(buf).ok()?).map(|(orientation, _)| orientation)
+}
+
+/// Read the ICC profile from IFD0 of the TIFF structure at the start of `buf`.
+pub(crate) fn icc_profile(buf: &[u8]) -> Option<Cow<'_, [u8]>> {
+    const ICC_PROFILE_TAG: u16 = 0x8773;
+
+    let tiff = Tiff::new(buf).ok()?;
+    let (mut entries, _) = tiff.read_ifd(tiff.first_ifd_offset()).ok()?;
+    entries
+        .find(|entry| entry.tag == ICC_PROFILE_TAG)
+        .and_then(|entry| tiff.entry_bytes(&entry))
+        .filter(|profile| !profile.is_empty())
+}
+
+/// Work out the colour space from the Exif IFD pointed to by IFD0 of the TIFF structure at the
+/// start of `buf`. The ColorSpace tag only distinguishes sRGB from "uncalibrated", which is what
+/// most cameras set for Adobe RGB, so for that the InteropIndex is used instead. Some Sony and Nikon
+/// bodies use a ColorSpace of 2 for Adobe RGB as well.
+pub(crate) fn color_space(buf: &[u8]) -> Option<ColorSpace> {
+    const COLOR_SPACE_TAG: u16 = 0xa001;
+    const INTEROP_INDEX_TAG: u16 = 0x1;
+
+    let tiff = Tiff::new(buf).ok()?;
+    let (mut entries, _) = tiff.read_ifd(tiff.first_ifd_offset()).ok()?;
+    let exif_ifd = entries
+        .find(|entry| entry.tag == EXIF_IFD_TAG)
+        .and_then(|entry| tiff.entry_uint(&entry))?;
+
+    let (mut color_space, mut interop_ifd) = (None, None);
+    for entry in tiff.read_ifd(exif_ifd).ok()?.0 {
+        match entry.tag {
+            COLOR_SPACE_TAG => color_space = tiff.entry_uint(&entry),
+            INTEROP_IFD_TAG => interop_ifd = tiff.entry_uint(&entry),
+            _ => {}
+        }
+    }
+    match color_space {
+        Some(1) => return Some(ColorSpace::Srgb),
+        Some(2) => return Some(ColorSpace::AdobeRgb),
+        _ => {}
+    }
+
+    let (mut entries, _) = tiff.read_ifd(interop_ifd?).ok()?;
+    let index = entries
+        .find(|entry| entry.tag == INTEROP_INDEX_TAG)
+        .and_then(|entry| tiff.entry_bytes(&entry))?;
+    match index.strip_suffix(b"\0").unwrap_or(&index) {
+        b"R98" => Some(ColorSpace::Srgb),
+        b"R03" => Some(ColorSpace::AdobeRgb),
+        _ => None,
+    }
+}
+
+/// Read the metadata rawtojpg uses from IFD0 and the Exif IFD of the TIFF structure at the start
+/// of `buf`.
+pub(crate) fn metadata(buf: &[u8]) -> Option<Metadata> {
+    const MAKE_TAG: u16 = 0x10f;
+    const MODEL_TAG: u16 = 0x110;
+    const EXPOSURE_TIME_TAG: u16 = 0x829a;
+    const F_NUMBER_TAG: u16 = 0x829d;
+    const ISO_TAG: u16 = 0x8827;
+    const DATE_TIME_ORIGINAL_TAG: u16 = 0x9003;
+    const OFFSET_TIME_ORIGINAL_TAG: u16 = 0x9011;
+    const FOCAL_LENGTH_TAG: u16 = 0x920a;
+    const SUB_SEC_TIME_ORIGINAL_TAG: u16 = 0x9291;
+    const LENS_MODEL_TAG: u16 = 0xa434;
+
+    let tiff = Tiff::new(buf).ok()?;
+    let text = |entry: &IfdEntry| {
+        Some(tiff.entry_ascii(entry)?.trim().to_string()).filter(|text| !text.is_empty())
+    };
+    let number = |entry: &IfdEntry| tiff.entry_numbers(entry)?.first().copied();
+
+    let mut metadata = Metadata::default();
+    let mut exif_ifd = None;
+    for entry in tiff.read_ifd(tiff.first_ifd_offset()).ok()?.0 {
+        match entry.tag {
+            MAKE_TAG => metadata.make = text(&entry),
+            MODEL_TAG => metadata.model = text(&entry),
+            EXIF_IFD_TAG => exif_ifd = tiff.entry_uint(&entry),
+            _ => {}
+        }
+    }
+    let Some(Ok((entries, _))) = exif_ifd.map(|offset| tiff.read_ifd(offset)) else {
+        return Some(metadata);
+    };
+    for entry in entries {
+        match entry.tag {
+            EXPOSURE_TIME_TAG => metadata.exposure_time = number(&entry),
+            F_NUMBER_TAG => metadata.f_number = number(&entry),
+            ISO_TAG => metadata.iso = tiff.entry_uint(&entry),
+            DATE_TIME_ORIGINAL_TAG => metadata.date_time_original = text(&entry),
+            OFFSET_TIME_ORIGINAL_TAG => metadata.offset_time_original = text(&entry),
+            FOCAL_LENGTH_TAG => metadata.focal_length = number(&entry),
+            SUB_SEC_TIME_ORIGINAL_TAG => metadata.sub_sec_time_original = text(&entry),
+            LENS_MODEL_TAG => metadata.lens = text(&entry),
+            _ => {}
+        }
+    }
+    Some(metadata)
+}
+
+/// Change the Orientation in IFD0 of the TIFF structure at the start of `buf`, returning whether
+/// it had one to change.
+pub(crate) fn set_orientation(buf: &mut [u8], orientation: u16) -> bool {
+    let Some((position, little_endian)) = Tiff::new(buf).ok().and_then(|tiff| {
+        let (_, position) = find_orientation(&tiff)?;
+        Some((position, tiff.is_little_endian()))
+    }) else {
+        return false;
+    };
+    let value = if little_endian {
+        orientation.to_le_bytes()
+    } else {
+        orientation.to_be_bytes()
+    };
+    buf[position..position + 2].copy_from_slice(&value);
+    true
+}
+
+/// Find the GPS IFD pointed to by IFD0, returning which entry of IFD0 points to it and where it is.
+fn find_gps(tiff: &Tiff) -> Option<(usize, u64)> {
+    let (entries, _) = tiff.read_ifd(tiff.first_ifd_offset()).ok()?;
+    entries
+        .enumerate()
+        .find(|(_, entry)| entry.tag == GPS_IFD_TAG)
+        .and_then(|(index, entry)| Some((index, tiff.entry_uint(&entry)?)))
+}
+
+/// Whether IFD0 of the TIFF structure at the start of `buf` points to a GPS IFD.
+pub(crate) fn has_gps(buf: &[u8]) -> bool {
+    Tiff::new(buf).is_ok_and(|tiff| find_gps(&tiff).is_some())
+}
+
+/// Remove the GPS IFD from the TIFF structure at the start of `buf`. Just dropping the pointer to
+/// it would leave the position in the file for anyone who looks, so the IFD and its values are
+/// zeroed as well. Returns whether there was one.
+pub(crate) fn strip_gps(buf: &mut [u8]) -> bool {
+    let Some((zeroed, ifd0, index, little_endian)) = Tiff::new(buf)
+        .ok()
+        .filter(|tiff| !tiff.big)
+        .and_then(|tiff| {
+            let (index, gps) = find_gps(&tiff)?;
+            let mut zeroed = Vec::new();
+            if let Ok((entries, _)) = tiff.read_ifd(gps) {
+                let start = usize::try_from(gps).ok()?;
+                zeroed.push(start..start + 2 + 12 * entries.len() + 4);
+                for entry in entries {
+                    let range = tiff.entry_range(&entry).and_then(|(offset, length)| {
+                        get_range(buf, offset, length)?;
+                        // The range is in the buffer, so it fits in a usize.
+                        Some(offset as usize..(offset + length) as usize)
+                    });
+                    zeroed.extend(range);
+                }
+            }
+            let ifd0 = usize::try_from(tiff.first_ifd_offset()).ok()?;
+            Some((zeroed, ifd0, index, tiff.is_little_endian()))
+        })
+    else {
+        return false;
+    };
+    for range in zeroed {
+        buf[range].fill(0);
+    }
+
+    // Move the later entries and the next IFD offset up over the pointer, and zero what's left at
+    // the end. The IFD was read from here before anything was zeroed, so its count is still valid
+    // unless the GPS IFD overlapped it, in which case there's nothing sensible left to keep.
+    let count = if little_endian {
+        LittleEndian::read_u16(&buf[ifd0..])
+    } else {
+        BigEndian::read_u16(&buf[ifd0..])
+    };
+    let end = ifd0 + 2 + 12 * usize::from(count) + 4;
+    if usize::from(count) <= index || end > buf.len() {
+        return true;
+    }
+    let pointer = ifd0 + 2 + 12 * index;
+    buf.copy_within(pointer + 12..end, pointer);
+    buf[end - 12..end].fill(0);
+    let count = if little_endian {
+        (count - 1).to_le_bytes()
+    } else {
+        (count - 1).to_be_bytes()
+    };
+    buf[ifd0..ifd0 + 2].copy_from_slice(&count);
+    true
+}
+
+/// Add an entry to the Exif IFD of the TIFF structure at the start of `buf`, replacing any with the
+/// same tag, and adding an Exif IFD if there isn't one. `value` has to be in the structure's byte
+/// order already. Nothing which is already there is moved, since MakerNotes often have offsets
+/// which would break: the IFDs which change are written again at the end instead, and the pointers
+/// to them updated.
+pub(crate) fn with_exif_entry(
+    buf: &[u8],
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: &[u8],
+) -> Option<Vec<u8>> {
+    with_entry(buf, true, tag, kind, count, value)
+}
+
+/// Add an entry to IFD0 of the TIFF structure at the start of `buf`, replacing any with the same
+/// tag, in the same way as `with_exif_entry`.
+pub(crate) fn with_ifd0_entry(
+    buf: &[u8],
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: &[u8],
+) -> Option<Vec<u8>> {
+    with_entry(buf, false, tag, kind, count, value)
+}
+
+/// Add an entry to the Exif IFD if `exif_ifd` is set, or to IFD0 otherwise.
+fn with_entry(
+    buf: &[u8],
+    exif_ifd: bool,
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: &[u8],
+) -> Option<Vec<u8>> {
+    const ENTRY_SIZE: usize = 12;
+
+    let tiff = Tiff::new(buf).ok().filter(|tiff| !tiff.big)?;
+    let little_endian = tiff.is_little_endian();
+    let u16_bytes = |value: u16| {
+        if little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        }
+    };
+    let u32_bytes = |value: u32| {
+        if little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        }
+    };
+    // The entries of an IFD as they are, and the offset of the next IFD.
+    let raw_ifd = |offset: u64| -> Option<(Vec<&[u8]>, u64)> {
+        let (entries, next) = tiff.read_ifd(offset).ok()?;
+        let start = usize::try_from(offset).ok()? + 2;
+        let raw = buf.get(start..start + ENTRY_SIZE * entries.len())?;
+        Some((raw.chunks_exact(ENTRY_SIZE).collect(), next))
+    };
+    let entry_tag = |entry: &[u8]| (tiff.read_u16)(&entry[..2]);
+
+    let mut out = buf.to_vec();
+    // Write an IFD at the end of `out`, with `value` after it if it doesn't fit in the entry, and
+    // return where it is.
+    let append_ifd = |out: &mut Vec<u8>, mut entries: Vec<Vec<u8>>, next: u64, value: &[u8]| {
+        if out.len() % 2 == 1 {
+            out.push(0);
+        }
+        let offset = u32::try_from(out.len()).ok()?;
+        entries.sort_by_key(|entry| entry_tag(entry));
+        out.extend_from_slice(&u16_bytes(u16::try_from(entries.len()).ok()?));
+        let value_offset = out.len() + ENTRY_SIZE * entries.len() + 4;
+        for mut entry in entries {
+            if entry.len() < ENTRY_SIZE {
+                entry.extend_from_slice(&u32_bytes(u32::try_from(value_offset).ok()?));
+            }
+            out.extend_from_slice(&entry);
+        }
+        out.extend_from_slice(&u32_bytes(u32::try_from(next).ok()?));
+        out.extend_from_slice(value);
+        Some(offset)
+    };
+
+    let (ifd0, ifd0_next) = raw_ifd(tiff.first_ifd_offset())?;
+    let pointer = ifd0
+        .iter()
+        .position(|entry| entry_tag(entry) == EXIF_IFD_TAG);
+    let old_entries = match pointer {
+        _ if !exif_ifd => ifd0.clone(),
+        Some(index) => {
+            let (mut entries, _) = tiff.read_ifd(tiff.first_ifd_offset()).ok()?;
+            let exif_ifd = tiff.entry_uint(&entries.nth(index)?)?;
+            raw_ifd(exif_ifd)?.0
+        }
+        None => Vec::new(),
+    };
+
+    // Entries shorter than ENTRY_SIZE have their value's offset filled in by append_ifd.
+    let mut new_entry = [u16_bytes(tag), u16_bytes(kind)].concat();
+    new_entry.extend_from_slice(&u32_bytes(count));
+    let out_of_line = if value.len() > 4 {
+        value
+    } else {
+        let mut inline = value.to_vec();
+        inline.resize(4, 0);
+        new_entry.extend_from_slice(&inline);
+        &[]
+    };
+    let mut entries: Vec<Vec<u8>> = old_entries
+        .into_iter()
+        .filter(|entry| entry_tag(entry) != tag)
+        .map(<[u8]>::to_vec)
+        .collect();
+    entries.push(new_entry);
+    if !exif_ifd {
+        let ifd0 = append_ifd(&mut out, entries, ifd0_next, out_of_line)?;
+        out[4..8].copy_from_slice(&u32_bytes(ifd0));
+        return Some(out);
+    }
+    let exif_ifd = append_ifd(&mut out, entries, 0, out_of_line)?;
+
+    match pointer {
+        Some(index) => {
+            let position = tiff.first_ifd_offset() as usize + 2 + ENTRY_SIZE * index + 8;
+            out[position..position + 4].copy_from_slice(&u32_bytes(exif_ifd));
+        }
+        None => {
+            let mut entries: Vec<Vec<u8>> = ifd0.into_iter().map(<[u8]>::to_vec).collect();
+            let mut pointer = [u16_bytes(EXIF_IFD_TAG), u16_bytes(TYPE_LONG)].concat();
+            pointer.extend_from_slice(&u32_bytes(1));
+            pointer.extend_from_slice(&u32_bytes(exif_ifd));
+            entries.push(pointer);
+            let ifd0 = append_ifd(&mut out, entries, ifd0_next, &[])?;
+            out[4..8].copy_from_slice(&u32_bytes(ifd0));
+        }
+    }
+    Some(out)
+}
+
+/// Work out what's in a single strip or tile, and return it if it's an image format we know.
+fn sniff_strip<S: Source + ?Sized>(
+    tiff: &Tiff<S>,
+    offset: u64,
+    length: u64,
+    jpeg_tables: Option<(u64, u64)>,
+) -> Option<EmbeddedJpegInfo> {
+    let jpeg = EmbeddedJpegInfo::new(offset, length);
+    let signature = tiff.read_up_to(offset, ImageFormat::SIGNATURE_LENGTH)?;
+    match ImageFormat::sniff(&signature) {
+        Some(ImageFormat::Jpeg) => Some(EmbeddedJpegInfo {
+            jpeg_tables,
+            ..jpeg
+        }),
+        Some(format) => Some(EmbeddedJpegInfo { format, ..jpeg }),
+        None => None,
+    }
+}
+
+/// Find a JPEG which has been split across several strips, like some JPEG compressed previews.
+///
+/// We only handle the case where the strips together make up a single JPEG stream, so the first
+/// strip must start with SOI, and the rest must not. Strips which are each their own JPEG can't be
+/// joined without reencoding. Usually the strips are contiguous, so the result is just one range,
+/// but otherwise we keep track of each strip so they can be joined together later.
+fn find_multi_strip_jpeg<S: Source + ?Sized>(
+    tiff: &Tiff<S>,
+    offsets: &IfdEntry,
+    lengths: &IfdEntry,
+) -> Result<Option<EmbeddedJpegInfo>> {
+    let (Some(offsets), Some(lengths)) = (tiff.entry_uints(offsets), tiff.entry_uints(lengths))
+    else {
+        return Ok(None);
+    };
+    if offsets.len() != lengths.len() {
+        return Ok(None);
+    }
+
+    let strips: Vec<_> = offsets.into_iter().zip(lengths).collect();
+    let Some((&(first_offset, first_length), rest)) = strips.split_first() else {
+        return Ok(None);
+    };
+    if !tiff.starts_with_soi(first_offset)
+        || rest.iter().any(|&(offset, _)| tiff.starts_with_soi(offset))
+    {
+        return Ok(None);
+    }
+
+    let contiguous = strips
+        .windows(2)
+        .all(|pair| pair[0].0.checked_add(pair[0].1) == Some(pair[1].0));
+    if contiguous {
+        let length = strips
+            .iter()
+            .map(|&(_, length)| length)
+            .fold(0, u64::saturating_add);
+        return Ok(Some(EmbeddedJpegInfo::new(first_offset, length)));
+    }
+
+    Ok(Some(EmbeddedJpegInfo {
+        strips: rest.to_vec(),
+        ..EmbeddedJpegInfo::new(first_offset, first_length)
+    }))
+}
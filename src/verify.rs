@@ -0,0 +1,76 @@
+use crate::manifest::{self, Algorithm};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::fs;
+
+/// What `rawtojpg verify` checks everything which would be written against, instead of writing
+/// it: either what's already in the output directory, or the hashes in a --manifest.
+pub struct Verifier {
+    manifest: Option<(Algorithm, HashMap<PathBuf, String>)>,
+    checked: AtomicUsize,
+    missing: AtomicUsize,
+    mismatched: AtomicUsize,
+}
+
+impl Verifier {
+    /// Check against the files themselves, or against the hashes in `manifest` if it's given,
+    /// whose relative paths are under `root`.
+    pub fn new(manifest: Option<&Path>, algorithm: Algorithm, root: &Path) -> Result<Self> {
+        let manifest = manifest
+            .map(|path| {
+                let data = std::fs::read(path)
+                    .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+                manifest::parse(&data, root).map(|hashes| (algorithm, hashes))
+            })
+            .transpose()?;
+        Ok(Self {
+            manifest,
+            checked: AtomicUsize::new(0),
+            missing: AtomicUsize::new(0),
+            mismatched: AtomicUsize::new(0),
+        })
+    }
+
+    /// Check that `path` has exactly `data` in it, saying so if it doesn't, or isn't there at all.
+    pub async fn check(&self, path: &Path, data: &[u8]) -> Result<()> {
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        let matches = match &self.manifest {
+            Some((algorithm, hashes)) => hashes.get(path).map(|hash| *hash == algorithm.hash(data)),
+            None => match fs::read(path).await {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+                Ok(existing) => Some(existing == data),
+            },
+        };
+        match matches {
+            Some(true) => {}
+            Some(false) => {
+                println!("{}: differs", path.display());
+                self.mismatched.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                println!("{}: missing", path.display());
+                self.missing.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Say how many files were checked, and fail if any of them differed or were missing.
+    pub fn finish(&self) -> Result<()> {
+        let checked = self.checked.load(Ordering::Relaxed);
+        let missing = self.missing.load(Ordering::Relaxed);
+        let mismatched = self.mismatched.load(Ordering::Relaxed);
+        eprintln!("Checked {checked} files: {mismatched} differed, {missing} were missing");
+        if missing + mismatched > 0 {
+            bail!(
+                "{} files didn't match what their RAWs give",
+                missing + mismatched
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::{Context, Result};
+use image::{imageops, RgbImage, RgbaImage};
+use std::path::Path;
+
+/// Where on the preview the watermark goes.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum Position {
+    #[value(name = "tl")]
+    TopLeft,
+    #[value(name = "tr")]
+    TopRight,
+    #[value(name = "bl")]
+    BottomLeft,
+    #[default]
+    #[value(name = "br")]
+    BottomRight,
+    Center,
+}
+
+/// How far the watermark is kept from the edges, as a fraction of the preview's short edge.
+const MARGIN: f32 = 0.02;
+
+#[derive(Debug)]
+pub struct Watermark {
+    image: RgbaImage,
+    position: Position,
+}
+
+impl Watermark {
+    /// Load a watermark from an image file, with its alpha scaled by `opacity`, from 0 to 1.
+    pub fn load(path: &Path, position: Position, opacity: f32) -> Result<Self> {
+        let mut image = image::open(path)
+            .with_context(|| format!("Failed to load watermark {}", path.display()))?
+            .into_rgba8();
+        for pixel in image.pixels_mut() {
+            pixel[3] = (f32::from(pixel[3]) * opacity).round() as u8;
+        }
+        Ok(Self { image, position })
+    }
+
+    /// Composite the watermark onto `image`. If it doesn't fit inside the margins, it's scaled down
+    /// until it does.
+    pub fn apply(&self, image: &mut RgbImage) {
+        let margin = (image.width().min(image.height()) as f32 * MARGIN).round() as u32;
+        let max_width = image.width().saturating_sub(2 * margin);
+        let max_height = image.height().saturating_sub(2 * margin);
+        if max_width == 0 || max_height == 0 {
+            return;
+        }
+
+        let scaled;
+        let mut watermark = &self.image;
+        if watermark.width() > max_width || watermark.height() > max_height {
+            scaled = imageops::thumbnail(watermark, max_width, max_height);
+            watermark = &scaled;
+        }
+
+        let right = image.width() - margin - watermark.width();
+        let bottom = image.height() - margin - watermark.height();
+        let (left, top) = match self.position {
+            Position::TopLeft => (margin, margin),
+            Position::TopRight => (right, margin),
+            Position::BottomLeft => (margin, bottom),
+            Position::BottomRight => (right, bottom),
+            Position::Center => ((margin + right) / 2, (margin + bottom) / 2),
+        };
+
+        for (x, y, pixel) in watermark.enumerate_pixels() {
+            let alpha = f32::from(pixel[3]) / 255.0;
+            let under = image.get_pixel_mut(left + x, top + y);
+            for (under, &over) in under.0.iter_mut().zip(&pixel.0[..3]) {
+                *under =
+                    (f32::from(*under) * (1.0 - alpha) + f32::from(over) * alpha).round() as u8;
+            }
+        }
+    }
+}
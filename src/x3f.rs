@@ -0,0 +1,69 @@
+use crate::EmbeddedJpegInfo;
+use anyhow::{ensure, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+
+const X3F_MAGIC: &[u8] = b"FOVb";
+
+pub fn is_x3f(buf: &[u8]) -> bool {
+    buf.starts_with(X3F_MAGIC)
+}
+
+fn read_u32_at(buf: &[u8], offset: usize) -> Result<u32> {
+    let bytes = buf
+        .get(offset..offset + 4)
+        .context("Truncated X3F section")?;
+    Ok(LittleEndian::read_u32(bytes))
+}
+
+/// Find all embedded JPEGs in a Sigma X3F file.
+///
+/// X3F is not TIFF based. The last four bytes of the file point to a directory section ("SECd"),
+/// which lists the offset, length, and type of every other section in the file. Image sections
+/// ("IMAG" and "IMA2") start with their own "SECi" header, which tells us whether the image data
+/// is a JPEG:
+///
+/// - u32 version
+/// - u32 image type (2 for a processed preview)
+/// - u32 data format (18 for JPEG)
+/// - u32 columns, u32 rows, and u32 row stride
+pub fn find_jpegs(raw_buf: &[u8], candidates: &mut Vec<EmbeddedJpegInfo>) -> Result<()> {
+    const DIR_ENTRY_SIZE: usize = 12;
+    const IMAGE_HEADER_SIZE: usize = 28;
+    const FORMAT_JPEG: u32 = 18;
+
+    ensure!(raw_buf.len() >= 4, "Truncated X3F file");
+    let dir_offset = read_u32_at(raw_buf, raw_buf.len() - 4)?.try_into()?;
+    let dir = raw_buf
+        .get(dir_offset..)
+        .with_context(|| format!("X3F directory offset {dir_offset} exceeds file size"))?;
+    ensure!(dir.starts_with(b"SECd"), "Invalid X3F directory");
+
+    let num_entries = read_u32_at(dir, 8)?.try_into()?;
+    let entries = dir.get(12..).context("Truncated X3F directory")?;
+
+    for entry in entries.chunks_exact(DIR_ENTRY_SIZE).take(num_entries) {
+        let kind = &entry[8..12];
+        if kind != b"IMAG" && kind != b"IMA2" {
+            continue;
+        }
+
+        let offset: usize = read_u32_at(entry, 0)?.try_into()?;
+        let length: usize = read_u32_at(entry, 4)?.try_into()?;
+        let section = raw_buf
+            .get(offset..)
+            .with_context(|| format!("X3F section offset {offset} exceeds file size"))?;
+        ensure!(
+            section.starts_with(b"SECi"),
+            "Invalid X3F image section at offset {offset}"
+        );
+
+        if read_u32_at(section, 12)? == FORMAT_JPEG && length > IMAGE_HEADER_SIZE {
+            candidates.push(EmbeddedJpegInfo::new(
+                (offset + IMAGE_HEADER_SIZE).try_into()?,
+                (length - IMAGE_HEADER_SIZE).try_into()?,
+            ));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use crate::gallery::escape;
+use rawtojpg::Metadata;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Make a minimal XMP sidecar for a preview of `source`, with just what photo managers need to
+/// sort it sensibly, since the preview itself may have no metadata at all.
+pub fn render(metadata: &Metadata, source: &Path) -> String {
+    let mut properties = String::new();
+    let mut property = |name: &str, value: &str| {
+        let _ = write!(properties, "\n    {name}=\"{}\"", escape(value));
+    };
+    if let Some(date) = xmp_date(metadata) {
+        property("exif:DateTimeOriginal", &date);
+        property("xmp:CreateDate", &date);
+    }
+    if let Some(make) = &metadata.make {
+        property("tiff:Make", make);
+    }
+    if let Some(model) = &metadata.model {
+        property("tiff:Model", model);
+    }
+    if let Some(lens) = &metadata.lens {
+        property("exifEX:LensModel", lens);
+    }
+    if let Some(name) = source.file_name() {
+        property("xmpMM:PreservedFileName", &name.to_string_lossy());
+    }
+
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>
+<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">
+ <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">
+  <rdf:Description rdf:about=\"\"
+    xmlns:exif=\"http://ns.adobe.com/exif/1.0/\"
+    xmlns:exifEX=\"http://cipa.jp/exif/1.0/\"
+    xmlns:stRef=\"http://ns.adobe.com/xap/1.0/sType/ResourceRef#\"
+    xmlns:tiff=\"http://ns.adobe.com/tiff/1.0/\"
+    xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"
+    xmlns:xmpMM=\"http://ns.adobe.com/xap/1.0/mm/\"{properties}>
+   <xmpMM:DerivedFrom rdf:parseType=\"Resource\">
+    <stRef:filePath>{}</stRef:filePath>
+   </xmpMM:DerivedFrom>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end=\"w\"?>
+",
+        escape(&source.to_string_lossy())
+    )
+}
+
+/// Convert the Exif date and time to the ISO 8601 form XMP uses, with the fraction of a second and
+/// the offset from UTC if there are any. Cameras with no clock set write all zeroes or spaces,
+/// which are left out.
+fn xmp_date(metadata: &Metadata) -> Option<String> {
+    let exif = metadata.date_time_original.as_deref()?;
+    let [year, month, day, hour, minute, second] =
+        [0..4, 5..7, 8..10, 11..13, 14..16, 17..19].map(|range| {
+            exif.get(range)
+                .filter(|digits| digits.bytes().all(|byte| byte.is_ascii_digit()))
+        });
+    let mut date = format!(
+        "{}-{}-{}T{}:{}:{}",
+        year.filter(|&year| year != "0000")?,
+        month?,
+        day?,
+        hour?,
+        minute?,
+        second?
+    );
+    if let Some(fraction) = metadata
+        .sub_sec_time_original
+        .as_deref()
+        .filter(|fraction| {
+            !fraction.is_empty() && fraction.bytes().all(|byte| byte.is_ascii_digit())
+        })
+    {
+        date.push('.');
+        date.push_str(fraction);
+    }
+    if let Some(offset) = metadata.offset_time_original.as_deref().filter(|offset| {
+        let bytes = offset.as_bytes();
+        matches!(bytes, [b'+' | b'-', h1, h2, b':', m1, m2]
+            if [h1, h2, m1, m2].iter().all(|digit| digit.is_ascii_digit()))
+    }) {
+        date.push_str(offset);
+    }
+    Some(date)
+}